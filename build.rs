@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Every .nes file under `dir`, in a stable order. Cargo is told to watch
+/// each directory on the way, so new ROMs get tests.
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")) {
+            roms.push(path);
+        }
+    }
+}
+
+/// A test name for the ROM, from its path under the ROM directory.
+fn test_name(dir: &Path, rom: &Path) -> String {
+    let path = rom.strip_prefix(dir).unwrap().with_extension("");
+    let name: String = path.to_string_lossy().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    format!("rom_{}", name)
+}
+
+/// Writes a #[test] for each ROM in $NES_TEST_ROMS, or tests/roms, that
+/// runs it through the $6000 harness in testrom.rs.
+fn main() {
+    println!("cargo:rerun-if-env-changed=NES_TEST_ROMS");
+
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let dir = manifest.join(env::var("NES_TEST_ROMS").unwrap_or("tests/roms".to_string()));
+
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+
+    let mut names = HashSet::new();
+    let mut tests = String::new();
+    for rom in roms {
+        let mut name = test_name(&dir, &rom);
+        while !names.insert(name.clone()) {
+            name.push('_');
+        }
+
+        tests.push_str(&format!("#[test]\nfn {}() {{\n    run_test_rom({:?});\n}}\n\n", name, rom.to_string_lossy()));
+    }
+
+    // Only touch the file when it changes, or everything gets rebuilt
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("test_roms.rs");
+    let mut old = String::new();
+    let unchanged = File::open(&out).and_then(|mut f| f.read_to_string(&mut old)).is_ok() && old == tests;
+    if !unchanged {
+        File::create(&out).and_then(|mut f| f.write_all(tests.as_bytes())).unwrap();
+    }
+}
@@ -0,0 +1,974 @@
+use audio::Resampler;
+
+use std::fmt;
+use std::fmt::Debug;
+
+/// NTSC CPU clock, which the APU runs from.
+pub const CPU_CLOCK: u64 = 1_789_773;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// Lengths loaded into the length counters, indexed by the top five bits of
+/// the last register of each channel.
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTIES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// Noise timer periods in APU cycles.
+const NOISE_PERIODS: [u16; 16] = [2, 4, 8, 16, 32, 48, 64, 80, 101, 127, 190, 254, 381, 508, 1017, 2034];
+
+/// DMC timer periods in CPU cycles.
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+/// CPU cycles the DMC's memory reader holds the CPU off the bus for.
+pub const DMC_STALL: u16 = 4;
+
+/// Frame counter steps, in CPU cycles since the sequence started.
+const QUARTER_1: u32 = 7457;
+const HALF_1: u32 = 14913;
+const QUARTER_3: u32 = 22371;
+const FOUR_STEP_IRQ: u32 = 29828;
+const FOUR_STEP_LAST: u32 = 29829;
+const FOUR_STEP_END: u32 = 29830;
+const FIVE_STEP_LAST: u32 = 37281;
+const FIVE_STEP_END: u32 = 37282;
+
+#[derive(Debug, Clone, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    /// The constant volume, or the divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        match self.constant {
+            true => self.volume,
+            false => self.decay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, val: u8) {
+        if self.enabled {
+            self.value = LENGTHS[(val >> 3) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Pulse {
+    /// Pulse 1 negates its sweep with ones' complement, pulse 2 with two's
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement: ones_complement,
+            ..Default::default()
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0b111;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 0b111) << 8;
+                self.length.load(val);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    /// The period the sweep unit is heading for. It can go negative or past
+    /// 11 bits, either of which mutes the channel.
+    fn sweep_target(&self) -> i32 {
+        let period = self.period as i32;
+        let change = period >> self.sweep_shift;
+
+        match (self.sweep_negate, self.ones_complement) {
+            (false, _) => period + change,
+            (true, true) => period - change - 1,
+            (true, false) => period - change,
+        }
+    }
+
+    /// The sweep unit mutes the channel even while it's disabled.
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target() as u16;
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    /// Clocked every APU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if DUTIES[self.duty as usize][self.step as usize] == 0 || self.length.value == 0 || self.muted() {
+            return 0;
+        }
+
+        self.envelope.output()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Triangle {
+    /// Halts the length counter and keeps reloading the linear counter
+    control: bool,
+    linear_period: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_period = val & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 0b111) << 8;
+                self.length.load(val);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    /// Clocked every CPU cycle. Silencing stops the sequencer where it is
+    /// rather than dropping the output to 0.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.value > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE[self.step as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Noise {
+    /// Short mode taps bit 6 instead of bit 1, for a 93 step sequence
+    short: bool,
+    period: u16,
+    timer: u16,
+    lfsr: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            short: false,
+            period: NOISE_PERIODS[0],
+            timer: 0,
+            lfsr: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            1 => {}
+            2 => {
+                self.short = val & 0x80 != 0;
+                self.period = NOISE_PERIODS[(val & 0x0F) as usize];
+            }
+            _ => {
+                self.length.load(val);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    /// Clocked every APU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            let tap = if self.short { 6 } else { 1 };
+            let feedback = (self.lfsr ^ (self.lfsr >> tap)) & 1;
+            self.lfsr = (self.lfsr >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.lfsr & 1 == 1 || self.length.value == 0 {
+            return 0;
+        }
+
+        self.envelope.output()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DMC {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    /// 7-bit DAC level, which $4011 sets directly
+    level: u8,
+
+    sample_addr: u16,
+    sample_len: u16,
+    /// Where the memory reader is in the current sample
+    addr: u16,
+    remaining: u16,
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits: u8,
+    silence: bool,
+}
+
+impl DMC {
+    fn new() -> Self {
+        DMC {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate: DMC_RATES[0],
+            timer: 0,
+            level: 0,
+
+            sample_addr: 0xC000,
+            sample_len: 1,
+            addr: 0xC000,
+            remaining: 0,
+            buffer: None,
+
+            shift: 0,
+            bits: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = val & 0x40 != 0;
+                self.rate = DMC_RATES[(val & 0x0F) as usize];
+            }
+            1 => self.level = val & 0x7F,
+            2 => self.sample_addr = 0xC000 | (val as u16) << 6,
+            _ => self.sample_len = (val as u16) << 4 | 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.remaining = 0;
+        } else if self.remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.remaining = self.sample_len;
+    }
+
+    /// Address the memory reader wants to fetch next, if its buffer is empty.
+    fn fetch_address(&self) -> Option<u16> {
+        match (self.buffer, self.remaining) {
+            (None, n) if n > 0 => Some(self.addr),
+            _ => None,
+        }
+    }
+
+    fn fill(&mut self, val: u8) {
+        self.buffer = Some(val);
+        // The address wraps around to $8000 rather than $0000
+        self.addr = match self.addr {
+            0xFFFF => 0x8000,
+            addr => addr + 1,
+        };
+
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            match self.shift & 1 {
+                1 if self.level <= 125 => self.level += 2,
+                0 if self.level >= 2 => self.level -= 2,
+                _ => {}
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift = val;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+/// The 2A03's audio: two pulse channels, triangle, noise and the delta
+/// modulation channel, mixed down to samples at `sample_rate`.
+#[derive(Clone)]
+pub struct APU {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: DMC,
+    pub cycles: u64,
+
+    /// Frame counter: which sequence it runs, how far into it it is, and
+    /// the CPU cycles until a $4017 write restarts it
+    five_step: bool,
+    frame_cycle: u32,
+    frame_reset: Option<u8>,
+    irq_inhibit: bool,
+    frame_irq: bool,
+
+    resampler: Resampler,
+}
+
+impl Debug for APU {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "APU")
+    }
+}
+
+impl APU {
+    pub fn new(sample_rate: u32) -> Self {
+        APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: DMC::new(),
+            cycles: 0u64,
+
+            five_step: false,
+            frame_cycle: 0,
+            frame_reset: None,
+            irq_inhibit: false,
+            frame_irq: false,
+
+            resampler: Resampler::new(sample_rate),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.rate()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(sample_rate);
+    }
+
+    /// Hands over the samples produced so far.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.resampler.take()
+    }
+
+    pub fn write8(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000...0x4003 => self.pulse1.write(addr & 0b11, val),
+            0x4004...0x4007 => self.pulse2.write(addr & 0b11, val),
+            0x4008...0x400B => self.triangle.write(addr & 0b11, val),
+            0x400C...0x400F => self.noise.write(addr & 0b11, val),
+            0x4010...0x4013 => self.dmc.write(addr & 0b11, val),
+            0x4015 => {
+                self.pulse1.length.set_enabled(val & 0x01 != 0);
+                self.pulse2.length.set_enabled(val & 0x02 != 0);
+                self.triangle.length.set_enabled(val & 0x04 != 0);
+                self.noise.length.set_enabled(val & 0x08 != 0);
+                self.dmc.set_enabled(val & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step = val & 0x80 != 0;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+
+                // The restart waits for the next APU cycle boundary
+                self.frame_reset = Some(3 + (self.cycles & 1) as u8);
+            }
+            _ => {}
+        }
+    }
+
+    /// $4015: which length counters are running, whether the DMC has bytes
+    /// left, and both IRQ flags. Bit 5 isn't driven. Reading acknowledges
+    /// the frame IRQ.
+    pub fn read_status(&mut self) -> u8 {
+        let val = (self.pulse1.length.value > 0) as u8
+            | ((self.pulse2.length.value > 0) as u8) << 1
+            | ((self.triangle.length.value > 0) as u8) << 2
+            | ((self.noise.length.value > 0) as u8) << 3
+            | ((self.dmc.remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7;
+
+        self.frame_irq = false;
+        val
+    }
+
+    pub fn irq(&self) -> bool {
+        self.dmc.irq || self.frame_irq
+    }
+
+    /// Address the DMC wants a sample byte from. The bus owner reads it,
+    /// stalls the CPU and passes it to `dmc_fill`.
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+
+    /// Clocks envelopes and the triangle's linear counter.
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    /// Clocks length counters and sweeps.
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    /// Steps the frame counter a CPU cycle, clocking envelopes, sweeps and
+    /// length counters, and raising the IRQ at the end of the 4-step
+    /// sequence.
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset {
+            match delay {
+                0 => {
+                    self.frame_reset = None;
+                    self.frame_cycle = 0;
+                    // 5-step mode clocks everything as soon as it starts
+                    if self.five_step {
+                        self.quarter_frame();
+                        self.half_frame();
+                    }
+                    return;
+                }
+                _ => self.frame_reset = Some(delay - 1),
+            }
+        }
+
+        self.frame_cycle += 1;
+
+        match (self.frame_cycle, self.five_step) {
+            (QUARTER_1, _) | (QUARTER_3, _) => self.quarter_frame(),
+            (HALF_1, _) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (FOUR_STEP_IRQ, false) => self.set_frame_irq(),
+            (FOUR_STEP_LAST, false) => {
+                self.quarter_frame();
+                self.half_frame();
+                self.set_frame_irq();
+            }
+            (FOUR_STEP_END, false) => {
+                self.set_frame_irq();
+                self.frame_cycle = 0;
+            }
+            (FIVE_STEP_LAST, true) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (FIVE_STEP_END, true) => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    /// Runs one CPU cycle.
+    pub fn step(&mut self) {
+        self.clock_frame_counter();
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+
+        // The rest of the channels run at half the CPU clock
+        if self.cycles & 1 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+            self.noise.clock_timer();
+        }
+
+        self.cycles += 1;
+        let level = self.mix();
+        self.resampler.push(level);
+    }
+
+    /// The nonlinear DAC mix of every channel, from 0.0 to about 1.0.
+    pub fn mix(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+
+        pulse_out + tnd_out
+    }
+}
+
+snapshot!(Envelope { start, looping, constant, volume, divider, decay });
+snapshot!(LengthCounter { enabled, halt, value });
+snapshot!(Pulse {
+    ones_complement, duty, step, period, timer, envelope, length,
+    sweep_enabled, sweep_period, sweep_negate, sweep_shift, sweep_reload, sweep_divider,
+});
+snapshot!(Triangle { control, linear_period, linear_counter, linear_reload, period, timer, step, length });
+snapshot!(Noise { short, period, timer, lfsr, envelope, length });
+snapshot!(DMC {
+    irq_enabled, irq, looping, rate, timer, level, sample_addr, sample_len,
+    addr, remaining, buffer, shift, bits, silence,
+});
+
+// The resampler is left alone, it's output rather than machine state
+snapshot!(APU {
+    pulse1, pulse2, triangle, noise, dmc, cycles,
+    five_step, frame_cycle, frame_reset, irq_inhibit, frame_irq,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apu() -> APU {
+        APU::new(DEFAULT_SAMPLE_RATE)
+    }
+
+    #[test]
+    fn length_counters() {
+        let mut apu = apu();
+
+        // Disabled channels ignore length loads
+        apu.write8(0x4003, 0x08);
+        assert_eq!(apu.read_status(), 0x00);
+
+        apu.write8(0x4015, 0x0F);
+        apu.write8(0x4003, 0x18);
+        apu.write8(0x400F, 0x08);
+        assert_eq!(apu.read_status(), 0x09);
+        assert_eq!(apu.pulse1.length.value, 2);
+
+        apu.half_frame();
+        apu.half_frame();
+        assert_eq!(apu.read_status(), 0x08);
+
+        // Halted counters hold, and disabling clears them
+        apu.write8(0x400C, 0x20);
+        apu.half_frame();
+        assert_eq!(apu.noise.length.value, 252);
+        apu.write8(0x4015, 0x00);
+        assert_eq!(apu.read_status(), 0x00);
+    }
+
+    #[test]
+    fn envelope_decays() {
+        let mut apu = apu();
+        apu.write8(0x4015, 0x01);
+        apu.write8(0x4000, 0x01);
+        apu.write8(0x4003, 0x00);
+
+        apu.quarter_frame();
+        assert_eq!(apu.pulse1.envelope.output(), 15);
+        apu.quarter_frame();
+        apu.quarter_frame();
+        assert_eq!(apu.pulse1.envelope.output(), 14);
+
+        // Constant volume ignores the decay level
+        apu.write8(0x4000, 0x17);
+        assert_eq!(apu.pulse1.envelope.output(), 7);
+    }
+
+    #[test]
+    fn sweep_negation_differs_between_pulses() {
+        let mut apu = apu();
+        apu.write8(0x4001, 0x89);
+        apu.write8(0x4002, 0x00);
+        apu.write8(0x4003, 0x01);
+        apu.write8(0x4005, 0x89);
+        apu.write8(0x4006, 0x00);
+        apu.write8(0x4007, 0x01);
+
+        assert_eq!(apu.pulse1.sweep_target(), 0x100 - 0x80 - 1);
+        assert_eq!(apu.pulse2.sweep_target(), 0x100 - 0x80);
+
+        apu.half_frame();
+        assert_eq!(apu.pulse1.period, 0x7F);
+        assert_eq!(apu.pulse2.period, 0x80);
+    }
+
+    #[test]
+    fn sweep_mutes_without_being_enabled() {
+        let mut apu = apu();
+        apu.write8(0x4015, 0x01);
+        apu.write8(0x4000, 0x3F);
+        apu.write8(0x4001, 0x01);
+        apu.write8(0x4002, 0xFF);
+        apu.write8(0x4003, 0x07);
+        assert!(apu.pulse1.muted());
+
+        apu.write8(0x4003, 0x03);
+        assert!(!apu.pulse1.muted());
+        apu.write8(0x4002, 0x07);
+        apu.write8(0x4003, 0x00);
+        assert!(apu.pulse1.muted());
+    }
+
+    #[test]
+    fn triangle_needs_both_counters() {
+        let mut apu = apu();
+        apu.write8(0x4015, 0x04);
+        apu.write8(0x4008, 0x00);
+        apu.write8(0x400A, 0x00);
+        apu.write8(0x400B, 0x08);
+
+        apu.step();
+        apu.step();
+        assert_eq!(apu.triangle.step, 0);
+
+        apu.write8(0x4008, 0x7F);
+        apu.write8(0x400B, 0x08);
+        apu.quarter_frame();
+        apu.step();
+        apu.step();
+        assert_eq!(apu.triangle.step, 2);
+    }
+
+    #[test]
+    fn noise_lfsr() {
+        let mut noise = Noise::new();
+        noise.period = 0;
+        noise.clock_timer();
+        assert_eq!(noise.lfsr, 0x4000);
+        noise.clock_timer();
+        assert_eq!(noise.lfsr, 0x2000);
+
+        // Short mode taps bit 6
+        let mut noise = Noise::new();
+        noise.period = 0;
+        noise.short = true;
+        noise.lfsr = 0x0041;
+        noise.clock_timer();
+        assert_eq!(noise.lfsr, 0x0020);
+    }
+
+    #[test]
+    fn dmc_reads_samples_and_raises_irq() {
+        let mut apu = apu();
+        apu.write8(0x4010, 0x8F);
+        apu.write8(0x4012, 0x01);
+        apu.write8(0x4013, 0x00);
+        assert_eq!(apu.dmc_fetch_address(), None);
+
+        apu.write8(0x4015, 0x10);
+        assert_eq!(apu.read_status(), 0x10);
+        assert_eq!(apu.dmc_fetch_address(), Some(0xC040));
+
+        apu.dmc_fill(0xFF);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        assert_eq!(apu.read_status(), 0x80);
+        assert!(apu.irq());
+
+        // Writing $4015 acknowledges it
+        apu.write8(0x4015, 0x00);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn dmc_output_follows_the_bits() {
+        let mut apu = apu();
+        apu.write8(0x4010, 0x0F);
+        apu.write8(0x4011, 0x40);
+        apu.write8(0x4015, 0x10);
+        apu.dmc_fill(0b0000_0111);
+
+        // Eight silent output cycles empty the shift register before the
+        // buffer is picked up, then three set bits raise the level
+        for _ in 0..54 * 11 {
+            apu.step();
+        }
+        assert_eq!(apu.dmc.level, 0x40 + 6);
+    }
+
+    fn run(apu: &mut APU, cycles: u32) {
+        for _ in 0..cycles {
+            apu.step();
+        }
+    }
+
+    #[test]
+    fn four_step_irq() {
+        let mut apu = apu();
+        run(&mut apu, FOUR_STEP_IRQ - 1);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+
+        // Reading $4015 acknowledges it, but the flag is set for two more
+        // cycles
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq());
+        run(&mut apu, 2);
+        assert!(apu.irq());
+        apu.read_status();
+
+        run(&mut apu, FOUR_STEP_END);
+        assert!(apu.irq());
+
+        // Inhibiting clears and blocks it
+        apu.write8(0x4017, 0x40);
+        assert!(!apu.irq());
+        run(&mut apu, FOUR_STEP_END * 2);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn five_step_sequence() {
+        let mut apu = apu();
+        apu.write8(0x4015, 0x01);
+        apu.write8(0x4003, 0x18);
+        assert_eq!(apu.pulse1.length.value, 2);
+
+        // Starting 5-step mode clocks the length counters at once
+        apu.write8(0x4017, 0x80);
+        run(&mut apu, 4);
+        assert_eq!(apu.pulse1.length.value, 1);
+
+        run(&mut apu, HALF_1 - 1);
+        assert_eq!(apu.pulse1.length.value, 1);
+        run(&mut apu, 1);
+        assert_eq!(apu.pulse1.length.value, 0);
+
+        run(&mut apu, FIVE_STEP_END * 2);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn four_step_half_frames() {
+        let mut apu = apu();
+        apu.write8(0x4015, 0x01);
+        apu.write8(0x4003, 0x08);
+        run(&mut apu, FOUR_STEP_END * 2);
+
+        // Two half frames a sequence
+        assert_eq!(apu.pulse1.length.value, 254 - 4);
+    }
+
+    #[test]
+    fn mixer() {
+        let mut apu = apu();
+
+        // The triangle powers up at the top of its sequence
+        let triangle = 159.79 / (8227.0 / 15.0 + 100.0);
+        assert!((apu.mix() - triangle).abs() < 1e-6);
+
+        apu.dmc.level = 127;
+        let tnd = 159.79 / (1.0 / (15.0 / 8227.0 + 127.0 / 22638.0) + 100.0);
+        assert!((apu.mix() - tnd).abs() < 1e-6);
+
+        apu.pulse1.length.value = 1;
+        apu.pulse1.period = 8;
+        apu.pulse1.step = 1;
+        apu.pulse1.envelope.constant = true;
+        apu.pulse1.envelope.volume = 15;
+        let pulse = 95.88 / (8128.0 / 15.0 + 100.0);
+        assert!((apu.mix() - tnd - pulse).abs() < 1e-6);
+    }
+
+    #[test]
+    fn samples_at_the_output_rate() {
+        let mut apu = APU::new(48_000);
+        for _ in 0..CPU_CLOCK {
+            apu.step();
+        }
+        assert_eq!(apu.take_samples().len(), 48_000);
+        assert!(apu.take_samples().is_empty());
+    }
+}
@@ -0,0 +1,374 @@
+use apu::CPU_CLOCK;
+
+#[cfg(feature = "cpal")]
+use cpal;
+#[cfg(feature = "cpal")]
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+#[cfg(feature = "cpal")]
+use std::sync::{Arc, Mutex};
+
+/// Kernel width, in output samples. It's also the resampler's latency.
+const TAPS: usize = 32;
+/// Fractional positions the kernel is precomputed for
+const PHASES: usize = 64;
+/// Cutoff as a fraction of the output rate, a little under Nyquist
+const CUTOFF: f64 = 0.45;
+/// Corner of the DC-blocking high-pass, like the one in the console
+const HIGH_PASS_HZ: f64 = 90.0;
+
+lazy_static! {
+    /// Blackman-windowed sinc impulses, one row per phase, each summing to
+    /// one so a step always settles at its full height.
+    static ref KERNEL: Vec<[f32; TAPS]> = (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+        let half = (TAPS / 2) as f64;
+
+        let mut taps = [0f64; TAPS];
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let x = i as f64 - half - offset;
+            let sinc = if x == 0.0 {
+                2.0 * CUTOFF
+            } else {
+                (2.0 * PI * CUTOFF * x).sin() / (PI * x)
+            };
+            let window = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+            *tap = sinc * window;
+        }
+
+        let sum: f64 = taps.iter().sum();
+        let mut row = [0f32; TAPS];
+        for (out, tap) in row.iter_mut().zip(taps.iter()) {
+            *out = (tap / sum) as f32;
+        }
+        row
+    }).collect();
+}
+
+/// Turns the mixer level, sampled every CPU cycle, into band-limited
+/// samples at the output rate.
+///
+/// Every change in level is drawn into the output as a windowed-sinc step
+/// instead of being point sampled, so pulse edges don't alias. That makes
+/// the cost scale with how often the level changes rather than with the
+/// 1.79 MHz input rate.
+#[derive(Clone)]
+pub struct Resampler {
+    rate: u32,
+    /// How far into the current output sample we are, in CPU_CLOCK units
+    phase: u64,
+    level: f32,
+    /// Pending differences, the front being the next sample out
+    deltas: VecDeque<f32>,
+    /// Running sum of the differences handed out so far
+    sum: f32,
+    high_pass: HighPass,
+    samples: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(rate: u32) -> Self {
+        Resampler {
+            rate: rate,
+            phase: 0,
+            level: 0.0,
+            deltas: vec![0.0; TAPS].into_iter().collect(),
+            sum: 0.0,
+            high_pass: HighPass::new(HIGH_PASS_HZ, rate),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Takes the level for one CPU cycle.
+    pub fn push(&mut self, level: f32) {
+        if level != self.level {
+            let row = &KERNEL[(self.phase * PHASES as u64 / CPU_CLOCK) as usize];
+            let delta = level - self.level;
+            for (out, tap) in self.deltas.iter_mut().zip(row.iter()) {
+                *out += delta * tap;
+            }
+            self.level = level;
+        }
+
+        self.phase += self.rate as u64;
+        if self.phase < CPU_CLOCK {
+            return;
+        }
+        self.phase -= CPU_CLOCK;
+
+        self.sum += self.deltas.pop_front().unwrap();
+        self.deltas.push_back(0.0);
+        let sample = self.high_pass.filter(self.sum);
+
+        // Nobody's listening, so don't grow forever
+        if self.samples.len() < self.rate as usize {
+            self.samples.push(sample);
+        }
+    }
+
+    /// Hands over the samples produced so far.
+    pub fn take(&mut self) -> Vec<f32> {
+        self.samples.split_off(0)
+    }
+}
+
+/// First order high-pass that takes the DC offset off the mixer, which
+/// only ever outputs positive levels.
+#[derive(Clone)]
+struct HighPass {
+    factor: f32,
+    input: f32,
+    output: f32,
+}
+
+impl HighPass {
+    fn new(corner: f64, rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * corner);
+        let dt = 1.0 / rate as f64;
+        HighPass {
+            factor: (rc / (rc + dt)) as f32,
+            input: 0.0,
+            output: 0.0,
+        }
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        self.output = self.factor * (self.output + input - self.input);
+        self.input = input;
+        self.output
+    }
+}
+
+/// Somewhere for the APU's samples to go.
+pub trait AudioSink: Send {
+    fn sample_rate(&self) -> u32;
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+
+    /// Samples written but not played yet. Sinks that play in real time
+    /// report it, so the emulator can take its pace from the sound card.
+    fn queued(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Records mono 16-bit PCM. The sizes in the header get filled in by
+/// `finish`, or when the writer is dropped.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    rate: u32,
+    /// Bytes of sample data written
+    len: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &str, rate: u32) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, rate: u32) -> io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        write_u32(&mut out, 36)?;
+        out.write_all(b"WAVEfmt ")?;
+        write_u32(&mut out, 16)?;
+        // PCM, one channel
+        write_u16(&mut out, 1)?;
+        write_u16(&mut out, 1)?;
+        write_u32(&mut out, rate)?;
+        // Byte rate, block align and bits per sample
+        write_u32(&mut out, rate * 2)?;
+        write_u16(&mut out, 2)?;
+        write_u16(&mut out, 16)?;
+        out.write_all(b"data")?;
+        write_u32(&mut out, 0)?;
+
+        Ok(WavWriter {
+            out: out,
+            rate: rate,
+            len: 0,
+        })
+    }
+
+    /// Patches the chunk sizes so the file is playable as it stands.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        write_u32(&mut self.out, 36 + self.len)?;
+        self.out.seek(SeekFrom::Start(40))?;
+        write_u32(&mut self.out, self.len)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek + Send> AudioSink for WavWriter<W> {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let sample = (sample.max(-1.0).min(1.0) * 32767.0) as i16;
+            write_u16(&mut self.out, sample as u16)?;
+        }
+        self.len += samples.len() as u32 * 2;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn write_u16<W: Write>(out: &mut W, val: u16) -> io::Result<()> {
+    out.write_all(&[val as u8, (val >> 8) as u8])
+}
+
+fn write_u32<W: Write>(out: &mut W, val: u32) -> io::Result<()> {
+    out.write_all(&[val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8])
+}
+
+/// Plays through the default output device. The stream that drains it
+/// isn't always `Send`, so `open` hands it back separately for the main
+/// thread to keep alive while the sink goes to the emulation thread.
+/// Only built with the `cpal` feature, since it needs the system's sound
+/// library.
+#[cfg(feature = "cpal")]
+pub struct LiveSink {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    rate: u32,
+}
+
+#[cfg(feature = "cpal")]
+impl LiveSink {
+    pub fn open() -> Result<(LiveSink, cpal::Stream), String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| "No audio output device".to_string())?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?.config();
+        let channels = config.channels as usize;
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let playing = queue.clone();
+        let mut last = 0.0;
+
+        let stream = device.build_output_stream(&config, move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let mut queue = playing.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                // Hold the last sample through underruns rather than click
+                last = queue.pop_front().unwrap_or(last);
+                for out in frame.iter_mut() {
+                    *out = last;
+                }
+            }
+        }, |e| println!("Audio error: {}", e), None).map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok((LiveSink { queue: queue, rate: config.sample_rate }, stream))
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl AudioSink for LiveSink {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+
+        // Running ahead of the device only adds latency
+        let excess = queue.len().saturating_sub(self.rate as usize / 4);
+        queue.drain(..excess);
+        Ok(())
+    }
+
+    fn queued(&self) -> Option<usize> {
+        Some(self.queue.lock().unwrap().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Feeds `cycles` CPU cycles of `level` and returns what came out.
+    fn run(resampler: &mut Resampler, cycles: u64, level: f32) -> Vec<f32> {
+        for _ in 0..cycles {
+            resampler.push(level);
+        }
+        resampler.take()
+    }
+
+    #[test]
+    fn output_rate() {
+        for &rate in &[44_100, 48_000] {
+            let mut resampler = Resampler::new(rate);
+            assert_eq!(run(&mut resampler, CPU_CLOCK, 0.5).len(), rate as usize);
+        }
+    }
+
+    #[test]
+    fn steps_settle_and_dc_decays() {
+        let mut resampler = Resampler::new(44_100);
+        run(&mut resampler, 10_000, 0.0);
+
+        // Ahead of the high-pass, the step lands at its full height
+        run(&mut resampler, 10_000, 1.0);
+        assert!((resampler.sum - 1.0).abs() < 1e-4);
+
+        let settled = run(&mut resampler, CPU_CLOCK / 10, 1.0);
+        assert!(settled.last().unwrap().abs() < 0.01);
+    }
+
+    #[test]
+    fn tones_above_nyquist_are_filtered() {
+        let mut resampler = Resampler::new(44_100);
+
+        // A 44.7 kHz square, which plain point sampling folds down to an
+        // audible few kHz at full volume
+        for cycle in 0..CPU_CLOCK / 10 {
+            resampler.push(if cycle / 20 % 2 == 0 { 1.0 } else { 0.0 });
+        }
+        let samples = resampler.take();
+
+        let tail = &samples[samples.len() / 2..];
+        let max = tail.iter().cloned().fold(-1.0, f32::max);
+        let min = tail.iter().cloned().fold(1.0, f32::min);
+        assert!(max - min < 0.05, "ripple {}", max - min);
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut out = Cursor::new(Vec::new());
+        {
+            let mut wav = WavWriter::new(&mut out, 44_100).unwrap();
+            wav.write(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        }
+        let data = out.into_inner();
+
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &[44, 0, 0, 0]);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(&data[24..28], &[0x44, 0xAC, 0, 0]);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(&data[40..44], &[8, 0, 0, 0]);
+        assert_eq!(&data[44..], &[0, 0, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+}
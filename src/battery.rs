@@ -0,0 +1,108 @@
+use cart::NESCart;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Keeps a cart's battery-backed PRG-RAM in a `.sav` next to the ROM.
+pub struct BatterySave {
+    path: PathBuf,
+    /// What the file holds, so unchanged RAM isn't written again
+    saved: Vec<u8>,
+}
+
+impl BatterySave {
+    /// Loads the `.sav` for `rom_path` into the cart's RAM, if there is
+    /// one yet.
+    pub fn open(rom_path: &str, cart: &mut NESCart) -> io::Result<BatterySave> {
+        let path = Path::new(rom_path).with_extension("sav");
+
+        let mut data = Vec::new();
+        match File::open(&path).and_then(|mut f| f.read_to_end(&mut data)) {
+            Ok(_) => {
+                // A save from a board with a different amount of RAM is
+                // still worth what fits
+                let len = data.len().min(cart.prg_ram.len());
+                cart.prg_ram[..len].copy_from_slice(&data[..len]);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(BatterySave {
+            path: path,
+            saved: cart.prg_ram.clone(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the RAM out if it changed, returning whether it did. The
+    /// new file replaces the old in one go, so a crash mid-write can't
+    /// lose the save.
+    pub fn flush(&mut self, cart: &NESCart) -> io::Result<bool> {
+        if cart.prg_ram == self.saved {
+            return Ok(false);
+        }
+
+        let tmp = self.path.with_extension("sav.tmp");
+        File::create(&tmp).and_then(|mut f| f.write_all(&cart.prg_ram).and_then(|_| f.sync_all()))?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.saved.copy_from_slice(&cart.prg_ram);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::test_rom;
+    use std::convert::TryFrom;
+    use std::env;
+
+    fn cart() -> NESCart {
+        NESCart::try_from(test_rom(0, 0b10, &[0; 0x4000], &[0; 0x2000])).unwrap()
+    }
+
+    fn rom_path(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("nes-emu-battery-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        let _ = fs::remove_file(dir.join("game.sav"));
+        dir.join("game.nes").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn saves_and_restores() {
+        let rom = rom_path("restore");
+
+        let mut cart = cart();
+        assert!(cart.header.battery);
+        let mut battery = BatterySave::open(&rom, &mut cart).unwrap();
+        assert!(!battery.flush(&cart).unwrap());
+        assert!(!battery.path().exists());
+
+        cart.prg_ram[0x123] = 0x45;
+        assert!(battery.flush(&cart).unwrap());
+        assert!(!battery.flush(&cart).unwrap());
+
+        let mut cart = self::cart();
+        BatterySave::open(&rom, &mut cart).unwrap();
+        assert_eq!(cart.prg_ram[0x123], 0x45);
+    }
+
+    #[test]
+    fn short_saves_fill_what_they_cover() {
+        let rom = rom_path("short");
+        File::create(Path::new(&rom).with_extension("sav")).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+        let mut cart = cart();
+        BatterySave::open(&rom, &mut cart).unwrap();
+        assert_eq!(&cart.prg_ram[..4], &[1, 2, 3, 0]);
+        assert_eq!(cart.prg_ram.len(), 0x2000);
+    }
+}
@@ -1,84 +1,205 @@
+use mapper::{Mapper, Mirroring, NROM, MMC1, UxROM, CNROM, MMC3, AxROM};
+use state::{Snapshot, StateError};
+
+use std::cmp;
+use std::convert::TryFrom;
 use std::fmt;
 
+const MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+
+/// Why a file couldn't be loaded as a cartridge.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+    /// Shorter than the 16 byte header
+    TooShort(usize),
+    /// Doesn't start with `NES\x1A`
+    BadMagic([u8; 4]),
+    /// The file ends before a section the header promises
+    Truncated { section: &'static str, expected: usize, found: usize },
+    NoPrgRom,
+    /// A NES 2.0 exponent-multiplier size too big to address
+    SizeOverflow(&'static str),
+    UnsupportedMapper { mapper: u16, submapper: u8 },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::TooShort(len) =>
+                write!(f, "File is {} bytes, too short for an iNES header", len),
+            RomError::BadMagic(m) =>
+                write!(f, "Not an iNES file: starts with {:02X} {:02X} {:02X} {:02X} instead of \"NES\\x1A\"",
+                       m[0], m[1], m[2], m[3]),
+            RomError::Truncated { section, expected, found } =>
+                write!(f, "File ends inside the {}: expected {} bytes, found {}", section, expected, found),
+            RomError::NoPrgRom =>
+                write!(f, "Header declares no PRG-ROM"),
+            RomError::SizeOverflow(section) =>
+                write!(f, "Header declares a {} too big to load", section),
+            RomError::UnsupportedMapper { mapper, submapper: 0 } =>
+                write!(f, "Unsupported mapper {}", mapper),
+            RomError::UnsupportedMapper { mapper, submapper } =>
+                write!(f, "Unsupported mapper {}.{}", mapper, submapper),
+        }
+    }
+}
+
+/// CPU/PPU timing the cartridge was made for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Runs on either
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Console {
+    Nes,
+    VsSystem { ppu: u8, hardware: u8 },
+    Playchoice10,
+    /// One of the NES 2.0 extended console types
+    Extended(u8),
+}
+
 #[derive(Debug, Clone)]
 pub struct NESHeader {
-    nes: [char; 4],
-    prg_rom_sz: usize,
-    chr_rom_sz: usize,
     flag_6: u8,
-    flag_7: u8,
-    prg_ram_sz: usize,
-    flag_9: u8,
-    flag_10: u8,
-    zero: [u8; 5],
-    pub mapper: u8,
-}
-
-impl From<Vec<u8>> for NESHeader {
-    fn from(cart: Vec<u8>) -> Self {
-        let mut nes = [0u8 as char; 4];
-        nes[0] = cart[0] as char;
-        nes[1] = cart[1] as char;
-        nes[2] = cart[2] as char;
-        nes[3] = cart[3] as char;
-
-        let prg_rom = cart[4];
-        let chr_rom = cart[5];
-        let flag_6 = cart[6];
-        let flag_7 = cart[7];
-        let prg_ram = cart[8];
-        let flag_9 = cart[9];
-        let flag_10 = cart[10];
-        let zero = [0u8; 5];
-
-        let mapper = (flag_6 & 0xF0) >> 4 | (flag_7 & 0xF0);
-
-        NESHeader {
-            nes: nes,
-            prg_rom_sz: prg_rom as usize * 16384,
-            chr_rom_sz: chr_rom as usize * 8192,
-            flag_6: flag_6,
-            flag_7: flag_7,
-            prg_ram_sz: prg_ram as usize * 8192,
-            flag_9: flag_9,
-            flag_10: flag_10,
-            zero: zero,
-            mapper: mapper,
+    /// Whether the header is in NES 2.0 format rather than iNES
+    pub nes2: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_sz: usize,
+    pub chr_rom_sz: usize,
+    pub prg_ram_sz: usize,
+    pub prg_nvram_sz: usize,
+    pub chr_ram_sz: usize,
+    pub chr_nvram_sz: usize,
+    /// 512 bytes between the header and PRG-ROM, loaded at $7000
+    pub trainer: bool,
+    /// Battery-backed PRG-RAM
+    pub battery: bool,
+    pub timing: Timing,
+    pub console: Console,
+    pub misc_roms: u8,
+    /// Default expansion port device
+    pub expansion: u8,
+}
+
+impl NESHeader {
+    pub fn mirroring(&self) -> Mirroring {
+        match (self.flag_6 & 0b1000, self.flag_6 & 0b1) {
+            (0b1000, _) => Mirroring::FourScreen,
+            (_, 0b1) => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
         }
     }
 }
 
-impl<'a> From<&'a Vec<u8>> for NESHeader {
-    fn from(cart: &'a Vec<u8>) -> Self {
-        let mut nes = [0u8 as char; 4];
-        nes[0] = cart[0] as char;
-        nes[1] = cart[1] as char;
-        nes[2] = cart[2] as char;
-        nes[3] = cart[3] as char;
+/// NES 2.0 ROM sizes are either a count of `unit`s, or an exponent and
+/// multiplier when the most significant nibble is $F.
+fn rom_size(lsb: u8, msb: u8, unit: usize, section: &'static str) -> Result<usize, RomError> {
+    match msb {
+        0xF => 1usize.checked_shl((lsb >> 2) as u32)
+            .and_then(|size| size.checked_mul((lsb & 0b11) as usize * 2 + 1))
+            .ok_or(RomError::SizeOverflow(section)),
+        _ => Ok((((msb as usize) << 8) | lsb as usize) * unit),
+    }
+}
+
+/// NES 2.0 RAM sizes are shift counts, with 0 meaning none.
+fn ram_size(shift: u8) -> usize {
+    match shift {
+        0 => 0,
+        n => 64 << n,
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for NESHeader {
+    type Error = RomError;
+
+    fn try_from(cart: &'a [u8]) -> Result<Self, RomError> {
+        if cart.len() < 16 {
+            return Err(RomError::TooShort(cart.len()));
+        }
+
+        if cart[0..4] != MAGIC {
+            return Err(RomError::BadMagic([cart[0], cart[1], cart[2], cart[3]]));
+        }
 
-        let prg_rom = cart[4];
-        let chr_rom = cart[5];
         let flag_6 = cart[6];
         let flag_7 = cart[7];
-        let prg_ram = cart[8];
-        let flag_9 = cart[9];
-        let flag_10 = cart[10];
-        let zero = [0u8; 5];
-
-        let mapper = (flag_6 & 0xF0) >> 4 | (flag_7 & 0xF0);
-
-        NESHeader {
-            nes: nes,
-            prg_rom_sz: prg_rom as usize * 16384,
-            chr_rom_sz: chr_rom as usize * 8192,
-            flag_6: flag_6,
-            flag_7: flag_7,
-            prg_ram_sz: prg_ram as usize * 8192,
-            flag_9: flag_9,
-            flag_10: flag_10,
-            zero: zero,
-            mapper: mapper,
-        }
+        let nes2 = flag_7 & 0x0C == 0x08;
+
+        let console = match flag_7 & 0b11 {
+            0 => Console::Nes,
+            1 => Console::VsSystem {
+                ppu: if nes2 { cart[13] & 0x0F } else { 0 },
+                hardware: if nes2 { cart[13] >> 4 } else { 0 },
+            },
+            2 => Console::Playchoice10,
+            _ => Console::Extended(if nes2 { cart[13] & 0x0F } else { 0 }),
+        };
+
+        let header = match nes2 {
+            true => NESHeader {
+                flag_6: flag_6,
+                nes2: true,
+                mapper: (flag_6 >> 4) as u16 | (flag_7 & 0xF0) as u16 | ((cart[8] & 0x0F) as u16) << 8,
+                submapper: cart[8] >> 4,
+                prg_rom_sz: rom_size(cart[4], cart[9] & 0x0F, 0x4000, "PRG-ROM")?,
+                chr_rom_sz: rom_size(cart[5], cart[9] >> 4, 0x2000, "CHR-ROM")?,
+                prg_ram_sz: ram_size(cart[10] & 0x0F),
+                prg_nvram_sz: ram_size(cart[10] >> 4),
+                chr_ram_sz: ram_size(cart[11] & 0x0F),
+                chr_nvram_sz: ram_size(cart[11] >> 4),
+                trainer: flag_6 & 0b100 == 0b100,
+                battery: flag_6 & 0b10 == 0b10,
+                timing: match cart[12] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                },
+                console: console,
+                misc_roms: cart[14] & 0b11,
+                expansion: cart[15] & 0x3F,
+            },
+            false => {
+                // Some old dumping tools wrote a signature over bytes 7-15,
+                // in which case the upper mapper nibble is garbage too
+                let mapper_hi = match cart[12..16].iter().all(|&b| b == 0) {
+                    true => flag_7 & 0xF0,
+                    false => 0,
+                };
+
+                NESHeader {
+                    flag_6: flag_6,
+                    nes2: false,
+                    mapper: ((flag_6 >> 4) | mapper_hi) as u16,
+                    submapper: 0,
+                    prg_rom_sz: cart[4] as usize * 0x4000,
+                    chr_rom_sz: cart[5] as usize * 0x2000,
+                    // iNES counts PRG-RAM in 8K units, with 0 meaning 8K
+                    prg_ram_sz: cmp::max(cart[8] as usize, 1) * 0x2000,
+                    prg_nvram_sz: 0,
+                    chr_ram_sz: if cart[5] == 0 { 0x2000 } else { 0 },
+                    chr_nvram_sz: 0,
+                    trainer: flag_6 & 0b100 == 0b100,
+                    battery: flag_6 & 0b10 == 0b10,
+                    timing: match cart[9] & 1 {
+                        0 => Timing::Ntsc,
+                        _ => Timing::Pal,
+                    },
+                    console: console,
+                    misc_roms: 0,
+                    expansion: 0,
+                }
+            }
+        };
+
+        Ok(header)
     }
 }
 
@@ -86,7 +207,9 @@ impl<'a> From<&'a Vec<u8>> for NESHeader {
 pub struct NESCart {
     pub header: NESHeader,
     pub prg_rom: Vec<u8>,
+    /// CHR-ROM, or the CHR-RAM when the header has no CHR-ROM
     pub chr_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
 }
 
 impl fmt::Debug for NESCart {
@@ -95,36 +218,204 @@ impl fmt::Debug for NESCart {
     }
 }
 
-impl From<Vec<u8>> for NESCart {
-    fn from(cart: Vec<u8>) -> Self {
-        let header = NESHeader::from(&cart);
+impl NESCart {
+    /// Whether the board has CHR-RAM in place of CHR-ROM.
+    pub fn chr_ram(&self) -> bool {
+        self.header.chr_rom_sz == 0
+    }
 
-        let prg_size = match header.prg_rom_sz < 0x8000 {
-            true => 0x8000,
-            false => header.prg_rom_sz,
-        };
+    /// Builds the mapper named in the header around this cart.
+    pub fn into_mapper(self) -> Result<Box<dyn Mapper>, RomError> {
+        match self.header.mapper {
+            0 => Ok(Box::new(NROM::new(self))),
+            1 => Ok(Box::new(MMC1::new(self))),
+            2 => Ok(Box::new(UxROM::new(self))),
+            3 => Ok(Box::new(CNROM::new(self))),
+            4 => Ok(Box::new(MMC3::new(self))),
+            7 => Ok(Box::new(AxROM::new(self))),
+            mapper => Err(RomError::UnsupportedMapper {
+                mapper: mapper,
+                submapper: self.header.submapper,
+            }),
+        }
+    }
+}
 
-        let mut prg_rom = vec![0u8; prg_size];
-        let mut chr_rom = vec![0u8; header.chr_rom_sz];
+/// Takes `len` bytes for `section` off the front of `data`.
+fn section<'a>(data: &mut &'a [u8], section: &'static str, len: usize) -> Result<&'a [u8], RomError> {
+    if data.len() < len {
+        return Err(RomError::Truncated {
+            section: section,
+            expected: len,
+            found: data.len(),
+        });
+    }
+
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Ok(head)
+}
 
-        assert!(header.prg_rom_sz == cart[16..header.prg_rom_sz + 16].len());
-        assert!(header.chr_rom_sz == cart[16 + header.prg_rom_sz..16 + header.prg_rom_sz + header.chr_rom_sz].len());
+impl TryFrom<Vec<u8>> for NESCart {
+    type Error = RomError;
+
+    fn try_from(cart: Vec<u8>) -> Result<Self, RomError> {
+        let header = NESHeader::try_from(&cart[..])?;
+        if header.prg_rom_sz == 0 {
+            return Err(RomError::NoPrgRom);
+        }
 
-        if header.prg_rom_sz < 0x8000 {
-            let mut tmp = Vec::<u8>::new();
-            tmp.extend_from_slice(&cart[16..header.prg_rom_sz + 16]);
-            tmp.extend_from_slice(&cart[16..header.prg_rom_sz + 16]);
+        let mut data = &cart[16..];
+        let trainer = match header.trainer {
+            true => Some(section(&mut data, "trainer", 0x200)?),
+            false => None,
+        };
+        let prg_rom = section(&mut data, "PRG-ROM", header.prg_rom_sz)?.to_vec();
+        let chr_rom = match header.chr_rom_sz {
+            0 => vec![0u8; cmp::max(header.chr_ram_sz + header.chr_nvram_sz, 0x2000)],
+            len => section(&mut data, "CHR-ROM", len)?.to_vec(),
+        };
 
-            prg_rom.copy_from_slice(&tmp.as_slice());
-        } else {
-            prg_rom.copy_from_slice(&cart[16..header.prg_rom_sz + 16]);
+        // Mappers expect at least the usual 8K at $6000
+        let mut prg_ram = vec![0u8; cmp::max(header.prg_ram_sz + header.prg_nvram_sz, 0x2000)];
+        if let Some(trainer) = trainer {
+            prg_ram[0x1000..0x1200].copy_from_slice(trainer);
         }
-        chr_rom.copy_from_slice(&cart[16 + header.prg_rom_sz..16 + header.prg_rom_sz + header.chr_rom_sz]);
 
-        NESCart {
+        Ok(NESCart {
             header: header,
             prg_rom: prg_rom,
             chr_rom: chr_rom,
+            prg_ram: prg_ram,
+        })
+    }
+}
+
+/// Only the RAM on the board changes, so that's all that gets saved.
+impl Snapshot for NESCart {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.prg_ram.save(out);
+        if self.chr_ram() {
+            self.chr_rom.save(out);
         }
     }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        self.prg_ram.load(input)?;
+        if self.chr_ram() {
+            self.chr_rom.load(input)?;
+        }
+        Ok(())
+    }
+}
+
+/// An iNES image for tests around whole 16K banks of `prg` and 8K banks of
+/// `chr`, with CHR-RAM when `chr` is empty. `flags` are the low bits of
+/// byte 6: mirroring, battery, trainer and four-screen.
+#[cfg(test)]
+pub fn test_rom(mapper: u8, flags: u8, prg: &[u8], chr: &[u8]) -> Vec<u8> {
+    let mut rom = MAGIC.to_vec();
+    rom.extend_from_slice(&[(prg.len() / 0x4000) as u8, (chr.len() / 0x2000) as u8, mapper << 4 | flags, mapper & 0xF0]);
+    rom.extend_from_slice(&[0; 8]);
+    rom.extend_from_slice(prg);
+    rom.extend_from_slice(chr);
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(header: [u8; 16], len: usize) -> Vec<u8> {
+        let mut rom = header.to_vec();
+        rom.extend(vec![0u8; len]);
+        rom
+    }
+
+    #[test]
+    fn ines() {
+        let cart = NESCart::try_from(rom([0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x13, 0x40, 0, 1, 0, 0, 0, 0, 0, 0], 0xA000)).unwrap();
+        let header = &cart.header;
+
+        assert!(!header.nes2);
+        assert_eq!(header.mapper, 0x41);
+        assert_eq!(header.mirroring(), Mirroring::Vertical);
+        assert!(header.battery);
+        assert_eq!(header.timing, Timing::Pal);
+        assert_eq!(cart.prg_rom.len(), 0x8000);
+        assert_eq!(cart.chr_rom.len(), 0x2000);
+        assert_eq!(cart.prg_ram.len(), 0x2000);
+    }
+
+    #[test]
+    fn ines_ignores_garbage_in_the_padding() {
+        let mut header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x10, 0x40, 0, 0, 0, 0, 0, 0, 0, 0];
+        header[12..16].copy_from_slice(b"Dude");
+        let header = NESHeader::try_from(&header[..]).unwrap();
+        assert_eq!(header.mapper, 1);
+    }
+
+    #[test]
+    fn nes2() {
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x42, 0x18, 0x31, 0x00, 0x97, 0x07, 0x03, 0x00, 0x01, 0x01];
+        let header = NESHeader::try_from(&header[..]).unwrap();
+
+        assert!(header.nes2);
+        assert_eq!(header.mapper, 0x114);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_ram_sz, 0x2000);
+        assert_eq!(header.prg_nvram_sz, 0x8000);
+        assert_eq!(header.chr_ram_sz, 0x2000);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(header.misc_roms, 1);
+        assert_eq!(header.expansion, 1);
+    }
+
+    #[test]
+    fn nes2_rom_sizes() {
+        // 2^5 * 3 bytes of PRG, and $100 8K banks of CHR
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0b10101, 0x00, 0, 0x08, 0, 0x1F, 0, 0, 0, 0, 0, 0];
+        let header = NESHeader::try_from(&header[..]).unwrap();
+        assert_eq!(header.prg_rom_sz, 96);
+        assert_eq!(header.chr_rom_sz, 0x100 * 0x2000);
+
+        // 2^63 * 7 doesn't fit anywhere
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0xFF, 0x00, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0];
+        assert_eq!(NESHeader::try_from(&header[..]).unwrap_err(), RomError::SizeOverflow("PRG-ROM"));
+    }
+
+    #[test]
+    fn vs_system() {
+        let header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0x09, 0, 0, 0, 0, 0, 0x23, 0, 0];
+        let header = NESHeader::try_from(&header[..]).unwrap();
+        assert_eq!(header.console, Console::VsSystem { ppu: 3, hardware: 2 });
+    }
+
+    #[test]
+    fn trainer_lands_at_7000() {
+        let mut data = rom([0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x200 + 0x4000);
+        data[16] = 0xAB;
+        let cart = NESCart::try_from(data).unwrap();
+        assert_eq!(cart.prg_ram[0x1000], 0xAB);
+        assert_eq!(cart.prg_rom[0], 0x00);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(NESCart::try_from(vec![0x4E, 0x45]).unwrap_err(), RomError::TooShort(2));
+        assert_eq!(NESCart::try_from(rom([0x50, 0x4B, 0x03, 0x04, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x6000)).unwrap_err(),
+                   RomError::BadMagic([0x50, 0x4B, 0x03, 0x04]));
+        assert_eq!(NESCart::try_from(rom([0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x5000)).unwrap_err(),
+                   RomError::Truncated { section: "PRG-ROM", expected: 0x8000, found: 0x5000 });
+        assert_eq!(NESCart::try_from(rom([0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x5000)).unwrap_err(),
+                   RomError::Truncated { section: "CHR-ROM", expected: 0x2000, found: 0x1000 });
+        assert_eq!(NESCart::try_from(rom([0x4E, 0x45, 0x53, 0x1A, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x2000)).unwrap_err(),
+                   RomError::NoPrgRom);
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(RomError::TooShort(3).to_string(), "File is 3 bytes, too short for an iNES header");
+        assert_eq!(RomError::UnsupportedMapper { mapper: 5, submapper: 0 }.to_string(), "Unsupported mapper 5");
+    }
 }
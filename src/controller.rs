@@ -0,0 +1,137 @@
+bitflags! {
+    /// Buttons in the order the controller shifts them out.
+    #[derive(Default)]
+    pub struct Buttons: u8 {
+        const A      = 0b00000001;
+        const B      = 0b00000010;
+        const SELECT = 0b00000100;
+        const START  = 0b00001000;
+        const UP     = 0b00010000;
+        const DOWN   = 0b00100000;
+        const LEFT   = 0b01000000;
+        const RIGHT  = 0b10000000;
+    }
+}
+
+impl Buttons {
+    pub fn from_name(name: &str) -> Option<Buttons> {
+        match name.to_lowercase().as_str() {
+            "a" => Some(Buttons::A),
+            "b" => Some(Buttons::B),
+            "select" => Some(Buttons::SELECT),
+            "start" => Some(Buttons::START),
+            "up" => Some(Buttons::UP),
+            "down" => Some(Buttons::DOWN),
+            "left" => Some(Buttons::LEFT),
+            "right" => Some(Buttons::RIGHT),
+            _ => None,
+        }
+    }
+}
+
+/// A standard controller: a 4021 shift register that latches the buttons
+/// while the strobe is high and shifts one out per read once it's low.
+#[derive(Debug, Clone, Default)]
+pub struct Controller {
+    /// What's held down right now, set by the frontend
+    pub buttons: Buttons,
+    strobe: bool,
+    shift: u8,
+    /// Reads since the last latch, past 8 of which the register is empty
+    reads: u8,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Controller::default()
+    }
+
+    /// A $4016 write. Only bit 0, the strobe, reaches the controllers.
+    pub fn write(&mut self, val: u8) {
+        self.strobe = val & 1 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    /// Bit 0 of a $4016/$4017 read.
+    pub fn read(&mut self) -> u8 {
+        // With the strobe high the register keeps reloading, so it's A
+        // every time
+        if self.strobe {
+            self.latch();
+        }
+
+        // Serial data is pulled up, so official controllers read 1 once
+        // all eight buttons are out
+        if self.reads >= 8 {
+            return 1;
+        }
+
+        let bit = self.shift & 1;
+        self.shift >>= 1;
+        self.reads += 1;
+        bit
+    }
+
+    fn latch(&mut self) {
+        self.shift = self.buttons.bits();
+        self.reads = 0;
+    }
+}
+
+// The buttons come from the frontend, not the state
+snapshot!(Controller { strobe, shift, reads });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(controller: &mut Controller) -> Vec<u8> {
+        (0..10).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn shifts_out_in_order() {
+        let mut controller = Controller::new();
+        controller.buttons = Buttons::A | Buttons::START | Buttons::RIGHT;
+        controller.write(1);
+        controller.write(0);
+
+        assert_eq!(read_all(&mut controller), vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn latches_on_strobe() {
+        let mut controller = Controller::new();
+        controller.buttons = Buttons::B;
+        controller.write(1);
+        controller.write(0);
+
+        // Changes after the latch wait for the next strobe
+        controller.buttons = Buttons::A;
+        assert_eq!(read_all(&mut controller)[..2], [0, 1]);
+
+        controller.write(1);
+        controller.write(0);
+        assert_eq!(read_all(&mut controller)[..2], [1, 0]);
+    }
+
+    #[test]
+    fn strobe_high_reads_a() {
+        let mut controller = Controller::new();
+        controller.buttons = Buttons::A;
+        controller.write(1);
+        assert_eq!(read_all(&mut controller), vec![1; 10]);
+
+        controller.buttons = Buttons::B;
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn button_names() {
+        assert_eq!(Buttons::from_name("Start"), Some(Buttons::START));
+        assert_eq!(Buttons::from_name("left"), Some(Buttons::LEFT));
+        assert_eq!(Buttons::from_name("turbo"), None);
+    }
+}
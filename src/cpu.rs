@@ -1,11 +1,8 @@
 use ppuregs::PPUCTL;
 use inst::{Instruction, Value, Opcode};
-use mem::Memory;
-use ppu::PPU;
-
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use mem::Bus;
+use state::{Snapshot, StateError};
+use trace::{TraceRecord, Tracer};
 
 use std::fmt;
 
@@ -23,11 +20,19 @@ bitflags! {
     }
 }
 
+/// How to treat the unstable undocumented opcodes (XAA, LXA, AHX, TAS,
+/// SHX, SHY and LAS), whose results vary between individual chips.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unstable {
+    /// Stop with an error, like the emulator did for every unknown opcode
+    Reject,
+    /// Emulate the common behaviour, with `magic` as the constant that XAA
+    /// and LXA OR into A
+    Emulate { magic: u8 },
+}
+
 #[derive(Debug, Clone)]
 pub struct NMOS6502 {
-    mem: Arc<Mutex<Memory>>,
-    ppu: Arc<Mutex<PPU>>,
-
     a: u8,
 
     x: u8,
@@ -38,970 +43,1640 @@ pub struct NMOS6502 {
     pc: u16,
 
     p_flags: PFlag,
+
+    pub cycles: u64,
+    stall: u16,
+    page_crossed: bool,
+    /// Base cycle count of the instruction being run, which places its
+    /// operand access
+    inst_cycles: u16,
+
+    pub unstable: Unstable,
+    halted: bool,
+
+    /// An NMI edge waiting for the current instruction to finish
+    nmi_pending: bool,
+    /// Whether the IRQ poll at the end of the last instruction saw the I
+    /// flag set. CLI, SEI and PLP change I only after the poll.
+    irq_inhibit: bool,
+    /// The last step was BRK or an IRQ, whose vector an NMI can take over
+    hijackable: bool,
+
+    tracer: Option<Tracer>,
 }
 
 impl NMOS6502 {
-    pub fn new(mem: Arc<Mutex<Memory>>, ppu: Arc<Mutex<PPU>>) -> Self {
+    pub fn new() -> Self {
         let mut p = PFlag::empty();
         p.insert(PFlag::FLAG_I);
         p.insert(PFlag::FLAG_X);
         NMOS6502 {
-            mem: mem,
-            ppu: ppu,
             a: 0u8,
             x: 0u8,
             y: 0u8,
-            sp: 0xFFu8,
+            sp: 0x00u8,
             pc: 0u16,
             p_flags: p,
+            cycles: 0u64,
+            stall: 0u16,
+            page_crossed: false,
+            inst_cycles: 0u16,
+            unstable: Unstable::Reject,
+            halted: false,
+            nmi_pending: false,
+            irq_inhibit: true,
+            hijackable: false,
+            tracer: None,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.pc = (self.read8(0xFFFD) as u16) << 8 | (self.read8(0xFFFC) as u16);
+    pub fn reset<B: Bus>(&mut self, bus: &mut B) {
+        self.pc = self.vector(bus, 0xFFFC);
+        // Reset runs the interrupt sequence with the stack writes suppressed
+        self.sp = self.sp.wrapping_sub(3);
+        self.p_flags.insert(PFlag::FLAG_I);
+        self.cycles += 7;
+        self.halted = false;
+        self.nmi_pending = false;
+        self.irq_inhibit = true;
+        self.hijackable = false;
     }
 
-    pub fn step(&mut self) -> Result<u8, String> {
-        print!("0x{:04X}: ", self.pc);
-        let (adv, inst) = Instruction::get(&mut self.clone());
-        self.pc += adv;
-        match inst {
-            Instruction(Opcode::LDA, Value::Immediate(val)) => {
-                println!("LDA #${:02X}", val);
-
-                self.a = val;
-
-                self.set_nz(val);
-
-                Ok(0u8)
-            }
-            Instruction(Opcode::LDA, Value::Absolute(addr)) => {
-                let val = self.read8(addr);
-                println!("LDA ${:04X} = {:02X}", addr, val);
-                self.a = val;
-
-                self.set_nz(val);
+    /// Whether a JAM opcode has locked up the CPU until the next reset.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::STA, Value::Absolute(addr)) => {
-                let a = self.a;
-                println!("STA @{:04X} = {:02X}", addr, a);
+    /// Runs one instruction, or enters a pending interrupt, and returns
+    /// the number of CPU cycles it took including any DMA stall.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> Result<u16, String> {
+        if self.halted {
+            // The rest of the machine keeps running while the CPU is stuck
+            self.cycles += 1;
+            return Ok(1);
+        }
 
-                self.write8(addr, a);
+        self.hijackable = false;
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::BPL, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BPL ${:04X}", pc);
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            return Ok(self.interrupt(bus, 0xFFFA));
+        }
 
-                if !self.p_flags.contains(PFlag::FLAG_N) {
-                    self.pc = pc;
-                }
+        if bus.irq() && !self.irq_inhibit {
+            let cycles = self.interrupt(bus, 0xFFFE);
+            self.hijackable = true;
+            return Ok(cycles);
+        }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::BMI, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BMI ${:04X}", pc);
+        let (adv, inst) = Instruction::get(bus, self.pc);
+        if let Some(ref tracer) = self.tracer {
+            tracer.trace(&self.trace_record(bus, adv, inst));
+        }
+        self.pc = self.pc.wrapping_add(adv);
+        self.page_crossed = false;
 
-                if self.p_flags.contains(PFlag::FLAG_N) {
-                    self.pc = pc;
-                }
+        let mut cycles = inst.cycles() as u16;
+        self.inst_cycles = cycles;
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::JMP, Value::Absolute(addr)) => {
-                println!("JMP ${:04X}", addr);
+        let Instruction(op, val) = inst;
+        if op.is_unstable() && self.unstable == Unstable::Reject {
+            return Err(format!("Unstable opcode {:?}", inst));
+        }
 
-                self.pc = addr;
+        let i_before = self.p_flags.contains(PFlag::FLAG_I);
 
-                Ok(0u8)
+        match op {
+            Opcode::LDA => {
+                let val = self.load(bus, val);
+                self.a = val;
+                self.set_nz(val);
             }
-            Instruction(Opcode::LDX, Value::Immediate(val)) => {
-                println!("LDX #${:02X}", val);
-
+            Opcode::LDX => {
+                let val = self.load(bus, val);
                 self.x = val;
-
                 self.set_nz(val);
-
-                Ok(0u8)
             }
-            Instruction(Opcode::STX, Value::ZeroPage(zpg)) => {
-                println!("STX ${:02X}", zpg);
-
+            Opcode::LDY => {
+                let val = self.load(bus, val);
+                self.y = val;
+                self.set_nz(val);
+            }
+            Opcode::STA => {
+                let a = self.a;
+                self.store(bus, val, a);
+            }
+            Opcode::STX => {
                 let x = self.x;
-                self.write8(zpg as u16, x);
-
-                Ok(0u8)
+                self.store(bus, val, x);
             }
-            Instruction(Opcode::JSR, Value::Absolute(addr)) => {
-                println!("JSR ${:04X}", addr);
-
-                let pc = self.pc;
-                self.push16(pc - 1);
-                self.pc = addr;
-
-                Ok(0u8)
+            Opcode::STY => {
+                let y = self.y;
+                self.store(bus, val, y);
             }
-            Instruction(Opcode::NOP, Value::Implied) => {
-                println!("NOP");
-
-                Ok(0u8)
+            Opcode::TAX => {
+                let a = self.a;
+                self.x = a;
+                self.set_nz(a);
             }
-            Instruction(Opcode::SEC, Value::Implied) => {
-                println!("SEC");
-
-                self.set_carry(true);
-
-                Ok(0u8)
+            Opcode::TAY => {
+                let a = self.a;
+                self.y = a;
+                self.set_nz(a);
             }
-            Instruction(Opcode::BCS, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BCS ${:04X}", pc);
-
-                if self.p_flags.contains(PFlag::FLAG_C) {
-                    self.pc = pc;
-                }
-
-                Ok(0u8)
+            Opcode::TXA => {
+                let x = self.x;
+                self.a = x;
+                self.set_nz(x);
             }
-            Instruction(Opcode::CLC, Value::Implied) => {
-                println!("CLC");
-
-                self.set_carry(false);
-
-                Ok(0u8)
+            Opcode::TYA => {
+                let y = self.y;
+                self.a = y;
+                self.set_nz(y);
             }
-            Instruction(Opcode::BCC, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BCC ${:04X}", pc);
-
-                if !self.p_flags.contains(PFlag::FLAG_C) {
-                    self.pc = pc;
-                }
-
-                Ok(0u8)
+            Opcode::TSX => {
+                let sp = self.sp;
+                self.x = sp;
+                self.set_nz(sp);
             }
-            Instruction(Opcode::BEQ, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BEQ ${:04X}", pc);
-
-                if self.p_flags.contains(PFlag::FLAG_Z) {
-                    self.pc = pc;
-                }
-
-                Ok(0u8)
+            Opcode::TXS => {
+                self.sp = self.x;
             }
-            Instruction(Opcode::BNE, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BNE ${:04X}", pc);
-
-                if !self.p_flags.contains(PFlag::FLAG_Z) {
-                    self.pc = pc;
-                }
-
-                Ok(0u8)
+            Opcode::PHA => {
+                let a = self.a;
+                self.push8(bus, a);
             }
-            Instruction(Opcode::SEI, Value::Implied) => {
-                println!("SEI");
-
-                self.p_flags.insert(PFlag::FLAG_I);
-
-                Ok(0u8)
+            Opcode::PHP => {
+                // B and the unused bit are always set in the pushed copy
+                let p = self.p_flags.bits | PFlag::FLAG_B.bits | PFlag::FLAG_X.bits;
+                self.push8(bus, p);
             }
-            Instruction(Opcode::CLD, Value::Implied) => {
-                println!("CLD");
-
-                self.p_flags.remove(PFlag::FLAG_D);
-
-                Ok(0u8)
+            Opcode::PLA => {
+                let val = self.pop8(bus);
+                self.a = val;
+                self.set_nz(val);
             }
-            Instruction(Opcode::STA, Value::ZeroPage(zpg)) => {
-                println!("STA ${:02X} = {:02X}", zpg, self.read8(zpg as u16));
-
-                let a = self.a;
-                self.write8(zpg as u16, a);
-
-                Ok(0u8)
+            Opcode::PLP => {
+                let p = self.pop8(bus);
+                self.set_p(p);
             }
-            Instruction(Opcode::BIT, Value::ZeroPage(zpg)) => {
-                let val = self.read8(zpg as u16);
-                println!("BIT ${:02X} = {:02X}", zpg, val);
-
-                if val & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
-
-                if val & 0x40 == 0x40 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
-                }
-
-                if val & self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
-
-                Ok(0u8)
+            Opcode::AND => {
+                let val = self.load(bus, val);
+                let a = self.a & val;
+                self.a = a;
+                self.set_nz(a);
             }
-            Instruction(Opcode::TXS, Value::Implied) => {
-                println!("TXS");
-
-                self.sp = self.x;
-
-                Ok(0u8)
+            Opcode::ORA => {
+                let val = self.load(bus, val);
+                let a = self.a | val;
+                self.a = a;
+                self.set_nz(a);
             }
-            Instruction(Opcode::BVS, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BVS ${:04X}", pc);
-
-                if self.p_flags.contains(PFlag::FLAG_V) {
-                    self.pc = pc;
-                }
-
-                Ok(0u8)
+            Opcode::EOR => {
+                let val = self.load(bus, val);
+                let a = self.a ^ val;
+                self.a = a;
+                self.set_nz(a);
             }
-            Instruction(Opcode::BVC, Value::Relative(offs)) => {
-                let pc = ((self.pc as i32 + offs as i32) & 0xFFFF) as u16;
-                println!("BVC ${:04X}", pc);
-
-                if !self.p_flags.contains(PFlag::FLAG_V) {
-                    self.pc = pc;
-                }
-
-                Ok(0u8)
+            Opcode::BIT => {
+                let val = self.load(bus, val);
+                self.p_flags.set(PFlag::FLAG_N, val & 0x80 == 0x80);
+                self.p_flags.set(PFlag::FLAG_V, val & 0x40 == 0x40);
+                self.p_flags.set(PFlag::FLAG_Z, val & self.a == 0);
+            }
+            Opcode::ADC => {
+                let val = self.load(bus, val);
+                self.adc(val);
+            }
+            Opcode::SBC => {
+                let val = self.load(bus, val);
+                self.adc(val ^ 0xFF);
+            }
+            Opcode::CMP => {
+                let (reg, val) = (self.a, self.load(bus, val));
+                self.compare(reg, val);
+            }
+            Opcode::CPX => {
+                let (reg, val) = (self.x, self.load(bus, val));
+                self.compare(reg, val);
+            }
+            Opcode::CPY => {
+                let (reg, val) = (self.y, self.load(bus, val));
+                self.compare(reg, val);
+            }
+            Opcode::INC => self.modify(bus, val, |cpu, val| {
+                let val = val.wrapping_add(1);
+                cpu.set_nz(val);
+                val
+            }),
+            Opcode::DEC => self.modify(bus, val, |cpu, val| {
+                let val = val.wrapping_sub(1);
+                cpu.set_nz(val);
+                val
+            }),
+            Opcode::INX => {
+                let x = self.x.wrapping_add(1);
+                self.x = x;
+                self.set_nz(x);
             }
-            Instruction(Opcode::RTS, Value::Implied) => {
-                println!("RTS");
-
-                self.pc = self.pop16() + 1;
-
-                Ok(0u8)
+            Opcode::INY => {
+                let y = self.y.wrapping_add(1);
+                self.y = y;
+                self.set_nz(y);
             }
-            Instruction(Opcode::PHP, Value::Implied) => {
-                println!("PHP");
-
-                let p = self.p_flags.bits;
-                self.push8(p);
-
-                Ok(0u8)
+            Opcode::DEX => {
+                let x = self.x.wrapping_sub(1);
+                self.x = x;
+                self.set_nz(x);
             }
-            Instruction(Opcode::SED, Value::Implied) => {
-                println!("SED");
-
-                self.p_flags.insert(PFlag::FLAG_D);
+            Opcode::DEY => {
+                let y = self.y.wrapping_sub(1);
+                self.y = y;
+                self.set_nz(y);
+            }
+            Opcode::ASL => self.modify(bus, val, Self::asl),
+            Opcode::LSR => self.modify(bus, val, Self::lsr),
+            Opcode::ROL => self.modify(bus, val, Self::rol),
+            Opcode::ROR => self.modify(bus, val, Self::ror),
+            Opcode::JMP => {
+                self.pc = self.operand_addr(bus, val);
+            }
+            Opcode::JSR => {
+                let pc = self.pc;
+                self.push16(bus, pc.wrapping_sub(1));
+                self.pc = self.operand_addr(bus, val);
+            }
+            Opcode::RTS => {
+                self.pc = self.pop16(bus).wrapping_add(1);
+            }
+            Opcode::RTI => {
+                let p = self.pop8(bus);
+                self.set_p(p);
+                self.pc = self.pop16(bus);
+            }
+            Opcode::BRK => {
+                // The return address skips the signature byte after the
+                // opcode, which handlers find through the pushed PC
+                let pc = self.pc.wrapping_add(1);
+                let p = self.p_flags.bits | PFlag::FLAG_B.bits | PFlag::FLAG_X.bits;
+                self.push16(bus, pc);
+                self.push8(bus, p);
+                self.p_flags.insert(PFlag::FLAG_I);
 
-                Ok(0u8)
+                self.pc = self.vector(bus, 0xFFFE);
+                self.hijackable = true;
+            }
+            Opcode::BPL => {
+                let cond = !self.p_flags.contains(PFlag::FLAG_N);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BMI => {
+                let cond = self.p_flags.contains(PFlag::FLAG_N);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BVC => {
+                let cond = !self.p_flags.contains(PFlag::FLAG_V);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BVS => {
+                let cond = self.p_flags.contains(PFlag::FLAG_V);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BCC => {
+                let cond = !self.p_flags.contains(PFlag::FLAG_C);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BCS => {
+                let cond = self.p_flags.contains(PFlag::FLAG_C);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BNE => {
+                let cond = !self.p_flags.contains(PFlag::FLAG_Z);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::BEQ => {
+                let cond = self.p_flags.contains(PFlag::FLAG_Z);
+                cycles += self.branch(cond, val);
+            }
+            Opcode::CLC => self.set_carry(false),
+            Opcode::SEC => self.set_carry(true),
+            Opcode::CLI => self.p_flags.remove(PFlag::FLAG_I),
+            Opcode::SEI => self.p_flags.insert(PFlag::FLAG_I),
+            Opcode::CLV => self.p_flags.remove(PFlag::FLAG_V),
+            Opcode::CLD => self.p_flags.remove(PFlag::FLAG_D),
+            Opcode::SED => self.p_flags.insert(PFlag::FLAG_D),
+            Opcode::NOP => {
+                // The undocumented variants still perform their read
+                if val != Value::Implied {
+                    self.load(bus, val);
+                }
             }
-            Instruction(Opcode::PLA, Value::Implied) => {
-                println!("PLA");
-                let val = self.pop8();
+            Opcode::SLO => self.modify(bus, val, |cpu, val| {
+                let val = cpu.asl(val);
+                let a = cpu.a | val;
+                cpu.a = a;
+                cpu.set_nz(a);
+                val
+            }),
+            Opcode::RLA => self.modify(bus, val, |cpu, val| {
+                let val = cpu.rol(val);
+                let a = cpu.a & val;
+                cpu.a = a;
+                cpu.set_nz(a);
+                val
+            }),
+            Opcode::SRE => self.modify(bus, val, |cpu, val| {
+                let val = cpu.lsr(val);
+                let a = cpu.a ^ val;
+                cpu.a = a;
+                cpu.set_nz(a);
+                val
+            }),
+            Opcode::RRA => self.modify(bus, val, |cpu, val| {
+                let val = cpu.ror(val);
+                cpu.adc(val);
+                val
+            }),
+            Opcode::DCP => self.modify(bus, val, |cpu, val| {
+                let val = val.wrapping_sub(1);
+                let a = cpu.a;
+                cpu.compare(a, val);
+                val
+            }),
+            Opcode::ISB => self.modify(bus, val, |cpu, val| {
+                let val = val.wrapping_add(1);
+                cpu.adc(val ^ 0xFF);
+                val
+            }),
+            Opcode::SAX => {
+                let ax = self.a & self.x;
+                self.store(bus, val, ax);
+            }
+            Opcode::LAX => {
+                let val = self.load(bus, val);
                 self.a = val;
-
-                let a = self.a;
-                self.set_nz(a);
-
-                Ok(0u8)
+                self.x = val;
+                self.set_nz(val);
             }
-            Instruction(Opcode::AND, Value::Immediate(val)) => {
-                println!("AND ${:02X}", val);
-                let a = self.a & val;
+            Opcode::ANC => {
+                let a = self.a & self.load(bus, val);
                 self.a = a;
-
-                let a = self.a;
                 self.set_nz(a);
-
-                Ok(0u8)
+                self.set_carry(a & 0x80 == 0x80);
             }
-            Instruction(Opcode::CMP, Value::Immediate(val)) => {
-                println!("CMP #${:02X}", val);
-
-                let t = self.a;
-                let temp = self.a.wrapping_sub(val);
-                self.set_nz(temp);
-                self.set_carry(t >= val);
-
-                Ok(0u8)
+            Opcode::ALR => {
+                let a = self.a & self.load(bus, val);
+                self.a = self.lsr(a);
             }
-            Instruction(Opcode::PHA, Value::Implied) => {
-                println!("PHA");
-
-                let a = self.a;
-                self.push8(a);
-
-                Ok(0u8)
+            Opcode::ARR => {
+                let a = self.a & self.load(bus, val);
+                let carry = self.p_flags.contains(PFlag::FLAG_C) as u8;
+                let a = (a >> 1) | (carry << 7);
+                self.a = a;
+                self.set_nz(a);
+                self.set_carry(a & 0x40 == 0x40);
+                self.p_flags.set(PFlag::FLAG_V, ((a >> 6) ^ (a >> 5)) & 0b1 == 0b1);
+            }
+            Opcode::AXS => {
+                let ax = self.a & self.x;
+                let val = self.load(bus, val);
+                let x = ax.wrapping_sub(val);
+                self.x = x;
+                self.set_nz(x);
+                self.set_carry(ax >= val);
             }
-            Instruction(Opcode::PLP, Value::Implied) => {
-                println!("PLP");
-
-                let mut res = self.pop8();
-                res = res & 0b11001111;
-                self.p_flags.bits = res;
-
-                Ok(0u8)
+            Opcode::XAA => {
+                let a = (self.a | self.magic()) & self.x & self.load(bus, val);
+                self.a = a;
+                self.set_nz(a);
             }
-            Instruction(Opcode::ORA, Value::Immediate(val)) => {
-                println!("ORA ${:02X}", val);
-                
-                self.a = self.a | val;
-
-                let a = self.a;
+            Opcode::LXA => {
+                let a = (self.a | self.magic()) & self.load(bus, val);
+                self.a = a;
+                self.x = a;
                 self.set_nz(a);
-
-                Ok(0u8)
             }
-            Instruction(Opcode::CLV, Value::Implied) => {
-                println!("CLV");
-
-                self.p_flags.remove(PFlag::FLAG_V);
-
-                Ok(0u8)
+            Opcode::LAS => {
+                let val = self.load(bus, val) & self.sp;
+                self.a = val;
+                self.x = val;
+                self.sp = val;
+                self.set_nz(val);
             }
-            Instruction(Opcode::EOR, Value::Immediate(val)) => {
-                println!("EOR ${:02X}", val);
-
-                self.a = self.a ^ val;
-
-                let a = self.a;
-                self.set_nz(a);
-
-                Ok(0u8)
+            Opcode::AHX => {
+                let ax = self.a & self.x;
+                self.store_high_and(bus, val, ax);
             }
-            Instruction(Opcode::ADC, Value::Immediate(val)) => {
-                println!("ADC ${:02X}", val);
-                let ainit = self.a;
-
-                let mut a: u16 = self.a as u16;
-                a += val as u16;
-                a += (self.p_flags.bits & 0b1) as u16;
-                self.a = (a & 0xFF) as u8;
-
-                if self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
-
-                if (self.a as i8) < 0 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
-
-                self.set_carry(a & 0x100 == 0x100);
-
-                if (ainit ^ self.a) & (val ^ self.a) & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
-                }
-
-                Ok(0u8)
+            Opcode::TAS => {
+                self.sp = self.a & self.x;
+                let sp = self.sp;
+                self.store_high_and(bus, val, sp);
             }
-            Instruction(Opcode::CPY, Value::Immediate(val)) => {
-                println!("CPY #${:02X}", val);
-
-                let t = self.y;
-                let temp = self.y.wrapping_sub(val);
-                self.set_nz(temp);
-                self.set_carry(t >= val);
-
-                Ok(0u8)
+            Opcode::SHX => {
+                let x = self.x;
+                self.store_high_and(bus, val, x);
             }
-            Instruction(Opcode::LDY, Value::Immediate(val)) => {
-                println!("LDY #${:02X}", val);
-
-                self.y = val;
-                self.set_nz(val);
-
-                Ok(0u8)
+            Opcode::SHY => {
+                let y = self.y;
+                self.store_high_and(bus, val, y);
             }
-            Instruction(Opcode::CPX, Value::Immediate(val)) => {
-                println!("CPX #${:02X}", val);
-
-                let t = self.x;
-                let temp = self.x.wrapping_sub(val);
-                self.set_nz(temp);
-                self.set_carry(t >= val);
-
-                Ok(0u8)
+            Opcode::JAM => {
+                self.pc = self.pc.wrapping_sub(1);
+                self.halted = true;
             }
-            Instruction(Opcode::SBC, Value::Immediate(val)) => {
-                println!("SBC ${:02X}", val);
-                let ainit = self.a;
-                let val = val ^ 0xFF;
+        }
 
-                let mut a: u16 = self.a as u16;
-                a += val as u16;
-                a += (self.p_flags.bits & 0b1) as u16;
-                self.a = (a & 0xFF) as u8;
+        self.irq_inhibit = match op {
+            Opcode::CLI | Opcode::SEI | Opcode::PLP => i_before,
+            _ => self.p_flags.contains(PFlag::FLAG_I),
+        };
 
-                if self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
+        cycles += self.stall + bus.dma_stall(self.cycles);
+        self.stall = 0;
+        self.cycles += cycles as u64;
 
-                if (self.a as i8) < 0 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
+        Ok(cycles)
+    }
 
-                self.set_carry(a & 0x100 == 0x100);
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
 
-                if (ainit ^ self.a) & (val ^ self.a) & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
-                }
+    fn trace_record<B: Bus>(&self, bus: &B, len: u16, inst: Instruction) -> TraceRecord {
+        let pc = self.pc;
+        let bytes = (0..len).map(|i| bus.peek8(pc.wrapping_add(i))).collect();
+
+        let next = pc.wrapping_add(len);
+        let (addr, ptr) = match inst.1 {
+            Value::Implied | Value::Accumulator | Value::Immediate(_) => (None, None),
+            Value::Relative(offs) => (Some(next.wrapping_add(offs as i16 as u16)), None),
+            Value::Absolute(addr) => (Some(addr), None),
+            Value::AbsoluteX(addr) => (Some(addr.wrapping_add(self.x as u16)), None),
+            Value::AbsoluteY(addr) => (Some(addr.wrapping_add(self.y as u16)), None),
+            Value::ZeroPage(zpg) => (Some(zpg as u16), None),
+            Value::ZeroPageX(zpg) => (Some(zpg.wrapping_add(self.x) as u16), None),
+            Value::ZeroPageY(zpg) => (Some(zpg.wrapping_add(self.y) as u16), None),
+            Value::Indirect(ind) => {
+                let hi = (ind & 0xFF00) | (ind.wrapping_add(1) & 0x00FF);
+                (Some((bus.peek8(hi) as u16) << 8 | bus.peek8(ind) as u16), Some(ind))
+            }
+            Value::PreIdxIndX(zpg) => {
+                let zaddr = zpg.wrapping_add(self.x);
+                (Some(self.peek_ind_addr(bus, zaddr)), Some(zaddr as u16))
+            }
+            Value::PreIdxIndY(zpg) => {
+                let base = self.peek_ind_addr(bus, zpg);
+                (Some(base.wrapping_add(self.y as u16)), Some(base))
+            }
+        };
+
+        let value = match inst {
+            Instruction(Opcode::JMP, _) | Instruction(Opcode::JSR, _) | Instruction(_, Value::Relative(_)) => None,
+            _ => addr.map(|addr| bus.peek8(addr)),
+        };
+
+        let (scanline, dot) = bus.ppu_position();
+
+        TraceRecord {
+            pc: pc,
+            bytes: bytes,
+            inst: inst,
+            addr: addr,
+            ptr: ptr,
+            value: value,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            p: self.p_flags.bits,
+            sp: self.sp,
+            scanline: scanline,
+            dot: dot,
+            cycles: self.cycles,
+        }
+    }
 
-                Ok(0u8)
+    /// Resolves the effective address of a memory operand.
+    fn operand_addr<B: Bus>(&mut self, bus: &mut B, val: Value) -> u16 {
+        match val {
+            Value::Absolute(addr) => addr,
+            Value::AbsoluteX(addr) => {
+                let x = self.x;
+                self.indexed(addr, x)
             }
-            Instruction(Opcode::INY, Value::Implied) => {
-                println!("INY");
-
-                let y = self.y;
-                self.y = y.wrapping_add(1);
-
+            Value::AbsoluteY(addr) => {
                 let y = self.y;
-                self.set_nz(y);
-
-                Ok(0u8)
+                self.indexed(addr, y)
             }
-            Instruction(Opcode::INX, Value::Implied) => {
-                println!("INX");
-
-                let x = self.x;
-                self.x = x.wrapping_add(1);
-
-                let x = self.x;
-                self.set_nz(x);
-
-                Ok(0u8)
+            Value::ZeroPage(zpg) => zpg as u16,
+            Value::ZeroPageX(zpg) => zpg.wrapping_add(self.x) as u16,
+            Value::ZeroPageY(zpg) => zpg.wrapping_add(self.y) as u16,
+            Value::PreIdxIndX(offs) => {
+                let zaddr = offs.wrapping_add(self.x);
+                self.get_ind_addr(bus, zaddr)
             }
-            Instruction(Opcode::DEY, Value::Implied) => {
-                println!("DEY");
-
-                let y = self.y;
-                self.y = y.wrapping_sub(1);
-
-                let y = self.y;
-                self.set_nz(y);
-
-                Ok(0u8)
+            Value::PreIdxIndY(offs) => {
+                let (base, y) = (self.get_ind_addr(bus, offs), self.y);
+                self.indexed(base, y)
             }
-            Instruction(Opcode::DEX, Value::Implied) => {
-                println!("DEX");
-
-                let x = self.x;
-                self.x = x.wrapping_sub(1);
-
-                let x = self.x;
-                self.set_nz(x);
-
-                Ok(0u8)
+            Value::Indirect(addr) => {
+                // The high byte is fetched without carrying into the page
+                let hi = (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF);
+                (bus.read8(hi) as u16) << 8 | bus.read8(addr) as u16
             }
-            Instruction(Opcode::TAY, Value::Implied) => {
-                println!("TAY");
-
-                let a = self.a;
-                self.y = a;
+            val => panic!("No effective address for {:?}", val),
+        }
+    }
 
-                self.set_nz(a);
+    fn indexed(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        self.page_crossed = (base & 0xFF00) != (addr & 0xFF00);
+        addr
+    }
 
-                Ok(0u8)
+    /// Fetches a read operand. Indexed reads that cross a page take an
+    /// extra cycle, which `step` picks up through `stall`.
+    fn load<B: Bus>(&mut self, bus: &mut B, val: Value) -> u8 {
+        match val {
+            Value::Immediate(val) => val,
+            Value::Accumulator => self.a,
+            val => {
+                let addr = self.operand_addr(bus, val);
+                if self.page_crossed {
+                    self.stall += 1;
+                }
+                // Reads land on the last cycle, page crossing included
+                bus.sync(self.inst_cycles + self.stall - 1);
+                bus.read8(addr)
             }
-            Instruction(Opcode::TAX, Value::Implied) => {
-                println!("TAX");
-
-                let a = self.a;
-                self.x = a;
+        }
+    }
 
-                self.set_nz(a);
+    fn store<B: Bus>(&mut self, bus: &mut B, val: Value, data: u8) {
+        let addr = self.operand_addr(bus, val);
+        bus.sync(self.inst_cycles - 1);
+        bus.write8(addr, data);
+    }
 
-                Ok(0u8)
+    /// Read-modify-write on either the accumulator or memory.
+    fn modify<B, F>(&mut self, bus: &mut B, val: Value, f: F)
+        where B: Bus, F: FnOnce(&mut Self, u8) -> u8
+    {
+        match val {
+            Value::Accumulator => {
+                let a = self.a;
+                self.a = f(self, a);
+            }
+            val => {
+                // The read comes before a cycle writing the old value back
+                // and a last one writing the new
+                let addr = self.operand_addr(bus, val);
+                bus.sync(self.inst_cycles - 3);
+                let data = bus.read8(addr);
+                let res = f(self, data);
+                bus.sync(self.inst_cycles - 1);
+                bus.write8(addr, res);
             }
-            Instruction(Opcode::TXA, Value::Implied) => {
-                println!("TXA");
-
-                let x = self.x;
-                self.a = x;
+        }
+    }
 
-                self.set_nz(x);
+    /// The AHX/TAS/SHX/SHY store: the value is ANDed with the high byte of
+    /// the base address plus one, and a page crossing corrupts the high
+    /// byte of the target with that same value.
+    fn store_high_and<B: Bus>(&mut self, bus: &mut B, val: Value, data: u8) {
+        let addr = self.operand_addr(bus, val);
+        let base_hi = if self.page_crossed {
+            (addr >> 8) as u8
+        } else {
+            ((addr >> 8) as u8).wrapping_add(1)
+        };
+
+        let data = data & base_hi;
+        let addr = match self.page_crossed {
+            true => ((data as u16) << 8) | (addr & 0x00FF),
+            false => addr,
+        };
+
+        bus.sync(self.inst_cycles - 1);
+        bus.write8(addr, data);
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::TYA, Value::Implied) => {
-                println!("TYA");
+    fn magic(&self) -> u8 {
+        match self.unstable {
+            Unstable::Emulate { magic } => magic,
+            Unstable::Reject => 0xFF,
+        }
+    }
 
-                let y = self.y;
-                self.a = y;
+    fn asl(&mut self, val: u8) -> u8 {
+        self.set_carry(val & 0x80 == 0x80);
+        let val = val << 1;
+        self.set_nz(val);
+        val
+    }
 
-                self.set_nz(y);
+    fn lsr(&mut self, val: u8) -> u8 {
+        self.set_carry(val & 0b1 == 0b1);
+        let val = val >> 1;
+        self.set_nz(val);
+        val
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::TSX, Value::Implied) => {
-                println!("TSX");
+    fn rol(&mut self, val: u8) -> u8 {
+        let carry = self.p_flags.contains(PFlag::FLAG_C) as u8;
+        self.set_carry(val & 0x80 == 0x80);
+        let val = (val << 1) | carry;
+        self.set_nz(val);
+        val
+    }
 
-                let sp = self.sp;
-                self.x = sp;
+    fn ror(&mut self, val: u8) -> u8 {
+        let carry = self.p_flags.contains(PFlag::FLAG_C) as u8;
+        self.set_carry(val & 0b1 == 0b1);
+        let val = (val >> 1) | (carry << 7);
+        self.set_nz(val);
+        val
+    }
 
-                self.set_nz(sp);
+    /// Returns the extra cycles taken: one for a taken branch, two if it
+    /// lands on another page.
+    fn branch(&mut self, cond: bool, val: Value) -> u16 {
+        match val {
+            Value::Relative(offs) if cond => {
+                let pc = self.pc;
+                self.pc = pc.wrapping_add(offs as i16 as u16);
 
-                Ok(0u8)
+                if (pc & 0xFF00) != (self.pc & 0xFF00) { 2 } else { 1 }
             }
-            Instruction(Opcode::STX, Value::Absolute(addr)) => {
-                let x = self.x;
-                println!("STX @{:04X} = {:02X}", addr, x);
+            _ => 0,
+        }
+    }
 
-                self.write8(addr, x);
+    fn adc(&mut self, val: u8) {
+        let ainit = self.a;
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::LDX, Value::Absolute(addr)) => {
-                let val = self.read8(addr);
-                println!("LDX @{:04X} = {:02X}", addr, val);
-                self.x = val;
+        let mut a: u16 = self.a as u16;
+        a += val as u16;
+        a += (self.p_flags.bits & 0b1) as u16;
+        self.a = (a & 0xFF) as u8;
 
-                self.set_nz(val);
-
-                Ok(0u8)
-            }
-            Instruction(Opcode::RTI, Value::Implied) => {
-                println!("RTI");
+        let res = self.a;
+        self.set_nz(res);
+        self.set_carry(a & 0x100 == 0x100);
+        self.p_flags.set(PFlag::FLAG_V, (ainit ^ res) & (val ^ res) & 0x80 == 0x80);
+    }
 
-                let p = self.pop8();
-                let ret = self.pop16();
+    fn compare(&mut self, reg: u8, val: u8) {
+        let temp = reg.wrapping_sub(val);
+        self.set_nz(temp);
+        self.set_carry(reg >= val);
+    }
 
-                self.p_flags.bits = p;
-                self.pc = ret;
+    /// Loads P from a pulled byte; B doesn't exist in the register and the
+    /// unused bit always reads back as set.
+    fn set_p(&mut self, val: u8) {
+        self.p_flags.bits = (val & !PFlag::FLAG_B.bits) | PFlag::FLAG_X.bits;
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::LSR, Value::Implied) => {
-                println!("LSR A");
+    /// Signals a falling edge on the NMI line, `cycle` CPU cycles into the
+    /// last step.
+    pub fn nmi<B: Bus>(&mut self, bus: &mut B, cycle: u16) {
+        // Until BRK or an IRQ has fetched its vector, an NMI takes it over.
+        // The pushed P keeps whatever B flag the hijacked sequence set.
+        if self.hijackable && cycle < 4 {
+            self.pc = self.vector(bus, 0xFFFA);
+            self.hijackable = false;
+        } else {
+            self.nmi_pending = true;
+        }
+    }
 
-                let mut a = self.a;
-                self.set_carry(a & 0b1 == 0b1);
-                a >>= 1;
-                self.set_nz(a);
-                self.a = a;
-                
-                Ok(0u8)
-            }
-            Instruction(Opcode::ASL, Value::Implied) => {
-                println!("ASL A");
+    /// The hardware interrupt sequence, which pushes P with B clear.
+    fn interrupt<B: Bus>(&mut self, bus: &mut B, vector: u16) -> u16 {
+        let pc = self.pc;
+        let p = (self.p_flags.bits & !PFlag::FLAG_B.bits) | PFlag::FLAG_X.bits;
+        self.push16(bus, pc);
+        self.push8(bus, p);
+        self.p_flags.insert(PFlag::FLAG_I);
+        self.irq_inhibit = true;
 
-                let mut a = self.a;
-                self.set_carry(a & 0x80 == 0x80);
-                a <<= 1;
-                self.set_nz(a);
-                self.a = a;
-                
-                Ok(0u8)
-            }
-            Instruction(Opcode::ROR, Value::Implied) => {
-                println!("ROR A");
+        self.pc = self.vector(bus, vector);
 
-                let mut a = self.a;
-                let carry = a & 0b1 == 0b1;
-                a >>= 1;
-                if self.p_flags.contains(PFlag::FLAG_C) {
-                    a |= 0x80;
-                }
-                self.set_nz(a);
-                self.set_carry(carry);
-                self.a = a;
-                
-                Ok(0u8)
-            }
-            Instruction(Opcode::ROL, Value::Implied) => {
-                println!("ROL A");
+        let cycles = 7 + bus.dma_stall(self.cycles);
+        self.cycles += cycles as u64;
+        cycles
+    }
 
-                let mut a = self.a;
-                let carry = a & 0x80 == 0x80;
-                a <<= 1;
-                if self.p_flags.contains(PFlag::FLAG_C) {
-                    a |= 0b01;
-                }
-                self.set_nz(a);
-                self.set_carry(carry);
-                self.a = a;
-                
-                Ok(0u8)
-            }
-            Instruction(Opcode::LDA, Value::ZeroPage(offs)) => {
-                let val = self.read8(offs as u16);
-                println!("LDA ${:02X} = {:02X}", offs, val);
-                self.a = val;
+    fn vector<B: Bus>(&mut self, bus: &mut B, addr: u16) -> u16 {
+        (bus.read8(addr + 1) as u16) << 8 | (bus.read8(addr) as u16)
+    }
 
-                self.set_nz(val);
+    pub fn get_ind_addr<B: Bus>(&mut self, bus: &mut B, zaddr: u8) -> u16 {
+        let addrl = bus.read8(zaddr as u16);
+        let zaddr = zaddr.wrapping_add(1);
+        let addrh = bus.read8(zaddr as u16);
+        ((addrh as u16) << 8) | addrl as u16
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::LDA, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("LDA (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr.wrapping_sub(1), addr, val);
+    pub fn set_carry(&mut self, val: bool) {
+        match val {
+            true => self.p_flags.insert(PFlag::FLAG_C),
+            false => self.p_flags.remove(PFlag::FLAG_C),
+        }
+    }
 
-                self.set_nz(val);
-                self.a = val;
+    pub fn set_nz(&mut self, val: u8) { 
+        match val {
+            0 => self.p_flags.insert(PFlag::FLAG_Z),
+            _ => self.p_flags.remove(PFlag::FLAG_Z),
+        }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::BIT, Value::Absolute(addr)) => {
-                let val = self.read8(addr);
-                println!("BIT ${:04X} = {:02X}", addr, val);
+        match val & 0x80 {
+            0x80 => self.p_flags.insert(PFlag::FLAG_N),
+            _ => self.p_flags.remove(PFlag::FLAG_N),
+        }
+    }
 
-                if val & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
+    fn peek_ind_addr<B: Bus>(&self, bus: &B, zaddr: u8) -> u16 {
+        (bus.peek8(zaddr.wrapping_add(1) as u16) as u16) << 8 | bus.peek8(zaddr as u16) as u16
+    }
 
-                if val & 0x40 == 0x40 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
-                }
+    fn push8<B: Bus>(&mut self, bus: &mut B, val: u8) {
+        bus.write8(0x100 | self.sp as u16, val);
+        self.sp = self.sp.wrapping_sub(1);
+    }
 
-                if val & self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
+    fn push16<B: Bus>(&mut self, bus: &mut B, val: u16) {
+        self.push8(bus, (val >> 8) as u8);
+        self.push8(bus, (val >> 0) as u8);
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::STA, Value::ZeroPageX(zpg)) => {
-                let x = self.x;
-                println!("STA ${:02X},X @ {:02X} = {:02X}", zpg, zpg.wrapping_sub(x), self.read8((zpg + x) as u16));
+    fn pop8<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        bus.read8(0x100 | self.sp as u16)
+    }
 
-                let a = self.a;
-                self.write8((zpg + x) as u16, a);
+    fn pop16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        self.pop8(bus) as u16 | ((self.pop8(bus) as u16) << 8)
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::STA, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("STA (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr.wrapping_sub(1), addr, val);
+    pub fn set_pc(&mut self, val: u16) {
+        self.pc = val;
+    }
 
-                let a = self.a;
-                self.write8(addr, a);
+    pub fn set_sp(&mut self, val: u8) {
+        self.sp = val;
+    }
+}
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::ORA, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("ORA (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr.wrapping_sub(1), addr, val);
+impl Snapshot for PFlag {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.bits().save(out)
+    }
 
-                let mut a = self.a;
-                a |= val;
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        let mut bits = 0u8;
+        bits.load(input)?;
+        *self = PFlag::from_bits_truncate(bits);
+        Ok(())
+    }
+}
 
-                self.set_nz(a);
+snapshot!(NMOS6502 {
+    a, x, y, sp, pc, p_flags, cycles, stall, page_crossed, halted,
+    nmi_pending, irq_inhibit, hijackable,
+});
 
-                self.a = a;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::AND, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("AND (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr.wrapping_sub(1), addr, val);
+    use std::ops::{Deref, DerefMut};
 
-                let mut a = self.a;
-                a &= val;
+    const ORG: u16 = 0x0400;
 
-                self.set_nz(a);
+    /// Flat 64K of RAM and an IRQ line
+    struct Ram([u8; 0x10000], bool);
 
-                self.a = a;
+    impl Bus for Ram {
+        fn read8(&mut self, addr: u16) -> u8 {
+            self.0[addr as usize]
+        }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::EOR, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("EOR (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr.wrapping_sub(1), addr, val);
+        fn write8(&mut self, addr: u16, val: u8) {
+            self.0[addr as usize] = val;
+        }
 
-                let mut a = self.a;
-                a ^= val;
+        fn peek8(&self, addr: u16) -> u8 {
+            self.0[addr as usize]
+        }
 
-                self.set_nz(a);
+        fn irq(&self) -> bool {
+            self.1
+        }
+    }
 
-                self.a = a;
+    /// A CPU together with the bus it runs on.
+    struct Rig {
+        cpu: NMOS6502,
+        bus: Ram,
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::STA, Value::AbsoluteX(addr)) => {
-                let a = self.a;
-                let x = self.x;
-                println!("STA @{:04X},X @ {:04X} = {:02X}", addr, addr + x as u16, self.read8(addr + x as u16));
+    impl Rig {
+        fn step(&mut self) -> Result<u16, String> {
+            self.cpu.step(&mut self.bus)
+        }
+    }
 
-                self.write8(addr + x as u16, a);
+    impl Deref for Rig {
+        type Target = NMOS6502;
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::ADC, Value::ZeroPage(zpg)) => {
-                let val = self.read8(zpg as u16);
-                println!("ADC ${:02X}", val);
-                let ainit = self.a;
+        fn deref(&self) -> &NMOS6502 {
+            &self.cpu
+        }
+    }
 
-                let mut a: u16 = self.a as u16;
-                a += val as u16;
-                a += (self.p_flags.bits & 0b1) as u16;
-                self.a = (a & 0xFF) as u8;
+    impl DerefMut for Rig {
+        fn deref_mut(&mut self) -> &mut NMOS6502 {
+            &mut self.cpu
+        }
+    }
 
-                if self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
+    fn cpu_with(prog: &[u8]) -> Rig {
+        let mut bus = Ram([0u8; 0x10000], false);
+        bus.0[ORG as usize..ORG as usize + prog.len()].copy_from_slice(prog);
+
+        // ($20,X) with X = 4 points at $0320, ($30),Y points at $0330 + Y
+        bus.0[0x24] = 0x20;
+        bus.0[0x25] = 0x03;
+        bus.0[0x30] = 0x30;
+        bus.0[0x31] = 0x03;
+
+        let mut cpu = NMOS6502::new();
+        cpu.pc = ORG;
+        cpu.sp = 0xFD;
+        cpu.x = 0x04;
+        cpu.y = 0x08;
+        Rig { cpu: cpu, bus: bus }
+    }
 
-                if (self.a as i8) < 0 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
+    fn peek(rig: &Rig, addr: u16) -> u8 {
+        rig.bus.0[addr as usize]
+    }
 
-                self.set_carry(a & 0x100 == 0x100);
+    fn poke(rig: &mut Rig, addr: u16, val: u8) {
+        rig.bus.0[addr as usize] = val;
+    }
 
-                if (ainit ^ self.a) & (val ^ self.a) & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
-                }
+    fn flag(cpu: &NMOS6502, f: PFlag) -> bool {
+        cpu.p_flags.contains(f)
+    }
 
-                Ok(0u8)
+    // Effective addresses of the operands used below, given the fixture in `cpu_with`
+    const ZP: u16 = 0x0010;
+    const ZPX: u16 = 0x0014;
+    const ZPY: u16 = 0x0018;
+    const ABS: u16 = 0x0300;
+    const ABX: u16 = 0x0304;
+    const ABY: u16 = 0x0308;
+    const IZX: u16 = 0x0320;
+    const IZY: u16 = 0x0338;
+
+    macro_rules! op_test {
+        ($name:ident, [$($b:expr),*], |$cpu:ident| $setup:block, $check:block) => {
+            #[test]
+            fn $name() {
+                let mut $cpu = cpu_with(&[$($b),*]);
+                $setup
+                $cpu.step().unwrap();
+                $check
             }
-            Instruction(Opcode::ADC, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("ADC (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr, addr, val);
-                let ainit = self.a;
-
-                let mut a: u16 = self.a as u16;
-                a += val as u16;
-                a += (self.p_flags.bits & 0b1) as u16;
-                self.a = (a & 0xFF) as u8;
-
-                if self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
-
-                if (self.a as i8) < 0 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
+        }
+    }
 
-                self.set_carry(a & 0x100 == 0x100);
+    macro_rules! load_test {
+        ($name:ident, [$($b:expr),*], imm, $reg:ident) => {
+            op_test!($name, [$($b),*], |cpu| {}, {
+                assert_eq!(cpu.$reg, 0x80);
+                assert!(flag(&cpu, PFlag::FLAG_N));
+                assert!(!flag(&cpu, PFlag::FLAG_Z));
+            });
+        };
+        ($name:ident, [$($b:expr),*], $ea:expr, $reg:ident) => {
+            op_test!($name, [$($b),*], |cpu| { poke(&mut cpu, $ea, 0x80); }, {
+                assert_eq!(cpu.$reg, 0x80);
+                assert!(flag(&cpu, PFlag::FLAG_N));
+                assert!(!flag(&cpu, PFlag::FLAG_Z));
+            });
+        };
+    }
 
-                if (ainit ^ self.a) & (val ^ self.a) & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
-                }
+    macro_rules! store_test {
+        ($name:ident, [$($b:expr),*], $ea:expr, $reg:ident) => {
+            op_test!($name, [$($b),*], |cpu| { cpu.$reg = 0x5A; }, {
+                assert_eq!(peek(&cpu, $ea), 0x5A);
+            });
+        };
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::CMP, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("CMP (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr, addr, val);
-
-                let t = self.a;
-                let temp = self.a.wrapping_sub(val);
-                self.set_nz(temp);
-                self.set_carry(t >= val);
-
-                Ok(0u8)
-            }
-            Instruction(Opcode::SBC, Value::PreIdxIndX(offs)) => {
-                let zaddr = self.x.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("SBC (${:02X},X) @ {:02X} = {:04X} = {:02X}", offs, zaddr, addr, val);
-                let ainit = self.a;
-                let val = val ^ 0xFF;
-
-                let mut a: u16 = self.a as u16;
-                a += val as u16;
-                a += (self.p_flags.bits & 0b1) as u16;
-                self.a = (a & 0xFF) as u8;
-
-                if self.a == 0 {
-                    self.p_flags.insert(PFlag::FLAG_Z);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_Z);
-                }
+    // Runs `a OP mem` with a = 0x0F and mem = 0xF3
+    macro_rules! alu_test {
+        ($name:ident, [$($b:expr),*], imm, $res:expr) => {
+            op_test!($name, [$($b),*], |cpu| { cpu.a = 0x0F; }, {
+                assert_eq!(cpu.a, $res);
+            });
+        };
+        ($name:ident, [$($b:expr),*], $ea:expr, $res:expr) => {
+            op_test!($name, [$($b),*], |cpu| { cpu.a = 0x0F; poke(&mut cpu, $ea, 0xF3); }, {
+                assert_eq!(cpu.a, $res);
+            });
+        };
+    }
 
-                if (self.a as i8) < 0 {
-                    self.p_flags.insert(PFlag::FLAG_N);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_N);
-                }
+    // Compares register 0x40 against memory 0x40, so Z and C must be set
+    macro_rules! cmp_test {
+        ($name:ident, [$($b:expr),*], imm, $reg:ident) => {
+            op_test!($name, [$($b),*], |cpu| { cpu.$reg = 0x40; }, {
+                assert!(flag(&cpu, PFlag::FLAG_Z));
+                assert!(flag(&cpu, PFlag::FLAG_C));
+            });
+        };
+        ($name:ident, [$($b:expr),*], $ea:expr, $reg:ident) => {
+            op_test!($name, [$($b),*], |cpu| { cpu.$reg = 0x40; poke(&mut cpu, $ea, 0x40); }, {
+                assert!(flag(&cpu, PFlag::FLAG_Z));
+                assert!(flag(&cpu, PFlag::FLAG_C));
+            });
+        };
+    }
 
-                self.set_carry(a & 0x100 == 0x100);
+    // Runs a read-modify-write on 0x81 with carry clear
+    macro_rules! rmw_test {
+        ($name:ident, [$($b:expr),*], acc, $res:expr, $carry:expr) => {
+            op_test!($name, [$($b),*], |cpu| { cpu.a = 0x81; }, {
+                assert_eq!(cpu.a, $res);
+                assert_eq!(flag(&cpu, PFlag::FLAG_C), $carry);
+            });
+        };
+        ($name:ident, [$($b:expr),*], $ea:expr, $res:expr, $carry:expr) => {
+            op_test!($name, [$($b),*], |cpu| { poke(&mut cpu, $ea, 0x81); }, {
+                assert_eq!(peek(&cpu, $ea), $res);
+                assert_eq!(flag(&cpu, PFlag::FLAG_C), $carry);
+            });
+        };
+    }
 
-                if (ainit ^ self.a) & (val ^ self.a) & 0x80 == 0x80 {
-                    self.p_flags.insert(PFlag::FLAG_V);
-                } else {
-                    self.p_flags.remove(PFlag::FLAG_V);
+    macro_rules! branch_test {
+        ($name:ident, $op:expr, $flag:expr, $taken_when_set:expr) => {
+            #[test]
+            fn $name() {
+                for &set in &[false, true] {
+                    let mut cpu = cpu_with(&[$op, 0x10]);
+                    cpu.p_flags.set($flag, set);
+                    cpu.step().unwrap();
+
+                    let target = if set == $taken_when_set { ORG + 0x12 } else { ORG + 2 };
+                    assert_eq!(cpu.pc, target);
                 }
-
-                Ok(0u8)
             }
-            Instruction(Opcode::LDY, Value::ZeroPage(offs)) => {
-                let val = self.read8(offs as u16);
-                println!("LDY ${:02X} = {:02X}", offs, val);
-                self.y = val;
+        };
+    }
 
-                self.set_nz(val);
+    macro_rules! flag_test {
+        ($name:ident, $op:expr, $flag:expr, $set:expr) => {
+            op_test!($name, [$op], |cpu| { cpu.p_flags.set($flag, !$set); }, {
+                assert_eq!(flag(&cpu, $flag), $set);
+            });
+        };
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::LDA, Value::PreIdxIndY(offs)) => {
-                let zaddr = self.y.wrapping_add(offs);
-                let addr = self.get_ind_addr(zaddr as u16);
-                let val = self.read8(addr);
-                println!("LDA (${:02X},Y) @ {:02X} = {:04X} = {:02X}", offs, zaddr.wrapping_sub(1), addr, val);
+    load_test!(lda_imm, [0xA9, 0x80], imm, a);
+    load_test!(lda_zp, [0xA5, 0x10], ZP, a);
+    load_test!(lda_zpx, [0xB5, 0x10], ZPX, a);
+    load_test!(lda_abs, [0xAD, 0x00, 0x03], ABS, a);
+    load_test!(lda_abx, [0xBD, 0x00, 0x03], ABX, a);
+    load_test!(lda_aby, [0xB9, 0x00, 0x03], ABY, a);
+    load_test!(lda_izx, [0xA1, 0x20], IZX, a);
+    load_test!(lda_izy, [0xB1, 0x30], IZY, a);
+
+    load_test!(ldx_imm, [0xA2, 0x80], imm, x);
+    load_test!(ldx_zp, [0xA6, 0x10], ZP, x);
+    load_test!(ldx_zpy, [0xB6, 0x10], ZPY, x);
+    load_test!(ldx_abs, [0xAE, 0x00, 0x03], ABS, x);
+    load_test!(ldx_aby, [0xBE, 0x00, 0x03], ABY, x);
+
+    load_test!(ldy_imm, [0xA0, 0x80], imm, y);
+    load_test!(ldy_zp, [0xA4, 0x10], ZP, y);
+    load_test!(ldy_zpx, [0xB4, 0x10], ZPX, y);
+    load_test!(ldy_abs, [0xAC, 0x00, 0x03], ABS, y);
+    load_test!(ldy_abx, [0xBC, 0x00, 0x03], ABX, y);
+
+    store_test!(sta_zp, [0x85, 0x10], ZP, a);
+    store_test!(sta_zpx, [0x95, 0x10], ZPX, a);
+    store_test!(sta_abs, [0x8D, 0x00, 0x03], ABS, a);
+    store_test!(sta_abx, [0x9D, 0x00, 0x03], ABX, a);
+    store_test!(sta_aby, [0x99, 0x00, 0x03], ABY, a);
+    store_test!(sta_izx, [0x81, 0x20], IZX, a);
+    store_test!(sta_izy, [0x91, 0x30], IZY, a);
+
+    store_test!(stx_zp, [0x86, 0x10], ZP, x);
+    store_test!(stx_zpy, [0x96, 0x10], ZPY, x);
+    store_test!(stx_abs, [0x8E, 0x00, 0x03], ABS, x);
+
+    store_test!(sty_zp, [0x84, 0x10], ZP, y);
+    store_test!(sty_zpx, [0x94, 0x10], ZPX, y);
+    store_test!(sty_abs, [0x8C, 0x00, 0x03], ABS, y);
+
+    alu_test!(and_imm, [0x29, 0xF3], imm, 0x03);
+    alu_test!(and_zp, [0x25, 0x10], ZP, 0x03);
+    alu_test!(and_zpx, [0x35, 0x10], ZPX, 0x03);
+    alu_test!(and_abs, [0x2D, 0x00, 0x03], ABS, 0x03);
+    alu_test!(and_abx, [0x3D, 0x00, 0x03], ABX, 0x03);
+    alu_test!(and_aby, [0x39, 0x00, 0x03], ABY, 0x03);
+    alu_test!(and_izx, [0x21, 0x20], IZX, 0x03);
+    alu_test!(and_izy, [0x31, 0x30], IZY, 0x03);
+
+    alu_test!(ora_imm, [0x09, 0xF3], imm, 0xFF);
+    alu_test!(ora_zp, [0x05, 0x10], ZP, 0xFF);
+    alu_test!(ora_zpx, [0x15, 0x10], ZPX, 0xFF);
+    alu_test!(ora_abs, [0x0D, 0x00, 0x03], ABS, 0xFF);
+    alu_test!(ora_abx, [0x1D, 0x00, 0x03], ABX, 0xFF);
+    alu_test!(ora_aby, [0x19, 0x00, 0x03], ABY, 0xFF);
+    alu_test!(ora_izx, [0x01, 0x20], IZX, 0xFF);
+    alu_test!(ora_izy, [0x11, 0x30], IZY, 0xFF);
+
+    alu_test!(eor_imm, [0x49, 0xF3], imm, 0xFC);
+    alu_test!(eor_zp, [0x45, 0x10], ZP, 0xFC);
+    alu_test!(eor_zpx, [0x55, 0x10], ZPX, 0xFC);
+    alu_test!(eor_abs, [0x4D, 0x00, 0x03], ABS, 0xFC);
+    alu_test!(eor_abx, [0x5D, 0x00, 0x03], ABX, 0xFC);
+    alu_test!(eor_aby, [0x59, 0x00, 0x03], ABY, 0xFC);
+    alu_test!(eor_izx, [0x41, 0x20], IZX, 0xFC);
+    alu_test!(eor_izy, [0x51, 0x30], IZY, 0xFC);
+
+    alu_test!(adc_imm, [0x69, 0xF3], imm, 0x02);
+    alu_test!(adc_zp, [0x65, 0x10], ZP, 0x02);
+    alu_test!(adc_zpx, [0x75, 0x10], ZPX, 0x02);
+    alu_test!(adc_abs, [0x6D, 0x00, 0x03], ABS, 0x02);
+    alu_test!(adc_abx, [0x7D, 0x00, 0x03], ABX, 0x02);
+    alu_test!(adc_aby, [0x79, 0x00, 0x03], ABY, 0x02);
+    alu_test!(adc_izx, [0x61, 0x20], IZX, 0x02);
+    alu_test!(adc_izy, [0x71, 0x30], IZY, 0x02);
+
+    // Carry is clear, so SBC subtracts one extra
+    alu_test!(sbc_imm, [0xE9, 0xF3], imm, 0x1B);
+    alu_test!(sbc_zp, [0xE5, 0x10], ZP, 0x1B);
+    alu_test!(sbc_zpx, [0xF5, 0x10], ZPX, 0x1B);
+    alu_test!(sbc_abs, [0xED, 0x00, 0x03], ABS, 0x1B);
+    alu_test!(sbc_abx, [0xFD, 0x00, 0x03], ABX, 0x1B);
+    alu_test!(sbc_aby, [0xF9, 0x00, 0x03], ABY, 0x1B);
+    alu_test!(sbc_izx, [0xE1, 0x20], IZX, 0x1B);
+    alu_test!(sbc_izy, [0xF1, 0x30], IZY, 0x1B);
+
+    cmp_test!(cmp_imm, [0xC9, 0x40], imm, a);
+    cmp_test!(cmp_zp, [0xC5, 0x10], ZP, a);
+    cmp_test!(cmp_zpx, [0xD5, 0x10], ZPX, a);
+    cmp_test!(cmp_abs, [0xCD, 0x00, 0x03], ABS, a);
+    cmp_test!(cmp_abx, [0xDD, 0x00, 0x03], ABX, a);
+    cmp_test!(cmp_aby, [0xD9, 0x00, 0x03], ABY, a);
+    cmp_test!(cmp_izx, [0xC1, 0x20], IZX, a);
+    cmp_test!(cmp_izy, [0xD1, 0x30], IZY, a);
+
+    cmp_test!(cpx_imm, [0xE0, 0x40], imm, x);
+    cmp_test!(cpx_zp, [0xE4, 0x10], ZP, x);
+    cmp_test!(cpx_abs, [0xEC, 0x00, 0x03], ABS, x);
+
+    cmp_test!(cpy_imm, [0xC0, 0x40], imm, y);
+    cmp_test!(cpy_zp, [0xC4, 0x10], ZP, y);
+    cmp_test!(cpy_abs, [0xCC, 0x00, 0x03], ABS, y);
+
+    rmw_test!(asl_acc, [0x0A], acc, 0x02, true);
+    rmw_test!(asl_zp, [0x06, 0x10], ZP, 0x02, true);
+    rmw_test!(asl_zpx, [0x16, 0x10], ZPX, 0x02, true);
+    rmw_test!(asl_abs, [0x0E, 0x00, 0x03], ABS, 0x02, true);
+    rmw_test!(asl_abx, [0x1E, 0x00, 0x03], ABX, 0x02, true);
+
+    rmw_test!(lsr_acc, [0x4A], acc, 0x40, true);
+    rmw_test!(lsr_zp, [0x46, 0x10], ZP, 0x40, true);
+    rmw_test!(lsr_zpx, [0x56, 0x10], ZPX, 0x40, true);
+    rmw_test!(lsr_abs, [0x4E, 0x00, 0x03], ABS, 0x40, true);
+    rmw_test!(lsr_abx, [0x5E, 0x00, 0x03], ABX, 0x40, true);
+
+    rmw_test!(rol_acc, [0x2A], acc, 0x02, true);
+    rmw_test!(rol_zp, [0x26, 0x10], ZP, 0x02, true);
+    rmw_test!(rol_zpx, [0x36, 0x10], ZPX, 0x02, true);
+    rmw_test!(rol_abs, [0x2E, 0x00, 0x03], ABS, 0x02, true);
+    rmw_test!(rol_abx, [0x3E, 0x00, 0x03], ABX, 0x02, true);
+
+    rmw_test!(ror_acc, [0x6A], acc, 0x40, true);
+    rmw_test!(ror_zp, [0x66, 0x10], ZP, 0x40, true);
+    rmw_test!(ror_zpx, [0x76, 0x10], ZPX, 0x40, true);
+    rmw_test!(ror_abs, [0x6E, 0x00, 0x03], ABS, 0x40, true);
+    rmw_test!(ror_abx, [0x7E, 0x00, 0x03], ABX, 0x40, true);
+
+    rmw_test!(inc_zp, [0xE6, 0x10], ZP, 0x82, false);
+    rmw_test!(inc_zpx, [0xF6, 0x10], ZPX, 0x82, false);
+    rmw_test!(inc_abs, [0xEE, 0x00, 0x03], ABS, 0x82, false);
+    rmw_test!(inc_abx, [0xFE, 0x00, 0x03], ABX, 0x82, false);
+
+    rmw_test!(dec_zp, [0xC6, 0x10], ZP, 0x80, false);
+    rmw_test!(dec_zpx, [0xD6, 0x10], ZPX, 0x80, false);
+    rmw_test!(dec_abs, [0xCE, 0x00, 0x03], ABS, 0x80, false);
+    rmw_test!(dec_abx, [0xDE, 0x00, 0x03], ABX, 0x80, false);
+
+    op_test!(bit_zp, [0x24, 0x10], |cpu| { cpu.a = 0x01; poke(&mut cpu, ZP, 0xC0); }, {
+        assert!(flag(&cpu, PFlag::FLAG_N));
+        assert!(flag(&cpu, PFlag::FLAG_V));
+        assert!(flag(&cpu, PFlag::FLAG_Z));
+    });
+    op_test!(bit_abs, [0x2C, 0x00, 0x03], |cpu| { cpu.a = 0x01; poke(&mut cpu, ABS, 0x41); }, {
+        assert!(!flag(&cpu, PFlag::FLAG_N));
+        assert!(flag(&cpu, PFlag::FLAG_V));
+        assert!(!flag(&cpu, PFlag::FLAG_Z));
+    });
+
+    branch_test!(bpl_rel, 0x10, PFlag::FLAG_N, false);
+    branch_test!(bmi_rel, 0x30, PFlag::FLAG_N, true);
+    branch_test!(bvc_rel, 0x50, PFlag::FLAG_V, false);
+    branch_test!(bvs_rel, 0x70, PFlag::FLAG_V, true);
+    branch_test!(bcc_rel, 0x90, PFlag::FLAG_C, false);
+    branch_test!(bcs_rel, 0xB0, PFlag::FLAG_C, true);
+    branch_test!(bne_rel, 0xD0, PFlag::FLAG_Z, false);
+    branch_test!(beq_rel, 0xF0, PFlag::FLAG_Z, true);
+
+    op_test!(branch_backwards, [0xD0, 0xFC], |cpu| {}, {
+        assert_eq!(cpu.pc, ORG - 2);
+    });
+
+    flag_test!(clc_imp, 0x18, PFlag::FLAG_C, false);
+    flag_test!(sec_imp, 0x38, PFlag::FLAG_C, true);
+    flag_test!(cli_imp, 0x58, PFlag::FLAG_I, false);
+    flag_test!(sei_imp, 0x78, PFlag::FLAG_I, true);
+    flag_test!(clv_imp, 0xB8, PFlag::FLAG_V, false);
+    flag_test!(cld_imp, 0xD8, PFlag::FLAG_D, false);
+    flag_test!(sed_imp, 0xF8, PFlag::FLAG_D, true);
+
+    op_test!(tax_imp, [0xAA], |cpu| { cpu.a = 0x00; }, {
+        assert_eq!(cpu.x, 0x00);
+        assert!(flag(&cpu, PFlag::FLAG_Z));
+    });
+    op_test!(tay_imp, [0xA8], |cpu| { cpu.a = 0x99; }, {
+        assert_eq!(cpu.y, 0x99);
+        assert!(flag(&cpu, PFlag::FLAG_N));
+    });
+    op_test!(txa_imp, [0x8A], |cpu| {}, { assert_eq!(cpu.a, 0x04); });
+    op_test!(tya_imp, [0x98], |cpu| {}, { assert_eq!(cpu.a, 0x08); });
+    op_test!(tsx_imp, [0xBA], |cpu| {}, {
+        assert_eq!(cpu.x, 0xFD);
+        assert!(flag(&cpu, PFlag::FLAG_N));
+    });
+    op_test!(txs_imp, [0x9A], |cpu| { cpu.p_flags.remove(PFlag::FLAG_Z); }, {
+        assert_eq!(cpu.sp, 0x04);
+        assert!(!flag(&cpu, PFlag::FLAG_Z));
+    });
+
+    op_test!(inx_imp, [0xE8], |cpu| { cpu.x = 0xFF; }, {
+        assert_eq!(cpu.x, 0x00);
+        assert!(flag(&cpu, PFlag::FLAG_Z));
+    });
+    op_test!(iny_imp, [0xC8], |cpu| {}, { assert_eq!(cpu.y, 0x09); });
+    op_test!(dex_imp, [0xCA], |cpu| { cpu.x = 0x00; }, {
+        assert_eq!(cpu.x, 0xFF);
+        assert!(flag(&cpu, PFlag::FLAG_N));
+    });
+    op_test!(dey_imp, [0x88], |cpu| {}, { assert_eq!(cpu.y, 0x07); });
+
+    op_test!(pha_imp, [0x48], |cpu| { cpu.a = 0x37; }, {
+        assert_eq!(peek(&cpu, 0x01FD), 0x37);
+        assert_eq!(cpu.sp, 0xFC);
+    });
+    op_test!(php_imp, [0x08], |cpu| { cpu.p_flags = PFlag::FLAG_C; }, {
+        assert_eq!(peek(&cpu, 0x01FD), 0x31);
+    });
+    op_test!(pla_imp, [0x68], |cpu| { cpu.sp = 0xFC; poke(&mut cpu, 0x01FD, 0x00); }, {
+        assert_eq!(cpu.a, 0x00);
+        assert!(flag(&cpu, PFlag::FLAG_Z));
+        assert_eq!(cpu.sp, 0xFD);
+    });
+    op_test!(plp_imp, [0x28], |cpu| { cpu.sp = 0xFC; poke(&mut cpu, 0x01FD, 0xDB); }, {
+        assert_eq!(cpu.p_flags.bits, 0xEB);
+    });
+
+    op_test!(jmp_abs, [0x4C, 0x34, 0x12], |cpu| {}, { assert_eq!(cpu.pc, 0x1234); });
+    op_test!(jmp_ind, [0x6C, 0x00, 0x03], |cpu| { poke(&mut cpu, 0x0300, 0x78); poke(&mut cpu, 0x0301, 0x56); }, {
+        assert_eq!(cpu.pc, 0x5678);
+    });
+    op_test!(jmp_ind_page_wrap, [0x6C, 0xFF, 0x02], |cpu| {
+        poke(&mut cpu, 0x02FF, 0x78);
+        poke(&mut cpu, 0x0200, 0x56);
+        poke(&mut cpu, 0x0300, 0xEE);
+    }, {
+        assert_eq!(cpu.pc, 0x5678);
+    });
+    op_test!(jsr_abs, [0x20, 0x34, 0x12], |cpu| {}, {
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(peek(&cpu, 0x01FD), 0x04);
+        assert_eq!(peek(&cpu, 0x01FC), 0x02);
+    });
+    op_test!(rts_imp, [0x60], |cpu| { cpu.sp = 0xFB; poke(&mut cpu, 0x01FC, 0x02); poke(&mut cpu, 0x01FD, 0x04); }, {
+        assert_eq!(cpu.pc, 0x0403);
+    });
+    op_test!(rti_imp, [0x40], |cpu| {
+        cpu.sp = 0xFA;
+        poke(&mut cpu, 0x01FB, 0xC3);
+        poke(&mut cpu, 0x01FC, 0x34);
+        poke(&mut cpu, 0x01FD, 0x12);
+    }, {
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cpu.p_flags.bits, 0xE3);
+    });
+    // B and the unused bit only exist in the pushed copy, next to the
+    // flags as they were
+    op_test!(brk_imp, [0x00, 0xFF], |cpu| { cpu.p_flags = PFlag::FLAG_C | PFlag::FLAG_V | PFlag::FLAG_N; }, {
+        assert_eq!(peek(&cpu, 0x01FB), 0xF1);
+        assert_eq!(cpu.p_flags.bits & 0x30, 0);
+        assert_eq!(cpu.p_flags, PFlag::FLAG_C | PFlag::FLAG_V | PFlag::FLAG_N | PFlag::FLAG_I);
+    });
+    op_test!(nop_imp, [0xEA], |cpu| {}, { assert_eq!(cpu.pc, ORG + 1); });
+
+    op_test!(slo_zp, [0x07, 0x10], |cpu| { cpu.a = 0x01; poke(&mut cpu, ZP, 0x81); }, {
+        assert_eq!(peek(&cpu, ZP), 0x02);
+        assert_eq!(cpu.a, 0x03);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    op_test!(rla_abs, [0x2F, 0x00, 0x03], |cpu| { cpu.a = 0xFF; poke(&mut cpu, ABS, 0x81); }, {
+        assert_eq!(peek(&cpu, ABS), 0x02);
+        assert_eq!(cpu.a, 0x02);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    op_test!(sre_izx, [0x43, 0x20], |cpu| { cpu.a = 0x0F; poke(&mut cpu, IZX, 0x81); }, {
+        assert_eq!(peek(&cpu, IZX), 0x40);
+        assert_eq!(cpu.a, 0x4F);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    op_test!(rra_izy, [0x73, 0x30], |cpu| { cpu.a = 0x01; poke(&mut cpu, IZY, 0x81); }, {
+        assert_eq!(peek(&cpu, IZY), 0x40);
+        assert_eq!(cpu.a, 0x42);
+    });
+    op_test!(sax_zpy, [0x97, 0x10], |cpu| { cpu.a = 0xFF; }, {
+        assert_eq!(peek(&cpu, ZPY), 0x04);
+    });
+    load_test!(lax_aby, [0xBF, 0x00, 0x03], ABY, x);
+    op_test!(lax_loads_a_too, [0xA7, 0x10], |cpu| { poke(&mut cpu, ZP, 0x33); }, {
+        assert_eq!(cpu.a, 0x33);
+        assert_eq!(cpu.x, 0x33);
+    });
+    op_test!(dcp_abx, [0xDF, 0x00, 0x03], |cpu| { cpu.a = 0x40; poke(&mut cpu, ABX, 0x41); }, {
+        assert_eq!(peek(&cpu, ABX), 0x40);
+        assert!(flag(&cpu, PFlag::FLAG_Z));
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    op_test!(isb_zpx, [0xF7, 0x10], |cpu| { cpu.a = 0x20; cpu.set_carry(true); poke(&mut cpu, ZPX, 0x0F); }, {
+        assert_eq!(peek(&cpu, ZPX), 0x10);
+        assert_eq!(cpu.a, 0x10);
+    });
+    op_test!(anc_imm, [0x0B, 0x80], |cpu| { cpu.a = 0xFF; }, {
+        assert_eq!(cpu.a, 0x80);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    op_test!(alr_imm, [0x4B, 0x03], |cpu| { cpu.a = 0xFF; }, {
+        assert_eq!(cpu.a, 0x01);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    op_test!(arr_imm, [0x6B, 0xFF], |cpu| { cpu.a = 0xFF; cpu.set_carry(true); }, {
+        assert_eq!(cpu.a, 0xFF);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+        assert!(!flag(&cpu, PFlag::FLAG_V));
+    });
+    op_test!(axs_imm, [0xCB, 0x01], |cpu| { cpu.a = 0xFF; }, {
+        assert_eq!(cpu.x, 0x03);
+        assert!(flag(&cpu, PFlag::FLAG_C));
+    });
+    alu_test!(sbc_imm_undocumented, [0xEB, 0xF3], imm, 0x1B);
+    op_test!(nop_zp, [0x04, 0x10], |cpu| {}, {
+        assert_eq!(cpu.pc, ORG + 2);
+        assert_eq!(cpu.a, 0x00);
+    });
+
+    #[test]
+    fn undocumented_nop_page_cross() {
+        assert_eq!(cycles_for(&[0x1C, 0x00, 0x03], |_| {}), 4);
+        assert_eq!(cycles_for(&[0x1C, 0xFF, 0x02], |_| {}), 5);
+        assert_eq!(cycles_for(&[0x1B, 0xFF, 0x02], |_| {}), 7);
+    }
 
-                self.set_nz(val);
-                self.a = val;
+    #[test]
+    fn unstable_rejected_by_default() {
+        let mut cpu = cpu_with(&[0x8B, 0xFF]);
+        assert!(cpu.step().is_err());
+    }
 
-                Ok(0u8)
-            }
-            Instruction(Opcode::INC, Value::ZeroPage(offs)) => {
-                let val = self.read8(offs as u16).wrapping_add(1);
-                println!("INC ${:02X} = {:02X}", offs, val);
+    op_test!(xaa_imm, [0x8B, 0xFF], |cpu| { cpu.unstable = Unstable::Emulate { magic: 0xEE }; cpu.a = 0x11; cpu.x = 0x0F; }, {
+        assert_eq!(cpu.a, 0x0F);
+    });
+    op_test!(las_aby, [0xBB, 0x00, 0x03], |cpu| { cpu.unstable = Unstable::Emulate { magic: 0xEE }; poke(&mut cpu, ABY, 0xF0); }, {
+        assert_eq!(cpu.a, 0xF0);
+        assert_eq!(cpu.x, 0xF0);
+        assert_eq!(cpu.sp, 0xF0);
+    });
+    op_test!(shx_aby, [0x9E, 0x00, 0x03], |cpu| { cpu.unstable = Unstable::Emulate { magic: 0xEE }; cpu.x = 0xFF; }, {
+        assert_eq!(peek(&cpu, ABY), 0x04);
+    });
+    op_test!(shy_abx_page_cross, [0x9C, 0xFE, 0x02], |cpu| { cpu.unstable = Unstable::Emulate { magic: 0xEE }; cpu.y = 0x03; }, {
+        // $02FE + 4 crosses into page 3, and 3 & 3 keeps the target on page 3
+        assert_eq!(peek(&cpu, 0x0302), 0x03);
+    });
+
+    #[test]
+    fn jam_halts_cleanly() {
+        let mut cpu = cpu_with(&[0x02, 0xEA]);
+        cpu.step().unwrap();
+        assert!(cpu.halted());
+        assert_eq!(cpu.pc, ORG);
+
+        assert_eq!(cpu.step(), Ok(1));
+        assert_eq!(cpu.pc, ORG);
+    }
 
-                self.set_nz(val);
-                self.write8(offs as u16, val);
+    fn with_vectors(mut rig: Rig) -> Rig {
+        // NMI at $8000, IRQ/BRK at $9000, both starting with NOPs
+        rig.bus.0[0xFFFA..0x10000].copy_from_slice(&[0x00, 0x80, 0x00, 0x00, 0x00, 0x90]);
+        rig.bus.0[0x8000] = 0xEA;
+        rig.bus.0[0x9000] = 0xEA;
+        rig
+    }
 
-                Ok(0u8)
-            }
-            instr => Err(format!("{:?}", instr)),
-        }
+    #[test]
+    fn nmi_pushes_p_with_b_clear() {
+        let mut rig = with_vectors(cpu_with(&[0xEA]));
+        rig.cpu.nmi(&mut rig.bus, 0);
+
+        assert_eq!(rig.step(), Ok(7));
+        assert_eq!(rig.pc, 0x8000);
+        assert!(flag(&rig, PFlag::FLAG_I));
+        assert_eq!(peek(&rig, 0x01FD), (ORG >> 8) as u8);
+        assert_eq!(peek(&rig, 0x01FC), ORG as u8);
+        assert_eq!(peek(&rig, 0x01FB) & 0x30, 0x20);
     }
 
-    pub fn nmi(&mut self) {
-        let pc = self.pc;
-        let p = self.p_flags.bits;
-        self.push16(pc);
-        self.push8(p);
-        
-        self.pc = (self.read8(0xFFFF) as u16) << 8 | (self.read8(0xFFFE) as u16);
+    #[test]
+    fn irq_is_level_triggered() {
+        // CLI / NOP, with the handler returning straight away
+        let mut rig = with_vectors(cpu_with(&[0x58, 0xEA, 0xEA]));
+        rig.bus.0[0x9000] = 0x40;
+        rig.bus.1 = true;
+        rig.p_flags.insert(PFlag::FLAG_I);
+
+        rig.step().unwrap();
+        assert_eq!(rig.pc, ORG + 1);
+
+        // CLI only takes effect after the next instruction
+        rig.step().unwrap();
+        assert_eq!(rig.pc, ORG + 2);
+
+        assert_eq!(rig.step(), Ok(7));
+        assert_eq!(rig.pc, 0x9000);
+
+        // Still held low, so it fires again right after RTI
+        rig.step().unwrap();
+        assert_eq!(rig.pc, ORG + 2);
+        rig.step().unwrap();
+        assert_eq!(rig.pc, 0x9000);
+
+        rig.bus.1 = false;
+        rig.step().unwrap();
+        rig.step().unwrap();
+        assert_eq!(rig.pc, ORG + 3);
     }
 
-    pub fn get_ind_addr(&mut self, zaddr: u16) -> u16 {
-        let addrl = self.read8(zaddr as u16);
-        let zaddr = zaddr.wrapping_add(1);
-        let addrh = self.read8(zaddr as u16);
-        ((addrh as u16) << 8) | addrl as u16
+    #[test]
+    fn sei_lets_a_pending_irq_through() {
+        let mut rig = with_vectors(cpu_with(&[0x78, 0xEA]));
+        rig.p_flags.remove(PFlag::FLAG_I);
+        rig.cpu.irq_inhibit = false;
+
+        rig.step().unwrap();
+        rig.bus.1 = true;
+        rig.step().unwrap();
+        assert_eq!(rig.pc, 0x9000);
+        // The pushed P already has I set
+        assert_eq!(peek(&rig, 0x01FB) & 0x04, 0x04);
     }
 
-    pub fn set_carry(&mut self, val: bool) {
-        match val {
-            true => self.p_flags.insert(PFlag::FLAG_C),
-            false => self.p_flags.remove(PFlag::FLAG_C),
-        }
+    #[test]
+    fn brk_skips_signature_byte() {
+        let mut rig = with_vectors(cpu_with(&[0x00, 0x42]));
+        assert_eq!(rig.step(), Ok(7));
+        assert_eq!(rig.pc, 0x9000);
+        assert!(flag(&rig, PFlag::FLAG_I));
+
+        let ret = ORG + 2;
+        assert_eq!(peek(&rig, 0x01FD), (ret >> 8) as u8);
+        assert_eq!(peek(&rig, 0x01FC), ret as u8);
+        assert_eq!(peek(&rig, 0x01FB) & 0x30, 0x30);
     }
 
-    pub fn set_nz(&mut self, val: u8) { 
-        match val {
-            0 => self.p_flags.insert(PFlag::FLAG_Z),
-            _ => self.p_flags.remove(PFlag::FLAG_Z),
-        }
+    #[test]
+    fn nmi_hijacks_brk() {
+        let mut rig = with_vectors(cpu_with(&[0x00, 0x42]));
+        rig.step().unwrap();
+        rig.cpu.nmi(&mut rig.bus, 2);
 
-        match val & 0x80 {
-            0x80 => self.p_flags.insert(PFlag::FLAG_N),
-            _ => self.p_flags.remove(PFlag::FLAG_N),
-        }
-    }
+        assert_eq!(rig.pc, 0x8000);
+        // B stays set in the pushed P
+        assert_eq!(peek(&rig, 0x01FB) & 0x30, 0x30);
 
-    pub fn read8(&mut self, addr: u16) -> u8 {
-        let ppu = self.ppu.lock().unwrap();
-        let mem = self.mem.lock().unwrap();
-        match addr {
-            0x2002 => ppu.ppustatus,
-            _ => mem.read8(addr),
-        }
+        // Consumed, so it doesn't fire a second time
+        rig.step().unwrap();
+        assert_eq!(rig.pc, 0x8001);
     }
 
-    pub fn read8_pc(&mut self) -> u8 {
-        let mem = self.mem.lock().unwrap();
-        let ret = mem.read8(self.pc);
-        self.pc += 1;
-        ret
+    #[test]
+    fn late_nmi_waits_for_brk() {
+        let mut rig = with_vectors(cpu_with(&[0x00, 0x42]));
+        rig.step().unwrap();
+        rig.cpu.nmi(&mut rig.bus, 5);
+        assert_eq!(rig.pc, 0x9000);
+
+        assert_eq!(rig.step(), Ok(7));
+        assert_eq!(rig.pc, 0x8000);
     }
 
-    pub fn read16_pc(&mut self) -> u16 {
-        let mem = self.mem.lock().unwrap();
-        let ret = (mem.read8(self.pc) as u16) | ((mem.read8(self.pc + 1) as u16) << 8);
-        self.pc += 2;
-        ret
+    #[test]
+    fn adc_overflow_and_carry() {
+        let mut cpu = cpu_with(&[0x69, 0x50, 0x69, 0x90]);
+        cpu.a = 0x50;
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0xA0);
+        assert!(flag(&cpu, PFlag::FLAG_V));
+        assert!(!flag(&cpu, PFlag::FLAG_C));
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x30);
+        assert!(flag(&cpu, PFlag::FLAG_V));
+        assert!(flag(&cpu, PFlag::FLAG_C));
     }
 
-    fn write8(&mut self, addr: u16, val: u8) {
-        let mut ppu = self.ppu.lock().unwrap();
-        match addr {
-            0x2000...0x2007 => ppu.write8(addr, val),
-            0x4010 => println!("APU: DMC @{:04X} = {:02X}", addr, val),
-            0x4014 => ppu.oamdma(val),
-            0x4015 => println!("APU: Status @{:04X} = {:02X}", addr, val),
-            _ => {
-                let mut mem = self.mem.lock().unwrap();
-                mem.write8(addr, val)
-            }
-        }
+    #[test]
+    fn zero_page_index_wraps() {
+        let mut cpu = cpu_with(&[0xB5, 0xFE, 0xA1, 0xFE]);
+        cpu.x = 0x04;
+        poke(&mut cpu, 0x0002, 0x42);
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x42);
+
+        // ($FE,X) reads its pointer from $02/$03
+        poke(&mut cpu, 0x0003, 0x03);
+        poke(&mut cpu, 0x0342, 0x24);
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x24);
     }
 
-    fn push8(&mut self, val: u8) {
-        let mut mem = self.mem.lock().unwrap();
-        mem.write8(0x100 | self.sp as u16, val);
-        self.sp -= 1;
+    fn cycles_for(prog: &[u8], setup: fn(&mut Rig)) -> u16 {
+        let mut cpu = cpu_with(prog);
+        setup(&mut cpu);
+        cpu.step().unwrap()
     }
 
-    fn push16(&mut self, val: u16) {
-        self.push8((val >> 8) as u8);
-        self.push8((val >> 0) as u8);
+    #[test]
+    fn base_cycles() {
+        assert_eq!(cycles_for(&[0xA9, 0x00], |_| {}), 2);
+        assert_eq!(cycles_for(&[0xA5, 0x10], |_| {}), 3);
+        assert_eq!(cycles_for(&[0xB5, 0x10], |_| {}), 4);
+        assert_eq!(cycles_for(&[0xAD, 0x00, 0x03], |_| {}), 4);
+        assert_eq!(cycles_for(&[0xA1, 0x20], |_| {}), 6);
+        assert_eq!(cycles_for(&[0x06, 0x10], |_| {}), 5);
+        assert_eq!(cycles_for(&[0x16, 0x10], |_| {}), 6);
+        assert_eq!(cycles_for(&[0x0E, 0x00, 0x03], |_| {}), 6);
+        assert_eq!(cycles_for(&[0x1E, 0x00, 0x03], |_| {}), 7);
+        assert_eq!(cycles_for(&[0x4C, 0x00, 0x03], |_| {}), 3);
+        assert_eq!(cycles_for(&[0x6C, 0x00, 0x03], |_| {}), 5);
+        assert_eq!(cycles_for(&[0x20, 0x00, 0x03], |_| {}), 6);
+        assert_eq!(cycles_for(&[0x48], |_| {}), 3);
+        assert_eq!(cycles_for(&[0x68], |_| {}), 4);
+        assert_eq!(cycles_for(&[0x00], |_| {}), 7);
     }
 
-    fn pop8(&mut self) -> u8 {
-        let mem = self.mem.lock().unwrap();
-        self.sp += 1;
-        let ret = mem.read8(0x100 | self.sp as u16);
-        ret
+    #[test]
+    fn page_cross_penalty_on_reads_only() {
+        assert_eq!(cycles_for(&[0xBD, 0x00, 0x03], |_| {}), 4);
+        assert_eq!(cycles_for(&[0xBD, 0xFF, 0x02], |_| {}), 5);
+        assert_eq!(cycles_for(&[0xB9, 0xFF, 0x02], |_| {}), 5);
+        assert_eq!(cycles_for(&[0x9D, 0xFF, 0x02], |_| {}), 5);
+        assert_eq!(cycles_for(&[0x1E, 0xFF, 0x02], |_| {}), 7);
+
+        assert_eq!(cycles_for(&[0xB1, 0x30], |_| {}), 5);
+        assert_eq!(cycles_for(&[0xB1, 0x30], |cpu| poke(cpu, 0x30, 0xFC)), 6);
+        assert_eq!(cycles_for(&[0x91, 0x30], |cpu| poke(cpu, 0x30, 0xFC)), 6);
     }
 
-    fn pop16(&mut self) -> u16 {
-        self.pop8() as u16 | ((self.pop8() as u16) << 8)
+    #[test]
+    fn branch_penalties() {
+        assert_eq!(cycles_for(&[0xD0, 0x10], |cpu| cpu.p_flags.insert(PFlag::FLAG_Z)), 2);
+        assert_eq!(cycles_for(&[0xD0, 0x10], |cpu| cpu.p_flags.remove(PFlag::FLAG_Z)), 3);
+        assert_eq!(cycles_for(&[0xD0, 0xFC], |cpu| cpu.p_flags.remove(PFlag::FLAG_Z)), 4);
     }
 
-    pub fn set_pc(&mut self, val: u16) {
-        self.pc = val;
+    #[test]
+    fn cycles_accumulate() {
+        let mut cpu = cpu_with(&[0xA9, 0x00, 0xBD, 0xFF, 0x02, 0xEA]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.cycles, 2 + 5 + 2);
     }
 
-    pub fn set_sp(&mut self, val: u8) {
-        self.sp = val;
+    #[test]
+    fn decodes_every_official_opcode() {
+        let table: &[(u8, Opcode, Value)] = &[
+            (0x00, Opcode::BRK, Value::Implied), (0x01, Opcode::ORA, Value::PreIdxIndX(0x34)),
+            (0x05, Opcode::ORA, Value::ZeroPage(0x34)), (0x06, Opcode::ASL, Value::ZeroPage(0x34)),
+            (0x08, Opcode::PHP, Value::Implied), (0x09, Opcode::ORA, Value::Immediate(0x34)),
+            (0x0A, Opcode::ASL, Value::Accumulator), (0x0D, Opcode::ORA, Value::Absolute(0x1234)),
+            (0x0E, Opcode::ASL, Value::Absolute(0x1234)), (0x10, Opcode::BPL, Value::Relative(0x34)),
+            (0x11, Opcode::ORA, Value::PreIdxIndY(0x34)), (0x15, Opcode::ORA, Value::ZeroPageX(0x34)),
+            (0x16, Opcode::ASL, Value::ZeroPageX(0x34)), (0x18, Opcode::CLC, Value::Implied),
+            (0x19, Opcode::ORA, Value::AbsoluteY(0x1234)), (0x1D, Opcode::ORA, Value::AbsoluteX(0x1234)),
+            (0x1E, Opcode::ASL, Value::AbsoluteX(0x1234)), (0x20, Opcode::JSR, Value::Absolute(0x1234)),
+            (0x21, Opcode::AND, Value::PreIdxIndX(0x34)), (0x24, Opcode::BIT, Value::ZeroPage(0x34)),
+            (0x25, Opcode::AND, Value::ZeroPage(0x34)), (0x26, Opcode::ROL, Value::ZeroPage(0x34)),
+            (0x28, Opcode::PLP, Value::Implied), (0x29, Opcode::AND, Value::Immediate(0x34)),
+            (0x2A, Opcode::ROL, Value::Accumulator), (0x2C, Opcode::BIT, Value::Absolute(0x1234)),
+            (0x2D, Opcode::AND, Value::Absolute(0x1234)), (0x2E, Opcode::ROL, Value::Absolute(0x1234)),
+            (0x30, Opcode::BMI, Value::Relative(0x34)), (0x31, Opcode::AND, Value::PreIdxIndY(0x34)),
+            (0x35, Opcode::AND, Value::ZeroPageX(0x34)), (0x36, Opcode::ROL, Value::ZeroPageX(0x34)),
+            (0x38, Opcode::SEC, Value::Implied), (0x39, Opcode::AND, Value::AbsoluteY(0x1234)),
+            (0x3D, Opcode::AND, Value::AbsoluteX(0x1234)), (0x3E, Opcode::ROL, Value::AbsoluteX(0x1234)),
+            (0x40, Opcode::RTI, Value::Implied), (0x41, Opcode::EOR, Value::PreIdxIndX(0x34)),
+            (0x45, Opcode::EOR, Value::ZeroPage(0x34)), (0x46, Opcode::LSR, Value::ZeroPage(0x34)),
+            (0x48, Opcode::PHA, Value::Implied), (0x49, Opcode::EOR, Value::Immediate(0x34)),
+            (0x4A, Opcode::LSR, Value::Accumulator), (0x4C, Opcode::JMP, Value::Absolute(0x1234)),
+            (0x4D, Opcode::EOR, Value::Absolute(0x1234)), (0x4E, Opcode::LSR, Value::Absolute(0x1234)),
+            (0x50, Opcode::BVC, Value::Relative(0x34)), (0x51, Opcode::EOR, Value::PreIdxIndY(0x34)),
+            (0x55, Opcode::EOR, Value::ZeroPageX(0x34)), (0x56, Opcode::LSR, Value::ZeroPageX(0x34)),
+            (0x58, Opcode::CLI, Value::Implied), (0x59, Opcode::EOR, Value::AbsoluteY(0x1234)),
+            (0x5D, Opcode::EOR, Value::AbsoluteX(0x1234)), (0x5E, Opcode::LSR, Value::AbsoluteX(0x1234)),
+            (0x60, Opcode::RTS, Value::Implied), (0x61, Opcode::ADC, Value::PreIdxIndX(0x34)),
+            (0x65, Opcode::ADC, Value::ZeroPage(0x34)), (0x66, Opcode::ROR, Value::ZeroPage(0x34)),
+            (0x68, Opcode::PLA, Value::Implied), (0x69, Opcode::ADC, Value::Immediate(0x34)),
+            (0x6A, Opcode::ROR, Value::Accumulator), (0x6C, Opcode::JMP, Value::Indirect(0x1234)),
+            (0x6D, Opcode::ADC, Value::Absolute(0x1234)), (0x6E, Opcode::ROR, Value::Absolute(0x1234)),
+            (0x70, Opcode::BVS, Value::Relative(0x34)), (0x71, Opcode::ADC, Value::PreIdxIndY(0x34)),
+            (0x75, Opcode::ADC, Value::ZeroPageX(0x34)), (0x76, Opcode::ROR, Value::ZeroPageX(0x34)),
+            (0x78, Opcode::SEI, Value::Implied), (0x79, Opcode::ADC, Value::AbsoluteY(0x1234)),
+            (0x7D, Opcode::ADC, Value::AbsoluteX(0x1234)), (0x7E, Opcode::ROR, Value::AbsoluteX(0x1234)),
+            (0x81, Opcode::STA, Value::PreIdxIndX(0x34)), (0x84, Opcode::STY, Value::ZeroPage(0x34)),
+            (0x85, Opcode::STA, Value::ZeroPage(0x34)), (0x86, Opcode::STX, Value::ZeroPage(0x34)),
+            (0x88, Opcode::DEY, Value::Implied), (0x8A, Opcode::TXA, Value::Implied),
+            (0x8C, Opcode::STY, Value::Absolute(0x1234)), (0x8D, Opcode::STA, Value::Absolute(0x1234)),
+            (0x8E, Opcode::STX, Value::Absolute(0x1234)), (0x90, Opcode::BCC, Value::Relative(0x34)),
+            (0x91, Opcode::STA, Value::PreIdxIndY(0x34)), (0x94, Opcode::STY, Value::ZeroPageX(0x34)),
+            (0x95, Opcode::STA, Value::ZeroPageX(0x34)), (0x96, Opcode::STX, Value::ZeroPageY(0x34)),
+            (0x98, Opcode::TYA, Value::Implied), (0x99, Opcode::STA, Value::AbsoluteY(0x1234)),
+            (0x9A, Opcode::TXS, Value::Implied), (0x9D, Opcode::STA, Value::AbsoluteX(0x1234)),
+            (0xA0, Opcode::LDY, Value::Immediate(0x34)), (0xA1, Opcode::LDA, Value::PreIdxIndX(0x34)),
+            (0xA2, Opcode::LDX, Value::Immediate(0x34)), (0xA4, Opcode::LDY, Value::ZeroPage(0x34)),
+            (0xA5, Opcode::LDA, Value::ZeroPage(0x34)), (0xA6, Opcode::LDX, Value::ZeroPage(0x34)),
+            (0xA8, Opcode::TAY, Value::Implied), (0xA9, Opcode::LDA, Value::Immediate(0x34)),
+            (0xAA, Opcode::TAX, Value::Implied), (0xAC, Opcode::LDY, Value::Absolute(0x1234)),
+            (0xAD, Opcode::LDA, Value::Absolute(0x1234)), (0xAE, Opcode::LDX, Value::Absolute(0x1234)),
+            (0xB0, Opcode::BCS, Value::Relative(0x34)), (0xB1, Opcode::LDA, Value::PreIdxIndY(0x34)),
+            (0xB4, Opcode::LDY, Value::ZeroPageX(0x34)), (0xB5, Opcode::LDA, Value::ZeroPageX(0x34)),
+            (0xB6, Opcode::LDX, Value::ZeroPageY(0x34)), (0xB8, Opcode::CLV, Value::Implied),
+            (0xB9, Opcode::LDA, Value::AbsoluteY(0x1234)), (0xBA, Opcode::TSX, Value::Implied),
+            (0xBC, Opcode::LDY, Value::AbsoluteX(0x1234)), (0xBD, Opcode::LDA, Value::AbsoluteX(0x1234)),
+            (0xBE, Opcode::LDX, Value::AbsoluteY(0x1234)), (0xC0, Opcode::CPY, Value::Immediate(0x34)),
+            (0xC1, Opcode::CMP, Value::PreIdxIndX(0x34)), (0xC4, Opcode::CPY, Value::ZeroPage(0x34)),
+            (0xC5, Opcode::CMP, Value::ZeroPage(0x34)), (0xC6, Opcode::DEC, Value::ZeroPage(0x34)),
+            (0xC8, Opcode::INY, Value::Implied), (0xC9, Opcode::CMP, Value::Immediate(0x34)),
+            (0xCA, Opcode::DEX, Value::Implied), (0xCC, Opcode::CPY, Value::Absolute(0x1234)),
+            (0xCD, Opcode::CMP, Value::Absolute(0x1234)), (0xCE, Opcode::DEC, Value::Absolute(0x1234)),
+            (0xD0, Opcode::BNE, Value::Relative(0x34)), (0xD1, Opcode::CMP, Value::PreIdxIndY(0x34)),
+            (0xD5, Opcode::CMP, Value::ZeroPageX(0x34)), (0xD6, Opcode::DEC, Value::ZeroPageX(0x34)),
+            (0xD8, Opcode::CLD, Value::Implied), (0xD9, Opcode::CMP, Value::AbsoluteY(0x1234)),
+            (0xDD, Opcode::CMP, Value::AbsoluteX(0x1234)), (0xDE, Opcode::DEC, Value::AbsoluteX(0x1234)),
+            (0xE0, Opcode::CPX, Value::Immediate(0x34)), (0xE1, Opcode::SBC, Value::PreIdxIndX(0x34)),
+            (0xE4, Opcode::CPX, Value::ZeroPage(0x34)), (0xE5, Opcode::SBC, Value::ZeroPage(0x34)),
+            (0xE6, Opcode::INC, Value::ZeroPage(0x34)), (0xE8, Opcode::INX, Value::Implied),
+            (0xE9, Opcode::SBC, Value::Immediate(0x34)), (0xEA, Opcode::NOP, Value::Implied),
+            (0xEC, Opcode::CPX, Value::Absolute(0x1234)), (0xED, Opcode::SBC, Value::Absolute(0x1234)),
+            (0xEE, Opcode::INC, Value::Absolute(0x1234)), (0xF0, Opcode::BEQ, Value::Relative(0x34)),
+            (0xF1, Opcode::SBC, Value::PreIdxIndY(0x34)), (0xF5, Opcode::SBC, Value::ZeroPageX(0x34)),
+            (0xF6, Opcode::INC, Value::ZeroPageX(0x34)), (0xF8, Opcode::SED, Value::Implied),
+            (0xF9, Opcode::SBC, Value::AbsoluteY(0x1234)), (0xFD, Opcode::SBC, Value::AbsoluteX(0x1234)),
+            (0xFE, Opcode::INC, Value::AbsoluteX(0x1234)),
+        ];
+        assert_eq!(table.len(), 151);
+
+        for &(byte, op, val) in table {
+            let mut rig = cpu_with(&[byte, 0x34, 0x12]);
+            let (len, inst) = Instruction::get(&mut rig.bus, ORG);
+            let expected = match val {
+                Value::Implied | Value::Accumulator => 1,
+                Value::Absolute(_) | Value::AbsoluteX(_) | Value::AbsoluteY(_) | Value::Indirect(_) => 3,
+                _ => 2,
+            };
+
+            assert_eq!(inst, Instruction(op, val), "opcode {:02X}", byte);
+            assert_eq!(len, expected, "opcode {:02X}", byte);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,207 @@
+use inst::{Instruction, Opcode, Value};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const VECTORS: [(u16, &'static str); 3] = [
+    (0xFFFA, "nmi"),
+    (0xFFFC, "reset"),
+    (0xFFFE, "irq"),
+];
+
+/// A PRG-ROM bank as it appears in the CPU address space.
+pub struct Bank<'a> {
+    bytes: &'a [u8],
+    base: u16,
+}
+
+impl<'a> Bank<'a> {
+    pub fn new(bytes: &'a [u8], base: u16) -> Self {
+        Bank {
+            bytes: bytes,
+            base: base,
+        }
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.base && ((addr - self.base) as usize) < self.bytes.len()
+    }
+
+    fn word(&self, addr: u16) -> Option<u16> {
+        match self.contains(addr) && self.contains(addr + 1) {
+            true => {
+                let offs = (addr - self.base) as usize;
+                Some((self.bytes[offs] as u16) | ((self.bytes[offs + 1] as u16) << 8))
+            }
+            false => None,
+        }
+    }
+
+    /// Where code ends: the vector table, if this bank holds it.
+    fn code_end(&self) -> usize {
+        match self.contains(0xFFFA) {
+            true => (0xFFFA - self.base) as usize,
+            false => self.bytes.len(),
+        }
+    }
+
+    /// Decodes the whole bank front to back, as `(address, bytes, instruction)`.
+    /// Bytes that don't form a whole instruction come back as `None`.
+    fn sweep(&self) -> Vec<(u16, &'a [u8], Option<Instruction>)> {
+        let end = self.code_end();
+        let mut lines = Vec::new();
+        let mut offs = 0;
+
+        while offs < end {
+            let addr = self.base.wrapping_add(offs as u16);
+            match Instruction::decode(&self.bytes[offs..end]) {
+                Some((len, inst)) => {
+                    lines.push((addr, &self.bytes[offs..offs + len as usize], Some(inst)));
+                    offs += len as usize;
+                }
+                None => {
+                    lines.push((addr, &self.bytes[offs..offs + 1], None));
+                    offs += 1;
+                }
+            }
+        }
+
+        lines
+    }
+
+    /// Labels for the interrupt vectors and every branch, jump and JSR
+    /// target that lands inside the bank.
+    pub fn labels(&self) -> BTreeMap<u16, String> {
+        let mut labels = BTreeMap::new();
+
+        for &(vector, name) in VECTORS.iter() {
+            if let Some(target) = self.word(vector) {
+                if self.contains(target) {
+                    labels.entry(target).or_insert(name.to_owned());
+                }
+            }
+        }
+
+        for (addr, _, inst) in self.sweep() {
+            let inst = match inst {
+                Some(inst) => inst,
+                None => continue,
+            };
+
+            if let Some(target) = inst.target(addr) {
+                if self.contains(target) {
+                    labels.entry(target).or_insert_with(|| match inst.0 {
+                        Opcode::JSR => format!("sub_{:04X}", target),
+                        _ => format!("L{:04X}", target),
+                    });
+                }
+            }
+        }
+
+        labels
+    }
+
+    pub fn disassemble(&self) -> String {
+        let labels = self.labels();
+        let mut out = String::new();
+
+        for (addr, bytes, inst) in self.sweep() {
+            if let Some(label) = labels.get(&addr) {
+                let _ = writeln!(out, "{}:", label);
+            }
+
+            let text = match inst {
+                Some(inst) => operand_text(inst, addr, &labels),
+                None => format!(".db ${:02X}", bytes[0]),
+            };
+            let _ = writeln!(out, "    {:04X}  {:<8}  {}", addr, hex(bytes), text);
+        }
+
+        for &(vector, name) in VECTORS.iter() {
+            if let Some(target) = self.word(vector) {
+                let offs = (vector - self.base) as usize;
+                let _ = writeln!(out, "    {:04X}  {:<8}  .dw {:<10} ; {}",
+                                 vector,
+                                 hex(&self.bytes[offs..offs + 2]),
+                                 name_or_addr(target, &labels),
+                                 name.to_uppercase());
+            }
+        }
+
+        out
+    }
+}
+
+fn operand_text(inst: Instruction, addr: u16, labels: &BTreeMap<u16, String>) -> String {
+    match (inst, inst.target(addr)) {
+        (Instruction(op, _), Some(target)) => format!("{:?} {}", op, name_or_addr(target, labels)),
+        (Instruction(op, Value::Implied), None) => format!("{:?}", op),
+        (Instruction(op, val), None) => format!("{:?} {}", op, val),
+    }
+}
+
+fn name_or_addr(addr: u16, labels: &BTreeMap<u16, String>) -> String {
+    match labels.get(&addr) {
+        Some(label) => label.clone(),
+        None => format!("${:04X}", addr),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank() -> Vec<u8> {
+        let mut bank = vec![0xEAu8; 0x4000];
+        // C000: SEI / JSR $C010 / BNE $C000 / JMP $C000
+        bank[0x0000..0x0009].copy_from_slice(&[0x78, 0x20, 0x10, 0xC0, 0xD0, 0xFA, 0x4C, 0x00, 0xC0]);
+        // C010: RTS
+        bank[0x0010] = 0x60;
+        bank[0x3FFA..0x4000].copy_from_slice(&[0x10, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+        bank
+    }
+
+    #[test]
+    fn labels_vectors_and_targets() {
+        let bytes = bank();
+        let labels = Bank::new(&bytes, 0xC000).labels();
+
+        assert_eq!(labels.get(&0xC000).map(|s| s.as_str()), Some("reset"));
+        assert_eq!(labels.get(&0xC010).map(|s| s.as_str()), Some("nmi"));
+        assert_eq!(labels.len(), 2);
+    }
+
+    #[test]
+    fn disassembles_with_labels() {
+        let bytes = bank();
+        let text = Bank::new(&bytes, 0xC000).disassemble();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines[0], "reset:");
+        assert_eq!(lines[1], "    C000  78        SEI");
+        assert_eq!(lines[2], "    C001  20 10 C0  JSR nmi");
+        assert_eq!(lines[3], "    C004  D0 FA     BNE reset");
+        assert_eq!(lines[4], "    C006  4C 00 C0  JMP reset");
+        assert!(text.contains("    FFFC  00 C0     .dw reset      ; RESET"));
+    }
+
+    #[test]
+    fn truncated_instruction_is_data() {
+        let bytes = [0xEA, 0xAD, 0x00];
+        let text = Bank::new(&bytes, 0x8000).disassemble();
+
+        assert!(text.contains("8001  AD        .db $AD"));
+    }
+
+    #[test]
+    fn decode_is_pure() {
+        assert_eq!(Instruction::decode(&[0xB1, 0x30]), Some((2, Instruction(Opcode::LDA, Value::PreIdxIndY(0x30)))));
+        assert_eq!(Instruction::decode(&[0x6C, 0x34]), None);
+        assert_eq!(Instruction::decode(&[]), None);
+        assert_eq!(Instruction(Opcode::BNE, Value::Relative(-6)).target(0xC004), Some(0xC000));
+    }
+}
@@ -0,0 +1,198 @@
+use audio::AudioSink;
+use controller::Buttons;
+use nes::NES;
+use ppu::{FRAME_WIDTH, FRAME_HEIGHT};
+
+use png;
+
+use std::fs::File;
+use std::io::BufWriter;
+
+/// Controller input for a headless run, as frame numbers and the buttons
+/// held from that frame until the next change.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputScript {
+    changes: Vec<(u64, [Buttons; 2])>,
+}
+
+impl InputScript {
+    /// Reads lines of `<frame> [button...]`, where buttons take the names
+    /// from keys.cfg and a `2.` in front for the second controller. A line
+    /// with no buttons lets go of everything.
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut changes: Vec<(u64, [Buttons; 2])> = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |what: String| format!("line {}: {}", number + 1, what);
+
+            let mut words = line.split_whitespace();
+            let frame = words.next().unwrap();
+            let frame = frame.parse::<u64>().map_err(|_| error(format!("bad frame number {:?}", frame)))?;
+            if changes.last().is_some_and(|&(last, _)| frame <= last) {
+                return Err(error(format!("frame {} isn't after the line before", frame)));
+            }
+
+            let mut buttons = [Buttons::empty(); 2];
+            for word in words {
+                let (player, name) = match word.find('.') {
+                    Some(dot) => (&word[..dot], &word[dot + 1..]),
+                    None => ("1", word),
+                };
+                let player = match player {
+                    "1" => 0,
+                    "2" => 1,
+                    _ => return Err(error(format!("no player {}, only 1 and 2", player))),
+                };
+                buttons[player] |= Buttons::from_name(name).ok_or_else(|| error(format!("unknown button {:?}", name)))?;
+            }
+
+            changes.push((frame, buttons));
+        }
+
+        Ok(InputScript { changes: changes })
+    }
+
+    /// What's held during `frame`.
+    pub fn buttons(&self, frame: u64) -> [Buttons; 2] {
+        self.changes.iter()
+            .take_while(|&&(from, _)| from <= frame)
+            .last()
+            .map_or([Buttons::empty(); 2], |&(_, buttons)| buttons)
+    }
+}
+
+/// Runs until `frames` more frames have finished, as fast as the host
+/// allows. Every run of the same ROM and script ends on the same frame.
+pub fn run(nes: &mut NES, frames: u64, script: &InputScript, sinks: &mut [Box<dyn AudioSink>]) -> Result<(), String> {
+    let end = nes.mem.ppu.frames + frames;
+    let mut frame = None;
+
+    while nes.mem.ppu.frames < end {
+        // Buttons change between frames, like a frontend polling at vblank
+        if frame != Some(nes.mem.ppu.frames) {
+            frame = Some(nes.mem.ppu.frames);
+            let buttons = script.buttons(nes.mem.ppu.frames);
+            nes.mem.controllers[0].buttons = buttons[0];
+            nes.mem.controllers[1].buttons = buttons[1];
+        }
+
+        nes.step()?;
+        if nes.cpu.halted() {
+            return Err(format!("CPU jammed on frame {}", nes.mem.ppu.frames));
+        }
+
+        let samples = nes.mem.apu.take_samples();
+        for sink in sinks.iter_mut() {
+            sink.write(&samples).map_err(|e| format!("Couldn't write audio: {}", e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// FNV-1a over the picture, for comparing against golden runs.
+pub fn frame_hash(frame: &[u32]) -> u64 {
+    frame.iter().fold(0xCBF29CE484222325, |hash, &pixel| {
+        (0..3).fold(hash, |hash, i| (hash ^ (pixel >> (16 - i * 8)) as u8 as u64).wrapping_mul(0x100000001B3))
+    })
+}
+
+pub fn write_png(path: &str, frame: &[u32]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("Couldn't create {}: {}", path, e))?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), FRAME_WIDTH as u32, FRAME_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut rgb = Vec::with_capacity(frame.len() * 3);
+    for &pixel in frame {
+        rgb.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+    }
+
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(&rgb))
+        .map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::test_rom;
+
+    /// Turns rendering on, then copies controller 1's buttons into the
+    /// backdrop color every frame.
+    fn nes() -> NES {
+        let mut prg = vec![0xEAu8; 0x4000];
+        let code = [
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80, STA $2000
+            0xA9, 0x08, 0x8D, 0x01, 0x20, // LDA #$08, STA $2001
+            0x4C, 0x0A, 0xC0,             // JMP *
+        ];
+        prg[..code.len()].copy_from_slice(&code);
+        let nmi = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // LDA #1, STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #0, STA $4016
+            0xAD, 0x16, 0x40, 0x29, 0x01, // LDA $4016, AND #1
+            0x0A, 0x0A, 0x0A, 0x0A,       // ASL x4
+            0xA2, 0x3F, 0x8E, 0x06, 0x20, // LDX #$3F, STX $2006
+            0xA2, 0x00, 0x8E, 0x06, 0x20, // LDX #$00, STX $2006
+            0x8D, 0x07, 0x20,             // STA $2007
+            0x8E, 0x06, 0x20, 0x8E, 0x06, 0x20, // STX $2006 twice
+            0x40,                         // RTI
+        ];
+        prg[0x100..0x100 + nmi.len()].copy_from_slice(&nmi);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xC1, 0x00, 0xC0, 0x00, 0xC0]);
+        NES::from_rom(test_rom(0, 0, &prg, &[0; 0x2000]))
+    }
+
+    #[test]
+    fn scripts() {
+        let script = InputScript::parse("# walk right, then jump\n10 right\n20 right a 2.start\n\n30\n").unwrap();
+        assert_eq!(script.buttons(0), [Buttons::empty(); 2]);
+        assert_eq!(script.buttons(15), [Buttons::RIGHT, Buttons::empty()]);
+        assert_eq!(script.buttons(20), [Buttons::RIGHT | Buttons::A, Buttons::START]);
+        assert_eq!(script.buttons(1000), [Buttons::empty(); 2]);
+    }
+
+    #[test]
+    fn script_errors() {
+        assert_eq!(InputScript::parse("ten a").unwrap_err(), "line 1: bad frame number \"ten\"");
+        assert_eq!(InputScript::parse("5 a\n5 b").unwrap_err(), "line 2: frame 5 isn't after the line before");
+        assert_eq!(InputScript::parse("5 3.a").unwrap_err(), "line 1: no player 3, only 1 and 2");
+        assert_eq!(InputScript::parse("5 turbo").unwrap_err(), "line 1: unknown button \"turbo\"");
+    }
+
+    #[test]
+    fn runs_are_deterministic() {
+        let script = InputScript::parse("5 a\n8").unwrap();
+        let mut hashes = Vec::new();
+
+        for _ in 0..2 {
+            let mut nes = nes();
+            run(&mut nes, 7, &script, &mut []).unwrap();
+            assert_eq!(nes.mem.ppu.frames, 7);
+            hashes.push(frame_hash(&nes.mem.ppu.frame));
+        }
+        assert_eq!(hashes[0], hashes[1]);
+
+        // Holding A shows up as a different backdrop
+        let mut nes = nes();
+        run(&mut nes, 7, &InputScript::default(), &mut []).unwrap();
+        assert!(frame_hash(&nes.mem.ppu.frame) != hashes[0]);
+    }
+
+    #[test]
+    fn hash_covers_every_channel() {
+        let black = frame_hash(&[0x000000; 4]);
+        assert!(frame_hash(&[0x000001, 0, 0, 0]) != black);
+        assert!(frame_hash(&[0x000100, 0, 0, 0]) != black);
+        assert!(frame_hash(&[0x010000, 0, 0, 0]) != black);
+        // The unused top byte doesn't count
+        assert_eq!(frame_hash(&[0xFF000000, 0, 0, 0]), black);
+    }
+}
@@ -1,189 +1,554 @@
-use cpu::NMOS6502;
+use mem::Bus;
 
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Value {
     Implied,
+    Accumulator,
     PreIdxIndX(u8),
     Immediate(u8),
     Absolute(u16),
     Relative(i8),
     ZeroPage(u8),
     ZeroPageX(u8),
+    ZeroPageY(u8),
     AbsoluteX(u16),
+    AbsoluteY(u16),
+    Indirect(u16),
     PreIdxIndY(u8),
 }
 
+fn word(b: &[u8; 3]) -> u16 {
+    (b[1] as u16) | ((b[2] as u16) << 8)
+}
+
 macro_rules! imp {
-    ($instr:ident, $cpu:ident) => {{ (1, (Instruction(Opcode::$instr, Value::Implied))) }}
+    ($instr:ident, $b:ident) => {{ (1, (Instruction(Opcode::$instr, Value::Implied))) }}
+}
+
+macro_rules! acc {
+    ($instr:ident, $b:ident) => {{ (1, (Instruction(Opcode::$instr, Value::Accumulator))) }}
 }
 
 macro_rules! imm {
-    ($instr:ident, $cpu:ident) => {{ (2, (Instruction(Opcode::$instr, Value::Immediate($cpu.read8_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::Immediate($b[1])))) }}
 }
 
 macro_rules! abs {
-    ($instr:ident, $cpu:ident) => {{ (3, (Instruction(Opcode::$instr, Value::Absolute($cpu.read16_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (3, (Instruction(Opcode::$instr, Value::Absolute(word($b))))) }}
 }
 
 macro_rules! rel {
-    ($instr:ident, $cpu:ident) => {{ (2, (Instruction(Opcode::$instr, Value::Relative($cpu.read8_pc() as i8)))) }}
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::Relative($b[1] as i8)))) }}
 }
 
 macro_rules! zpg {
-    ($instr:ident, $cpu:ident) => {{ (2, (Instruction(Opcode::$instr, Value::ZeroPage($cpu.read8_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::ZeroPage($b[1])))) }}
 }
 
 macro_rules! izx {
-    ($instr:ident, $cpu:ident) => {{ (2, (Instruction(Opcode::$instr, Value::PreIdxIndX($cpu.read8_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::PreIdxIndX($b[1])))) }}
 }
 
 macro_rules! zpx {
-    ($instr:ident, $cpu:ident) => {{ (2, (Instruction(Opcode::$instr, Value::ZeroPageX($cpu.read8_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::ZeroPageX($b[1])))) }}
+}
+
+macro_rules! zpy {
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::ZeroPageY($b[1])))) }}
 }
 
 macro_rules! abx {
-    ($instr:ident, $cpu:ident) => {{ (3, (Instruction(Opcode::$instr, Value::AbsoluteX($cpu.read16_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (3, (Instruction(Opcode::$instr, Value::AbsoluteX(word($b))))) }}
+}
+
+macro_rules! aby {
+    ($instr:ident, $b:ident) => {{ (3, (Instruction(Opcode::$instr, Value::AbsoluteY(word($b))))) }}
+}
+
+macro_rules! ind {
+    ($instr:ident, $b:ident) => {{ (3, (Instruction(Opcode::$instr, Value::Indirect(word($b))))) }}
 }
 
 macro_rules! izy {
-    ($instr:ident, $cpu:ident) => {{ (2, (Instruction(Opcode::$instr, Value::PreIdxIndY($cpu.read8_pc())))) }}
+    ($instr:ident, $b:ident) => {{ (2, (Instruction(Opcode::$instr, Value::PreIdxIndY($b[1])))) }}
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode {
+    BRK,    // 00
     PHP,    // 08
-    ORA,    // 01 09
-    ASL,    // 0A
+    ORA,    // 01 05 09 0D 11 15 19 1D
+    ASL,    // 06 0A 0E 16 1E
     BPL,    // 10
     CLC,    // 18
     JSR,    // 20
     BIT,    // 24 2C
     PLP,    // 28
-    AND,    // 21 29
-    ROL,    // 2A
+    AND,    // 21 25 29 2D 31 35 39 3D
+    ROL,    // 26 2A 2E 36 3E
     BMI,    // 30
     SEC,    // 38
     RTI,    // 40
     PHA,    // 48
-    EOR,    // 41 49
-    LSR,    // 4A
-    JMP,    // 4C
+    EOR,    // 41 45 49 4D 51 55 59 5D
+    LSR,    // 46 4A 4E 56 5E
+    JMP,    // 4C 6C
     BVC,    // 50
+    CLI,    // 58
     RTS,    // 60
     PLA,    // 68
-    ADC,    // 61 65 69
-    ROR,    // 6A
+    ADC,    // 61 65 69 6D 71 75 79 7D
+    ROR,    // 66 6A 6E 76 7E
     BVS,    // 70
     SEI,    // 78
-    STX,    // 86 8E
-    STA,    // 81 85 8D 95 9D
+    STY,    // 84 8C 94
+    STX,    // 86 8E 96
+    STA,    // 81 85 8D 91 95 99 9D
     DEY,    // 88
     TXA,    // 8A
     BCC,    // 90
     TYA,    // 98
     TXS,    // 9A
-    LDY,    // A0 A4
-    LDX,    // A2 AE
-    LDA,    // A1 A5 A9 AD B1
+    LDY,    // A0 A4 AC B4 BC
+    LDX,    // A2 A6 AE B6 BE
+    LDA,    // A1 A5 A9 AD B1 B5 B9 BD
     TAY,    // A8
     TAX,    // AA
     BCS,    // B0
     CLV,    // B8
     TSX,    // BA
-    CPY,    // C0
+    CPY,    // C0 C4 CC
     INY,    // C8
-    CMP,    // C1 C9
+    CMP,    // C1 C5 C9 CD D1 D5 D9 DD
+    DEC,    // C6 CE D6 DE
     DEX,    // CA
     BNE,    // D0
     CLD,    // D8
-    CPX,    // E0
+    CPX,    // E0 E4 EC
     INX,    // E8
-    SBC,    // E1 E9
+    SBC,    // E1 E5 E9 ED F1 F5 F9 FD
     NOP,    // EA
+    INC,    // E6 EE F6 FE
     BEQ,    // F0
     SED,    // F8
-    INC,    // E6
-    Unknown(u8),
+
+    // Stable undocumented opcodes
+    SLO,    // 03 07 0F 13 17 1B 1F
+    RLA,    // 23 27 2F 33 37 3B 3F
+    SRE,    // 43 47 4F 53 57 5B 5F
+    RRA,    // 63 67 6F 73 77 7B 7F
+    SAX,    // 83 87 8F 97
+    LAX,    // A3 A7 AF B3 B7 BF
+    DCP,    // C3 C7 CF D3 D7 DB DF
+    ISB,    // E3 E7 EF F3 F7 FB FF
+    ANC,    // 0B 2B
+    ALR,    // 4B
+    ARR,    // 6B
+    AXS,    // CB
+
+    // Unstable undocumented opcodes, see `cpu::Unstable`
+    XAA,    // 8B
+    AHX,    // 93 9F
+    TAS,    // 9B
+    SHY,    // 9C
+    SHX,    // 9E
+    LXA,    // AB
+    LAS,    // BB
+
+    JAM,    // 02 12 22 32 42 52 62 72 92 B2 D2 F2
 }
 
-#[derive(Debug)]
+impl Opcode {
+    /// Mnemonics that only exist as undocumented opcodes. NOP and SBC also
+    /// have undocumented encodings, which only the opcode byte tells apart.
+    pub fn is_undocumented(&self) -> bool {
+        match *self {
+            Opcode::SLO | Opcode::RLA | Opcode::SRE | Opcode::RRA | Opcode::SAX | Opcode::LAX |
+            Opcode::DCP | Opcode::ISB | Opcode::ANC | Opcode::ALR | Opcode::ARR | Opcode::AXS |
+            Opcode::JAM => true,
+            op => op.is_unstable(),
+        }
+    }
+
+    pub fn is_unstable(&self) -> bool {
+        match *self {
+            Opcode::XAA | Opcode::AHX | Opcode::TAS | Opcode::SHY | Opcode::SHX | Opcode::LXA | Opcode::LAS => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction(pub Opcode, pub Value);
 
 impl Instruction {
-    pub fn get(cpu: &mut NMOS6502) -> (u16, Self) {
-        match {cpu.read8_pc()} {
-            0x01 => izx!(ORA, cpu),
-            0x08 => imp!(PHP, cpu),
-            0x09 => imm!(ORA, cpu),
-            0x0A => imp!(ASL, cpu),
-            0x10 => rel!(BPL, cpu),
-            0x18 => imp!(CLC, cpu),
-            0x30 => rel!(BMI, cpu),
-            0x38 => imp!(SEC, cpu),
-            0x20 => abs!(JSR, cpu),
-            0x21 => izx!(AND, cpu),
-            0x24 => zpg!(BIT, cpu),
-            0x28 => imp!(PLP, cpu),
-            0x29 => imm!(AND, cpu),
-            0x2A => imp!(ROL, cpu),
-            0x2C => abs!(BIT, cpu),
-            0x40 => imp!(RTI, cpu),
-            0x41 => izx!(EOR, cpu),
-            0x48 => imp!(PHA, cpu),
-            0x49 => imm!(EOR, cpu),
-            0x4a => imp!(LSR, cpu),
-            0x4C => abs!(JMP, cpu),
-            0x50 => rel!(BVC, cpu),
-            0x60 => imp!(RTS, cpu),
-            0x61 => izx!(ADC, cpu),
-            0x65 => zpg!(ADC, cpu),
-            0x68 => imp!(PLA, cpu),
-            0x69 => imm!(ADC, cpu),
-            0x6A => imp!(ROR, cpu),
-            0x70 => rel!(BVS, cpu),
-            0x78 => imp!(SEI, cpu),
-            0x81 => izx!(STA, cpu),
-            0x85 => zpg!(STA, cpu),
-            0x86 => zpg!(STX, cpu),
-            0x88 => imp!(DEY, cpu),
-            0x8A => imp!(TXA, cpu),
-            0x8D => abs!(STA, cpu),
-            0x8E => abs!(STX, cpu),
-            0x90 => rel!(BCC, cpu),
-            0x95 => zpx!(STA, cpu),
-            0x98 => imp!(TYA, cpu),
-            0x9A => imp!(TXS, cpu),
-            0x9D => abx!(STA, cpu),
-            0xA0 => imm!(LDY, cpu),
-            0xA1 => izx!(LDA, cpu),
-            0xA2 => imm!(LDX, cpu),
-            0xA4 => zpg!(LDY, cpu),
-            0xA5 => zpg!(LDA, cpu),
-            0xA8 => imp!(TAY, cpu),
-            0xA9 => imm!(LDA, cpu),
-            0xAA => imp!(TAX, cpu),
-            0xAD => abs!(LDA, cpu),
-            0xAE => abs!(LDX, cpu),
-            0xB0 => rel!(BCS, cpu),
-            0xB1 => izy!(LDA, cpu),
-            0xB8 => imp!(CLV, cpu),
-            0xBA => imp!(TSX, cpu),
-            0xC0 => imm!(CPY, cpu),
-            0xC1 => izx!(CMP, cpu),
-            0xC8 => imp!(INY, cpu),
-            0xC9 => imm!(CMP, cpu),
-            0xCA => imp!(DEX, cpu),
-            0xD0 => rel!(BNE, cpu),
-            0xD8 => imp!(CLD, cpu),
-            0xE0 => imm!(CPX, cpu),
-            0xE1 => izx!(SBC, cpu),
-            0xE6 => zpg!(INC, cpu),
-            0xE8 => imp!(INX, cpu),
-            0xE9 => imm!(SBC, cpu),
-            0xEA => imp!(NOP, cpu),
-            0xF0 => rel!(BEQ, cpu),
-            0xF8 => imp!(SED, cpu),
-            op => (0, Instruction(Opcode::Unknown(op), Value::Implied)),
+    /// Decodes the instruction at the start of `bytes`, returning its
+    /// length, or `None` if the slice ends before its operands do.
+    pub fn decode(bytes: &[u8]) -> Option<(u16, Self)> {
+        if bytes.is_empty() {
+            return None;
+        }
+
+        let mut b = [0u8; 3];
+        for (dst, src) in b.iter_mut().zip(bytes) {
+            *dst = *src;
+        }
+
+        let (len, inst) = Self::lookup(&b);
+        match bytes.len() >= len as usize {
+            true => Some((len, inst)),
+            false => None,
+        }
+    }
+
+    /// Length in bytes of the instruction starting with `op`.
+    pub fn len(op: u8) -> u16 {
+        Self::lookup(&[op, 0, 0]).0
+    }
+
+    /// Fetches and decodes the instruction at `pc` over the bus.
+    pub fn get<B: Bus>(bus: &mut B, pc: u16) -> (u16, Self) {
+        let op = bus.read8(pc);
+        let len = Self::len(op);
+
+        let mut b = [op, 0, 0];
+        for i in 1..len {
+            b[i as usize] = bus.read8(pc.wrapping_add(i));
+        }
+
+        Self::lookup(&b)
+    }
+
+    /// Address this instruction jumps or branches to, given its own address.
+    pub fn target(&self, addr: u16) -> Option<u16> {
+        match *self {
+            Instruction(_, Value::Relative(offs)) => Some(addr.wrapping_add(2).wrapping_add(offs as i16 as u16)),
+            Instruction(Opcode::JMP, Value::Absolute(addr)) | Instruction(Opcode::JSR, Value::Absolute(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    fn lookup(b: &[u8; 3]) -> (u16, Self) {
+        match b[0] {
+            0x00 => imp!(BRK, b),
+            0x01 => izx!(ORA, b),
+            0x02 => imp!(JAM, b),
+            0x03 => izx!(SLO, b),
+            0x04 => zpg!(NOP, b),
+            0x05 => zpg!(ORA, b),
+            0x06 => zpg!(ASL, b),
+            0x07 => zpg!(SLO, b),
+            0x08 => imp!(PHP, b),
+            0x09 => imm!(ORA, b),
+            0x0A => acc!(ASL, b),
+            0x0B => imm!(ANC, b),
+            0x0C => abs!(NOP, b),
+            0x0D => abs!(ORA, b),
+            0x0E => abs!(ASL, b),
+            0x0F => abs!(SLO, b),
+            0x10 => rel!(BPL, b),
+            0x11 => izy!(ORA, b),
+            0x12 => imp!(JAM, b),
+            0x13 => izy!(SLO, b),
+            0x14 => zpx!(NOP, b),
+            0x15 => zpx!(ORA, b),
+            0x16 => zpx!(ASL, b),
+            0x17 => zpx!(SLO, b),
+            0x18 => imp!(CLC, b),
+            0x19 => aby!(ORA, b),
+            0x1A => imp!(NOP, b),
+            0x1B => aby!(SLO, b),
+            0x1C => abx!(NOP, b),
+            0x1D => abx!(ORA, b),
+            0x1E => abx!(ASL, b),
+            0x1F => abx!(SLO, b),
+            0x20 => abs!(JSR, b),
+            0x21 => izx!(AND, b),
+            0x22 => imp!(JAM, b),
+            0x23 => izx!(RLA, b),
+            0x24 => zpg!(BIT, b),
+            0x25 => zpg!(AND, b),
+            0x26 => zpg!(ROL, b),
+            0x27 => zpg!(RLA, b),
+            0x28 => imp!(PLP, b),
+            0x29 => imm!(AND, b),
+            0x2A => acc!(ROL, b),
+            0x2B => imm!(ANC, b),
+            0x2C => abs!(BIT, b),
+            0x2D => abs!(AND, b),
+            0x2E => abs!(ROL, b),
+            0x2F => abs!(RLA, b),
+            0x30 => rel!(BMI, b),
+            0x31 => izy!(AND, b),
+            0x32 => imp!(JAM, b),
+            0x33 => izy!(RLA, b),
+            0x34 => zpx!(NOP, b),
+            0x35 => zpx!(AND, b),
+            0x36 => zpx!(ROL, b),
+            0x37 => zpx!(RLA, b),
+            0x38 => imp!(SEC, b),
+            0x39 => aby!(AND, b),
+            0x3A => imp!(NOP, b),
+            0x3B => aby!(RLA, b),
+            0x3C => abx!(NOP, b),
+            0x3D => abx!(AND, b),
+            0x3E => abx!(ROL, b),
+            0x3F => abx!(RLA, b),
+            0x40 => imp!(RTI, b),
+            0x41 => izx!(EOR, b),
+            0x42 => imp!(JAM, b),
+            0x43 => izx!(SRE, b),
+            0x44 => zpg!(NOP, b),
+            0x45 => zpg!(EOR, b),
+            0x46 => zpg!(LSR, b),
+            0x47 => zpg!(SRE, b),
+            0x48 => imp!(PHA, b),
+            0x49 => imm!(EOR, b),
+            0x4A => acc!(LSR, b),
+            0x4B => imm!(ALR, b),
+            0x4C => abs!(JMP, b),
+            0x4D => abs!(EOR, b),
+            0x4E => abs!(LSR, b),
+            0x4F => abs!(SRE, b),
+            0x50 => rel!(BVC, b),
+            0x51 => izy!(EOR, b),
+            0x52 => imp!(JAM, b),
+            0x53 => izy!(SRE, b),
+            0x54 => zpx!(NOP, b),
+            0x55 => zpx!(EOR, b),
+            0x56 => zpx!(LSR, b),
+            0x57 => zpx!(SRE, b),
+            0x58 => imp!(CLI, b),
+            0x59 => aby!(EOR, b),
+            0x5A => imp!(NOP, b),
+            0x5B => aby!(SRE, b),
+            0x5C => abx!(NOP, b),
+            0x5D => abx!(EOR, b),
+            0x5E => abx!(LSR, b),
+            0x5F => abx!(SRE, b),
+            0x60 => imp!(RTS, b),
+            0x61 => izx!(ADC, b),
+            0x62 => imp!(JAM, b),
+            0x63 => izx!(RRA, b),
+            0x64 => zpg!(NOP, b),
+            0x65 => zpg!(ADC, b),
+            0x66 => zpg!(ROR, b),
+            0x67 => zpg!(RRA, b),
+            0x68 => imp!(PLA, b),
+            0x69 => imm!(ADC, b),
+            0x6A => acc!(ROR, b),
+            0x6B => imm!(ARR, b),
+            0x6C => ind!(JMP, b),
+            0x6D => abs!(ADC, b),
+            0x6E => abs!(ROR, b),
+            0x6F => abs!(RRA, b),
+            0x70 => rel!(BVS, b),
+            0x71 => izy!(ADC, b),
+            0x72 => imp!(JAM, b),
+            0x73 => izy!(RRA, b),
+            0x74 => zpx!(NOP, b),
+            0x75 => zpx!(ADC, b),
+            0x76 => zpx!(ROR, b),
+            0x77 => zpx!(RRA, b),
+            0x78 => imp!(SEI, b),
+            0x79 => aby!(ADC, b),
+            0x7A => imp!(NOP, b),
+            0x7B => aby!(RRA, b),
+            0x7C => abx!(NOP, b),
+            0x7D => abx!(ADC, b),
+            0x7E => abx!(ROR, b),
+            0x7F => abx!(RRA, b),
+            0x80 => imm!(NOP, b),
+            0x81 => izx!(STA, b),
+            0x82 => imm!(NOP, b),
+            0x83 => izx!(SAX, b),
+            0x84 => zpg!(STY, b),
+            0x85 => zpg!(STA, b),
+            0x86 => zpg!(STX, b),
+            0x87 => zpg!(SAX, b),
+            0x88 => imp!(DEY, b),
+            0x89 => imm!(NOP, b),
+            0x8A => imp!(TXA, b),
+            0x8B => imm!(XAA, b),
+            0x8C => abs!(STY, b),
+            0x8D => abs!(STA, b),
+            0x8E => abs!(STX, b),
+            0x8F => abs!(SAX, b),
+            0x90 => rel!(BCC, b),
+            0x91 => izy!(STA, b),
+            0x92 => imp!(JAM, b),
+            0x93 => izy!(AHX, b),
+            0x94 => zpx!(STY, b),
+            0x95 => zpx!(STA, b),
+            0x96 => zpy!(STX, b),
+            0x97 => zpy!(SAX, b),
+            0x98 => imp!(TYA, b),
+            0x99 => aby!(STA, b),
+            0x9A => imp!(TXS, b),
+            0x9B => aby!(TAS, b),
+            0x9C => abx!(SHY, b),
+            0x9D => abx!(STA, b),
+            0x9E => aby!(SHX, b),
+            0x9F => aby!(AHX, b),
+            0xA0 => imm!(LDY, b),
+            0xA1 => izx!(LDA, b),
+            0xA2 => imm!(LDX, b),
+            0xA3 => izx!(LAX, b),
+            0xA4 => zpg!(LDY, b),
+            0xA5 => zpg!(LDA, b),
+            0xA6 => zpg!(LDX, b),
+            0xA7 => zpg!(LAX, b),
+            0xA8 => imp!(TAY, b),
+            0xA9 => imm!(LDA, b),
+            0xAA => imp!(TAX, b),
+            0xAB => imm!(LXA, b),
+            0xAC => abs!(LDY, b),
+            0xAD => abs!(LDA, b),
+            0xAE => abs!(LDX, b),
+            0xAF => abs!(LAX, b),
+            0xB0 => rel!(BCS, b),
+            0xB1 => izy!(LDA, b),
+            0xB2 => imp!(JAM, b),
+            0xB3 => izy!(LAX, b),
+            0xB4 => zpx!(LDY, b),
+            0xB5 => zpx!(LDA, b),
+            0xB6 => zpy!(LDX, b),
+            0xB7 => zpy!(LAX, b),
+            0xB8 => imp!(CLV, b),
+            0xB9 => aby!(LDA, b),
+            0xBA => imp!(TSX, b),
+            0xBB => aby!(LAS, b),
+            0xBC => abx!(LDY, b),
+            0xBD => abx!(LDA, b),
+            0xBE => aby!(LDX, b),
+            0xBF => aby!(LAX, b),
+            0xC0 => imm!(CPY, b),
+            0xC1 => izx!(CMP, b),
+            0xC2 => imm!(NOP, b),
+            0xC3 => izx!(DCP, b),
+            0xC4 => zpg!(CPY, b),
+            0xC5 => zpg!(CMP, b),
+            0xC6 => zpg!(DEC, b),
+            0xC7 => zpg!(DCP, b),
+            0xC8 => imp!(INY, b),
+            0xC9 => imm!(CMP, b),
+            0xCA => imp!(DEX, b),
+            0xCB => imm!(AXS, b),
+            0xCC => abs!(CPY, b),
+            0xCD => abs!(CMP, b),
+            0xCE => abs!(DEC, b),
+            0xCF => abs!(DCP, b),
+            0xD0 => rel!(BNE, b),
+            0xD1 => izy!(CMP, b),
+            0xD2 => imp!(JAM, b),
+            0xD3 => izy!(DCP, b),
+            0xD4 => zpx!(NOP, b),
+            0xD5 => zpx!(CMP, b),
+            0xD6 => zpx!(DEC, b),
+            0xD7 => zpx!(DCP, b),
+            0xD8 => imp!(CLD, b),
+            0xD9 => aby!(CMP, b),
+            0xDA => imp!(NOP, b),
+            0xDB => aby!(DCP, b),
+            0xDC => abx!(NOP, b),
+            0xDD => abx!(CMP, b),
+            0xDE => abx!(DEC, b),
+            0xDF => abx!(DCP, b),
+            0xE0 => imm!(CPX, b),
+            0xE1 => izx!(SBC, b),
+            0xE2 => imm!(NOP, b),
+            0xE3 => izx!(ISB, b),
+            0xE4 => zpg!(CPX, b),
+            0xE5 => zpg!(SBC, b),
+            0xE6 => zpg!(INC, b),
+            0xE7 => zpg!(ISB, b),
+            0xE8 => imp!(INX, b),
+            0xE9 => imm!(SBC, b),
+            0xEA => imp!(NOP, b),
+            0xEB => imm!(SBC, b),
+            0xEC => abs!(CPX, b),
+            0xED => abs!(SBC, b),
+            0xEE => abs!(INC, b),
+            0xEF => abs!(ISB, b),
+            0xF0 => rel!(BEQ, b),
+            0xF1 => izy!(SBC, b),
+            0xF2 => imp!(JAM, b),
+            0xF3 => izy!(ISB, b),
+            0xF4 => zpx!(NOP, b),
+            0xF5 => zpx!(SBC, b),
+            0xF6 => zpx!(INC, b),
+            0xF7 => zpx!(ISB, b),
+            0xF8 => imp!(SED, b),
+            0xF9 => aby!(SBC, b),
+            0xFA => imp!(NOP, b),
+            0xFB => aby!(ISB, b),
+            0xFC => abx!(NOP, b),
+            0xFD => abx!(SBC, b),
+            0xFE => abx!(INC, b),
+            0xFF => abx!(ISB, b),
+        }
+    }
+}
+
+impl Instruction {
+    /// Base cycle count, without the page crossing and taken branch penalties.
+    pub fn cycles(&self) -> u8 {
+        let Instruction(op, val) = *self;
+
+        let (rmw, store) = match op {
+            Opcode::ASL | Opcode::LSR | Opcode::ROL | Opcode::ROR | Opcode::INC | Opcode::DEC |
+            Opcode::SLO | Opcode::RLA | Opcode::SRE | Opcode::RRA | Opcode::DCP | Opcode::ISB => (true, false),
+            Opcode::STA | Opcode::STX | Opcode::STY |
+            Opcode::SAX | Opcode::AHX | Opcode::TAS | Opcode::SHX | Opcode::SHY => (false, true),
+            _ => (false, false),
+        };
+
+        match (op, val) {
+            (Opcode::BRK, _) => 7,
+            (Opcode::RTI, _) | (Opcode::RTS, _) | (Opcode::JSR, _) => 6,
+            (Opcode::PHA, _) | (Opcode::PHP, _) => 3,
+            (Opcode::PLA, _) | (Opcode::PLP, _) => 4,
+            (Opcode::JMP, Value::Absolute(_)) => 3,
+            (_, Value::Indirect(_)) => 5,
+            (_, Value::Implied) | (_, Value::Accumulator) | (_, Value::Immediate(_)) | (_, Value::Relative(_)) => 2,
+            (_, Value::ZeroPage(_)) => if rmw { 5 } else { 3 },
+            (_, Value::ZeroPageX(_)) | (_, Value::ZeroPageY(_)) => if rmw { 6 } else { 4 },
+            (_, Value::Absolute(_)) => if rmw { 6 } else { 4 },
+            (_, Value::AbsoluteX(_)) | (_, Value::AbsoluteY(_)) => if rmw { 7 } else if store { 5 } else { 4 },
+            (_, Value::PreIdxIndX(_)) => if rmw { 8 } else { 6 },
+            (_, Value::PreIdxIndY(_)) => if rmw { 8 } else if store { 6 } else { 5 },
         }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Implied => Ok(()),
+            Value::Accumulator => write!(f, "A"),
+            Value::Immediate(val) => write!(f, "#${:02X}", val),
+            Value::Absolute(addr) => write!(f, "${:04X}", addr),
+            Value::AbsoluteX(addr) => write!(f, "${:04X},X", addr),
+            Value::AbsoluteY(addr) => write!(f, "${:04X},Y", addr),
+            Value::Indirect(addr) => write!(f, "(${:04X})", addr),
+            Value::ZeroPage(zpg) => write!(f, "${:02X}", zpg),
+            Value::ZeroPageX(zpg) => write!(f, "${:02X},X", zpg),
+            Value::ZeroPageY(zpg) => write!(f, "${:02X},Y", zpg),
+            Value::PreIdxIndX(zpg) => write!(f, "(${:02X},X)", zpg),
+            Value::PreIdxIndY(zpg) => write!(f, "(${:02X}),Y", zpg),
+            // Relative to the start of the branch, which is two bytes long
+            Value::Relative(offs) => write!(f, "*{:+}", offs as i16 + 2),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction(op, Value::Implied) => write!(f, "{:?}", op),
+            Instruction(op, val) => write!(f, "{:?} {}", op, val),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use controller::Buttons;
+
+use minifb::Key;
+
+use std::fs::File;
+use std::io::prelude::*;
+
+/// The bindings used when no file is given.
+const DEFAULT: &'static str = include_str!("../keys.cfg");
+
+/// Every key a binding can name. They're matched by their Debug names so
+/// the config uses the same spelling as minifb.
+const KEYS: [Key; 104] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8,
+    Key::F9, Key::F10, Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket,
+    Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home,
+    Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause,
+    Key::Space, Key::Tab, Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4,
+    Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus,
+    Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt,
+];
+
+fn key_from_name(name: &str) -> Option<Key> {
+    KEYS.iter().cloned().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+/// Which keyboard keys press which buttons on the two controllers.
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    bindings: Vec<(Key, usize, Buttons)>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap::parse(DEFAULT).unwrap()
+    }
+}
+
+impl KeyMap {
+    pub fn load(path: &str) -> Result<KeyMap, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+        KeyMap::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Reads `<player>.<button> = <key>[, <key>...]` lines, skipping blank
+    /// ones and `#` comments.
+    pub fn parse(text: &str) -> Result<KeyMap, String> {
+        let mut bindings = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |what: &str| format!("line {}: {}", number + 1, what);
+
+            let mut sides = line.splitn(2, '=');
+            let target = sides.next().unwrap().trim();
+            let keys = sides.next().ok_or_else(|| error("expected <player>.<button> = <key>"))?;
+
+            let mut target = target.splitn(2, '.');
+            let player = match target.next().unwrap().trim() {
+                "1" => 0,
+                "2" => 1,
+                p => return Err(error(&format!("no player {}, only 1 and 2", p))),
+            };
+            let button = target.next().map(|b| b.trim()).unwrap_or("");
+            let button = Buttons::from_name(button)
+                .ok_or_else(|| error(&format!("unknown button {:?}", button)))?;
+
+            for key in keys.split(',').map(|k| k.trim()) {
+                let key = key_from_name(key).ok_or_else(|| error(&format!("unknown key {:?}", key)))?;
+                bindings.push((key, player, button));
+            }
+        }
+
+        Ok(KeyMap { bindings: bindings })
+    }
+
+    /// The buttons held on each controller, given which keys are down.
+    pub fn buttons<F: Fn(Key) -> bool>(&self, is_down: F) -> [Buttons; 2] {
+        let mut buttons = [Buttons::empty(); 2];
+        for &(key, player, button) in &self.bindings {
+            if is_down(key) {
+                buttons[player] |= button;
+            }
+        }
+        buttons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_bindings() {
+        let keys = KeyMap::default();
+        let buttons = keys.buttons(|key| key == Key::X || key == Key::Up || key == Key::W);
+        assert_eq!(buttons, [Buttons::A | Buttons::UP, Buttons::UP]);
+    }
+
+    #[test]
+    fn several_keys_per_button() {
+        let keys = KeyMap::parse("# turbo-less\n1.a = X, numpad0\n\n2.Start = enter # pause\n").unwrap();
+        assert_eq!(keys.buttons(|key| key == Key::NumPad0), [Buttons::A, Buttons::empty()]);
+        assert_eq!(keys.buttons(|key| key == Key::Enter), [Buttons::empty(), Buttons::START]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(KeyMap::parse("1.a X").unwrap_err(), "line 1: expected <player>.<button> = <key>");
+        assert_eq!(KeyMap::parse("\n3.a = X").unwrap_err(), "line 2: no player 3, only 1 and 2");
+        assert_eq!(KeyMap::parse("1.turbo = X").unwrap_err(), "line 1: unknown button \"turbo\"");
+        assert_eq!(KeyMap::parse("1.a = Kay").unwrap_err(), "line 1: unknown key \"Kay\"");
+    }
+}
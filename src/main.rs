@@ -1,4 +1,4 @@
-#![feature(box_syntax, box_patterns, inclusive_range_syntax)]
+#![feature(box_syntax, box_patterns, inclusive_range_syntax, try_from)]
 
 #[macro_use]
 extern crate clap;
@@ -10,8 +10,13 @@ extern crate bitflags;
 extern crate lazy_static;
 
 extern crate clock_ticks;
+#[cfg(feature = "cpal")]
+extern crate cpal;
 extern crate minifb;
+extern crate png;
 
+#[macro_use]
+mod state;
 mod ppuregs;
 mod cart;
 mod inst;
@@ -19,143 +24,590 @@ mod nes;
 mod mem;
 mod cpu;
 mod ppu;
-
+mod apu;
+mod audio;
+mod battery;
+mod controller;
+mod keymap;
+mod mapper;
+mod trace;
+mod testrom;
+mod disasm;
+mod headless;
+mod palette;
+
+use apu::DEFAULT_SAMPLE_RATE;
+use audio::{AudioSink, WavWriter};
+#[cfg(feature = "cpal")]
+use audio::LiveSink;
+use battery::BatterySave;
 use cart::NESCart;
+use cpu::Unstable;
+use disasm::Bank;
+use headless::InputScript;
+use testrom::Outcome;
+use keymap::KeyMap;
 use nes::NES;
+use ppu::{FRAME_WIDTH, FRAME_HEIGHT};
+use trace::{NestestCompare, NestestSink, Tracer};
 
+use std::convert::TryFrom;
 use std::io;
 use std::io::prelude::*;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
 use std::sync::{Arc, Mutex};
 
+use std::process;
 use std::thread;
 use std::time::Duration;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
 
-const palette: [u32; 64] = [
-     0x7C7C7Cu32 ,0x0000FCu32 ,0x0000BCu32 ,0x4428BCu32 ,0x940084u32 ,0xA80020u32 ,0xA81000u32 ,0x881400u32
-    ,0x503000u32 ,0x007800u32 ,0x006800u32 ,0x005800u32 ,0x004058u32 ,0x000000u32 ,0x000000u32 ,0x000000u32
-    ,0xBCBCBCu32 ,0x0078F8u32 ,0x0058F8u32 ,0x6844FCu32 ,0xD800CCu32 ,0xE40058u32 ,0xF83800u32 ,0xE45C10u32
-    ,0xAC7C00u32 ,0x00B800u32 ,0x00A800u32 ,0x00A844u32 ,0x008888u32 ,0x000000u32 ,0x000000u32 ,0x000000u32
-    ,0xF8F8F8u32 ,0x3CBCFCu32 ,0x6888FCu32 ,0x9878F8u32 ,0xF878F8u32 ,0xF85898u32 ,0xF87858u32 ,0xFCA044u32 
-    ,0xF8B800u32 ,0xB8F818u32 ,0x58D854u32 ,0x58F898u32 ,0x00E8D8u32 ,0x787878u32 ,0x000000u32 ,0x000000u32
-    ,0xFCFCFCu32 ,0xA4E4FCu32 ,0xB8B8F8u32 ,0xD8B8F8u32 ,0xF8B8F8u32 ,0xF8A4C0u32 ,0xF0D0B0u32 ,0xFCE0A8u32
-    ,0xF8D878u32 ,0xD8F878u32 ,0xB8F8B8u32 ,0xB8F8D8u32 ,0x00FCFCu32 ,0xF8D8F8u32 ,0x000000u32 ,0x000000u32
+use minifb::{Key, KeyRepeat, WindowOptions, Window, Scale};
+
+/// Pick the save state slot, like the number keys in most emulators.
+const SLOT_KEYS: [Key; 10] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
 ];
+const SAVE_STATE_KEY: Key = Key::F5;
+const LOAD_STATE_KEY: Key = Key::F7;
+
+/// How often battery RAM goes to disk while running, so a crash loses at
+/// most this much progress.
+const BATTERY_FLUSH_NS: u64 = 5_000_000_000;
+
+/// Sample rates `--rate` accepts, from telephone quality to the most a
+/// sound card will usually take.
+const MIN_SAMPLE_RATE: u32 = 8000;
+const MAX_SAMPLE_RATE: u32 = 192000;
+
+/// NTSC frames a second, 341 * 262 - 0.5 dots at 5.369318 MHz.
+const FRAME_RATE: f64 = 60.0988;
+
+/// Runs the machine on its own thread a frame at a time. A sink playing in
+/// real time sets the pace when there is one, and otherwise each frame
+/// waits for its turn on the clock.
+pub fn cpu_loop(nes: Arc<Mutex<NES>>, mut sinks: Vec<Box<dyn AudioSink>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let frame_ns = 1_000_000_000.0 / FRAME_RATE;
+        let mut start = clock_ticks::precise_time_ns();
+        let mut frames = 0;
 
-const WIDTH: usize = 355;
-const HEIGHT: usize = 240;
+        loop {
+            let samples = {
+                let mut nes = nes.lock().unwrap();
+                let frame = nes.mem.ppu.frames;
+                while nes.mem.ppu.frames == frame {
+                    if nes.step().is_err() {
+                        return;
+                    }
+                }
+                nes.mem.apu.take_samples()
+            };
+            frames += 1;
+
+            sinks = sinks.into_iter().filter_map(|mut sink| match sink.write(&samples) {
+                Ok(()) => Some(sink),
+                Err(e) => {
+                    println!("Audio error, dropping the sink: {}", e);
+                    None
+                }
+            }).collect();
+
+            // Keeping about 50ms queued
+            if let Some(live) = sinks.iter().find(|s| s.queued().is_some()) {
+                let target = live.sample_rate() as usize / 20;
+                while live.queued().unwrap() > target {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                continue;
+            }
 
-use minifb::{Key, WindowOptions, Window, Scale};
+            // Timing from the start keeps rounding from adding up. After
+            // falling well behind, like when the host is busy, it starts
+            // over instead of racing to catch up.
+            let due = start + (frames as f64 * frame_ns) as u64;
+            let now = clock_ticks::precise_time_ns();
+            if due > now {
+                thread::sleep(Duration::new(0, (due - now) as u32));
+            } else if now - due > 100_000_000 {
+                start = now;
+                frames = 0;
+            }
+        }
+    })
+}
 
-pub fn cpu_loop(rate: u64, nes: Arc<Mutex<NES>>) {
-    let thr = thread::spawn(move || {
-        let mut accumulator = 0;
-        let mut previous_clock = clock_ticks::precise_time_ns();
+/// Runs from the current PC and compares every instruction against a
+/// nestest.log, returning the process exit code.
+fn run_nestest(nes: &Arc<Mutex<NES>>, log_path: &str) -> i32 {
+    let mut log = String::new();
+    if let Err(e) = File::open(log_path).and_then(|mut f| f.read_to_string(&mut log)) {
+        println!("Couldn't read {}: {}", log_path, e);
+        return 1;
+    }
 
-        let rate = 1_000_000_000 / rate;
+    let compare = Arc::new(Mutex::new(NestestCompare::new(&log)));
+    let mut nes = nes.lock().unwrap();
+    nes.cpu.set_tracer(Some(Tracer::new(compare.clone())));
 
-        loop {
-            match nes.lock().unwrap().step() {
-                Result::Err(f) => break,
-                Result::Ok(f) => (),
-            };
+    while !compare.lock().unwrap().finished() {
+        if let Err(e) = nes.step() {
+            println!("CPU stopped: {}", e);
+            break;
+        }
 
-            let now = clock_ticks::precise_time_ns();
-            accumulator += now - previous_clock;
-            previous_clock = now;
+        if nes.cpu.halted() {
+            println!("CPU jammed");
+            break;
+        }
+    }
+
+    let compare = compare.lock().unwrap();
+    match compare.mismatch {
+        Some(ref m) => {
+            println!("First difference at line {}:", m.line);
+            println!("expected: {}", m.expected);
+            println!("  actual: {}", m.actual);
+            1
+        }
+        None if compare.finished() => {
+            println!("All {} lines match", compare.matched());
+            0
+        }
+        None => {
+            println!("Stopped after {} matching lines", compare.matched());
+            1
+        }
+    }
+}
+
+/// Reads and parses a ROM file, exiting with a message when it can't.
+fn read_cart(path: &str) -> Result<NESCart, String> {
+    let mut rom_raw = Vec::<u8>::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut rom_raw))
+        .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+
+    NESCart::try_from(rom_raw).map_err(|e| format!("Couldn't load {}: {}", path, e))
+}
+
+fn load_cart(path: &str) -> NESCart {
+    match read_cart(path) {
+        Ok(cart) => cart,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Save states live next to the ROM, as `game.state1` and so on.
+fn state_path(rom_path: &str, slot: usize) -> PathBuf {
+    Path::new(rom_path).with_extension(format!("state{}", slot))
+}
+
+fn save_slot(nes: &NES, rom_path: &str, slot: usize) {
+    let path = state_path(rom_path, slot);
+    match File::create(&path).and_then(|mut f| f.write_all(&nes.save_state())) {
+        Ok(()) => println!("Saved state {} to {}", slot, path.display()),
+        Err(e) => println!("Couldn't save {}: {}", path.display(), e),
+    }
+}
+
+fn load_slot(nes: &mut NES, rom_path: &str, slot: usize) {
+    let path = state_path(rom_path, slot);
+    let mut data = Vec::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut data)) {
+        println!("Couldn't read {}: {}", path.display(), e);
+        return;
+    }
+
+    match nes.load_state(&data) {
+        Ok(()) => println!("Loaded state {} from {}", slot, path.display()),
+        Err(e) => println!("Couldn't load {}: {}", path.display(), e),
+    }
+}
+
+fn flush_battery(battery: &mut BatterySave, nes: &NES) {
+    if let Err(e) = battery.flush(nes.mem.mapper.cart()) {
+        println!("Couldn't write {}: {}", battery.path().display(), e);
+    }
+}
 
-            while accumulator >= rate {
-                accumulator -= rate;
+/// Runs a ROM for a number of frames without a window, for CI. Exits
+/// with 0 when it ran and matched any expected hash, 1 when the hash
+/// differs and 2 when emulation itself failed.
+fn run_headless(matches: &clap::ArgMatches) -> i32 {
+    let rom_path = matches.value_of("INPUT").unwrap();
+    // No .sav here, so every run starts from the same place
+    let mut nes = match read_cart(rom_path).and_then(|cart| cart.into_mapper().map_err(|e| e.to_string())) {
+        Ok(mapper) => NES::new(mapper),
+        Err(e) => {
+            println!("{}", e);
+            return 2;
+        }
+    };
+    nes.reset();
+
+    let frames = matches.value_of("frames").unwrap();
+    let frames = match frames.parse::<u64>() {
+        Ok(frames) => frames,
+        Err(_) => {
+            println!("Bad frame count {:?}", frames);
+            return 2;
+        }
+    };
+    let script = match matches.value_of("input") {
+        Some(path) => {
+            let mut text = String::new();
+            let res = File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                .map_err(|e| e.to_string())
+                .and_then(|_| InputScript::parse(&text));
+            match res {
+                Ok(script) => script,
+                Err(e) => {
+                    println!("Couldn't load {}: {}", path, e);
+                    return 2;
+                }
+            }
+        }
+        None => InputScript::default(),
+    };
+
+    let mut sinks: Vec<Box<dyn AudioSink>> = Vec::new();
+    if let Some(path) = matches.value_of("wav") {
+        match WavWriter::create(path, nes.mem.apu.sample_rate()) {
+            Ok(wav) => sinks.push(Box::new(wav)),
+            Err(e) => {
+                println!("Couldn't create {}: {}", path, e);
+                return 2;
             }
+        }
+    }
+
+    if let Err(e) = headless::run(&mut nes, frames, &script, &mut sinks) {
+        println!("{}", e);
+        return 2;
+    }
+
+    let frame = &nes.mem.ppu.frame;
+    if let Some(path) = matches.value_of("png") {
+        if let Err(e) = headless::write_png(path, frame) {
+            println!("{}", e);
+            return 2;
+        }
+    }
 
-            thread::sleep(Duration::from_millis(((rate - accumulator) / 1000000) as u64));
+    let hash = format!("{:016x}", headless::frame_hash(frame));
+    println!("Frame {} hash: {}", nes.mem.ppu.frames, hash);
+
+    match matches.value_of("hash") {
+        Some(expected) if !expected.eq_ignore_ascii_case(&hash) => {
+            println!("Expected hash: {}", expected);
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// Runs a test ROM that reports through $6000, printing what it says.
+/// Exits with 0 when it passes, 1 when it fails and 2 when it couldn't
+/// finish.
+fn run_test(matches: &clap::ArgMatches) -> i32 {
+    let rom_path = matches.value_of("INPUT").unwrap();
+    let frames = match matches.value_of("frames").map(|f| f.parse::<u64>().map_err(|_| f)) {
+        Some(Ok(frames)) => frames,
+        Some(Err(f)) => {
+            println!("Bad frame count {:?}", f);
+            return 2;
+        }
+        None => testrom::DEFAULT_FRAMES,
+    };
+
+    match testrom::run_rom(Path::new(rom_path), frames) {
+        Ok(Outcome::Passed(message)) => {
+            println!("{}\nPassed", message);
+            0
+        }
+        Ok(Outcome::Failed(code, message)) => {
+            println!("{}\nFailed with code {}", message, code);
+            1
+        }
+        Err(e) => {
+            println!("{}", e);
+            2
+        }
+    }
+}
+
+/// Prints one 16K PRG-ROM bank. The last bank is assumed to sit at $C000
+/// and the others at $8000 unless an address is given.
+fn run_disasm(cart: &NESCart, bank: Option<&str>, addr: Option<&str>) -> i32 {
+    let banks: Vec<&[u8]> = cart.prg_rom.chunks(0x4000).collect();
+    let index = match bank.map(|b| b.parse::<usize>().map_err(|_| b)) {
+        Some(Ok(index)) => index,
+        Some(Err(b)) => {
+            println!("Bad bank number {:?}", b);
+            return 1;
         }
-    });
+        None => banks.len() - 1,
+    };
+
+    if index >= banks.len() {
+        println!("No PRG-ROM bank {}, the cart has {}", index, banks.len());
+        return 1;
+    }
+
+    let base = match addr {
+        Some(addr) => match u16::from_str_radix(addr, 16) {
+            Ok(base) => base,
+            Err(_) => {
+                println!("Bad address {:?}, expected hex like C000", addr);
+                return 1;
+            }
+        },
+        None if index == banks.len() - 1 => 0xC000,
+        None => 0x8000,
+    };
+
+    print!("{}", Bank::new(banks[index], base).disassemble());
+    0
 }
 
 fn main() {
-    let matches = clap_app!(snes_emu =>
+    let app = clap_app!(snes_emu =>
         (version: VERSION)
         (author: AUTHORS)
         (about: "Rustic NES Emulator")
+        (@setting SubcommandsNegateReqs)
         (@arg INPUT: +required "ROM file to load")
         (@arg pc: -p +takes_value "Set PC execution start")
         (@arg sp: -s +takes_value "Set SP execution start")
-    ).get_matches();
+        (@arg unstable: -u +takes_value "Emulate unstable opcodes with the given XAA/LXA magic constant")
+        (@arg trace: -t "Print a nestest.log style trace of every instruction")
+        (@arg nestest: --nestest +takes_value "Compare the trace against a nestest.log, starting at $C000 unless -p is given")
+        (@arg wav: --wav +takes_value "Record the sound to a WAV file")
+        (@arg rate: --rate +takes_value "Sample rate when not playing live, 44100 by default")
+        (@arg keys: --keys +takes_value "Keyboard bindings file, see keys.cfg for the format")
+        (@subcommand disasm =>
+            (about: "Disassemble a PRG-ROM bank")
+            (@arg INPUT: +required "ROM file to load")
+            (@arg bank: -b +takes_value "16K bank to disassemble, the last one by default")
+            (@arg addr: -a +takes_value "Address the bank is mapped at")
+        )
+        (@subcommand headless =>
+            (about: "Run a ROM without a window, as fast as possible, then hash the last frame")
+            (@arg INPUT: +required "ROM file to load")
+            (@arg frames: -f --frames +takes_value +required "Frames to run")
+            (@arg input: -i --input +takes_value "Input script: lines of <frame> [button...]")
+            (@arg png: --png +takes_value "Save the last frame as a PNG")
+            (@arg wav: --wav +takes_value "Record the sound to a WAV file")
+            (@arg hash: --hash +takes_value "Fail unless the last frame hashes to this")
+        )
+        (@subcommand test =>
+            (about: "Run a blargg-style test ROM, which reports its result at $6000")
+            (@arg INPUT: +required "ROM file to load")
+            (@arg frames: -f --frames +takes_value "Frames to wait for a result, 7200 by default")
+        )
+    );
+
+    // Live sound needs the system's sound library, so it's optional
+    #[cfg(feature = "cpal")]
+    let app = app.arg(clap::Arg::from_usage("--audio 'Play sound, letting the sound card set the pace'"));
+
+    let matches = app.get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        let cart = load_cart(matches.value_of("INPUT").unwrap());
+        process::exit(run_disasm(&cart, matches.value_of("bank"), matches.value_of("addr")));
+    }
 
-    let rom_path = matches.value_of("INPUT").unwrap();
-    println!("Opening ROM: {}", rom_path);
+    if let Some(matches) = matches.subcommand_matches("headless") {
+        process::exit(run_headless(matches));
+    }
 
-    let mut rom_raw = Vec::<u8>::new();
+    if let Some(matches) = matches.subcommand_matches("test") {
+        process::exit(run_test(matches));
+    }
 
-    File::open(rom_path).and_then(|mut f| f.read_to_end(&mut rom_raw)).unwrap();
+    let rom_path = matches.value_of("INPUT").unwrap();
+    println!("Opening ROM: {}", rom_path);
 
-    let cart = NESCart::from(rom_raw);
+    let mut cart = load_cart(rom_path);
+    let mut battery = match cart.header.battery {
+        true => match BatterySave::open(rom_path, &mut cart) {
+            Ok(battery) => Some(battery),
+            Err(e) => {
+                // Carrying on would overwrite it
+                println!("Couldn't read the save for {}: {}", rom_path, e);
+                process::exit(1);
+            }
+        },
+        false => None,
+    };
+
+    let mapper = match cart.into_mapper() {
+        Ok(mapper) => mapper,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    let nes = &mut Arc::new(Mutex::new(NES::new(Arc::new(RwLock::new(cart)))));
+    let nes = &mut Arc::new(Mutex::new(NES::new(mapper)));
     let ness = &mut nes.clone();
     ness.lock().unwrap().reset();
 
-    if matches.is_present("pc") {
-        let ness = nes.lock().unwrap();
-        let mut cpu = ness.cpu.lock().unwrap();
-        cpu.set_pc(u16::from_str_radix(matches.value_of("pc").unwrap(), 16).unwrap());
+    let pc = match matches.value_of("pc") {
+        None if matches.is_present("nestest") => Some("C000"),
+        pc => pc,
+    };
+
+    if let Some(pc) = pc {
+        let mut ness = nes.lock().unwrap();
+        let cpu = &mut ness.cpu;
+        cpu.set_pc(u16::from_str_radix(pc, 16).unwrap());
     }
 
     if matches.is_present("sp") {
-        let ness = nes.lock().unwrap();
-        let mut cpu = ness.cpu.lock().unwrap();
+        let mut ness = nes.lock().unwrap();
+        let cpu = &mut ness.cpu;
         cpu.set_sp(u8::from_str_radix(matches.value_of("sp").unwrap(), 16).unwrap());
     }
 
+    if let Some(magic) = matches.value_of("unstable") {
+        let magic = match u8::from_str_radix(magic, 16) {
+            Ok(magic) => magic,
+            Err(_) => {
+                println!("Bad magic constant {:?}, expected a hex byte like EE", magic);
+                process::exit(1);
+            }
+        };
+        nes.lock().unwrap().cpu.unstable = Unstable::Emulate { magic: magic };
+    }
+
+    if matches.is_present("trace") {
+        let mut ness = nes.lock().unwrap();
+        let cpu = &mut ness.cpu;
+        cpu.set_tracer(Some(Tracer::new(Arc::new(Mutex::new(NestestSink::new(io::stdout()))))));
+    }
+
+    if let Some(log_path) = matches.value_of("nestest") {
+        process::exit(run_nestest(nes, log_path));
+    }
+
+    let keys = match matches.value_of("keys") {
+        Some(path) => KeyMap::load(path).unwrap_or_else(|e| {
+            println!("{}", e);
+            process::exit(1);
+        }),
+        None => KeyMap::default(),
+    };
+
+    let mut sinks: Vec<Box<dyn AudioSink>> = Vec::new();
+    let sample_rate = match matches.value_of("rate") {
+        Some(rate) => match rate.parse::<u32>() {
+            Ok(rate) if rate >= MIN_SAMPLE_RATE && rate <= MAX_SAMPLE_RATE => rate,
+            _ => {
+                println!("Bad sample rate {:?}, expected {} to {}", rate, MIN_SAMPLE_RATE, MAX_SAMPLE_RATE);
+                process::exit(1);
+            }
+        },
+        None => DEFAULT_SAMPLE_RATE,
+    };
+
+    // The stream has to outlive the window loop or the sound stops
+    #[cfg(feature = "cpal")]
+    let (_stream, sample_rate) = if matches.is_present("audio") {
+        match LiveSink::open() {
+            Ok((sink, stream)) => {
+                let rate = sink.sample_rate();
+                sinks.push(Box::new(sink));
+                (Some(stream), rate)
+            }
+            Err(e) => {
+                println!("Couldn't open the sound card: {}", e);
+                (None, sample_rate)
+            }
+        }
+    } else {
+        (None, sample_rate)
+    };
+
+    if let Some(path) = matches.value_of("wav") {
+        match WavWriter::create(path, sample_rate) {
+            Ok(wav) => sinks.push(Box::new(wav)),
+            Err(e) => {
+                println!("Couldn't create {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    nes.lock().unwrap().mem.apu.set_sample_rate(sample_rate);
+
     let nes_arc = nes.clone();
-    cpu_loop (1000, nes_arc);
+    let cpu_thread = cpu_loop(nes_arc, sinks);
 
-    let mut window = Window::new("NES Emulator", WIDTH as usize,
-                                HEIGHT as usize,
+    let mut window = Window::new("NES Emulator", FRAME_WIDTH,
+                                FRAME_HEIGHT,
                                 WindowOptions {
-                                    scale: Scale::X4,
+                                    scale: Scale::X2,
                                     ..Default::default()
                                 }).unwrap_or_else(|e| {
                                      panic!("{}", e);
                                 });
 
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-
-    let mut sprite_bytes: [u8; 256*16] = [0u8; 256*16];
-    let ness = nes.lock().unwrap();
-    let cart = ness.cart.lock().unwrap();
-        panic!("wtf");
-    sprite_bytes.copy_from_slice(&cart.chr_rom[0..256*16]);
+    let mut buffer: Vec<u32> = vec![0; FRAME_WIDTH * FRAME_HEIGHT];
+    let mut slot = 1;
+    let mut last_flush = clock_ticks::precise_time_ns();
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        let nesl = nes.lock();
-        let nesu = nesl.unwrap();
-        let ppul = nesu.ppu.lock();
-        let ppuu = ppul.unwrap();
-        let vram = ppuu.vram;
-        panic!("wtf");
-        for y in 0..8usize {
-            for x in 0..8usize {
-                let ind: usize = vram[0x21ca] as usize * 16;
-                let b1 = ((((sprite_bytes[ind + (y % 8) + 0] as u8) >> (7 - (x % 8))) & 0b1) << 0) as u8;
-                let b2 = ((((sprite_bytes[ind + (y % 8) + 8] as u8) >> (7 - (x % 8))) & 0b1) << 1) as u8;
-                let col = b1 | b2;
-                buffer[x as usize + y as usize * WIDTH] = palette[col as usize + 32];
+        for (n, &key) in SLOT_KEYS.iter().enumerate() {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                slot = n;
+                println!("Save state slot {}", slot);
+            }
+        }
+        if window.is_key_pressed(SAVE_STATE_KEY, KeyRepeat::No) {
+            save_slot(&nes.lock().unwrap(), rom_path, slot);
+        }
+        if window.is_key_pressed(LOAD_STATE_KEY, KeyRepeat::No) {
+            load_slot(&mut nes.lock().unwrap(), rom_path, slot);
+        }
+
+        if let Some(ref mut battery) = battery {
+            let now = clock_ticks::precise_time_ns();
+            if now - last_flush >= BATTERY_FLUSH_NS {
+                last_flush = now;
+                flush_battery(battery, &nes.lock().unwrap());
             }
         }
 
+        let buttons = keys.buttons(|key| window.is_key_down(key));
+        {
+            let mut nes = nes.lock().unwrap();
+            nes.mem.controllers[0].buttons = buttons[0];
+            nes.mem.controllers[1].buttons = buttons[1];
+            buffer.copy_from_slice(&nes.mem.ppu.frame);
+        }
         window.update_with_buffer(&buffer);
     }
 
     nes.lock().unwrap().kill = true;
+    // Let the sinks drop so the recording gets finished
+    cpu_thread.join().unwrap();
+
+    if let Some(ref mut battery) = battery {
+        flush_battery(battery, &nes.lock().unwrap());
+    }
 
     println!("{:?}", &nes.lock().unwrap().cpu);
 }
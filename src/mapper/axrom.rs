@@ -0,0 +1,58 @@
+use cart::NESCart;
+use mapper::{banked, write_chr, Mapper, Mirroring};
+
+/// Mapper 7: one switchable 32K PRG bank, CHR-RAM, and single-screen
+/// mirroring picked by the same register.
+pub struct AxROM {
+    cart: NESCart,
+    bank: usize,
+    mirroring: Mirroring,
+}
+
+impl AxROM {
+    pub fn new(cart: NESCart) -> Self {
+        AxROM {
+            cart: cart,
+            bank: 0,
+            mirroring: Mirroring::SingleLower,
+        }
+    }
+}
+
+snapshot!(AxROM { cart, bank, mirroring });
+
+impl Mapper for AxROM {
+    fn cart(&self) -> &NESCart {
+        &self.cart
+    }
+
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000...0xFFFF => Some(self.cart.prg_rom[banked(&self.cart.prg_rom, 0x8000, self.bank, addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if let 0x8000...0xFFFF = addr {
+            self.bank = (val & 0b111) as usize;
+            self.mirroring = match val & 0x10 {
+                0 => Mirroring::SingleLower,
+                _ => Mirroring::SingleUpper,
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.cart.chr_rom[banked(&self.cart.chr_rom, 0x2000, 0, addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let i = banked(&self.cart.chr_rom, 0x2000, 0, addr);
+        write_chr(&mut self.cart, i, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
@@ -0,0 +1,56 @@
+use cart::NESCart;
+use mapper::{banked, read_prg_ram, write_prg_ram, write_chr, Mapper, Mirroring};
+
+/// Mapper 3: fixed PRG-ROM and a switchable 8K CHR bank.
+pub struct CNROM {
+    cart: NESCart,
+    mirroring: Mirroring,
+    chr_bank: usize,
+}
+
+impl CNROM {
+    pub fn new(cart: NESCart) -> Self {
+        CNROM {
+            mirroring: cart.header.mirroring(),
+            cart: cart,
+            chr_bank: 0,
+        }
+    }
+}
+
+snapshot!(CNROM { cart, mirroring, chr_bank });
+
+impl Mapper for CNROM {
+    fn cart(&self) -> &NESCart {
+        &self.cart
+    }
+
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000...0x7FFF => Some(read_prg_ram(&self.cart, addr)),
+            0x8000...0xFFFF => Some(self.cart.prg_rom[banked(&self.cart.prg_rom, 0x8000, 0, addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF => write_prg_ram(&mut self.cart, addr, val),
+            0x8000...0xFFFF => self.chr_bank = val as usize,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.cart.chr_rom[banked(&self.cart.chr_rom, 0x2000, self.chr_bank, addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let i = banked(&self.cart.chr_rom, 0x2000, self.chr_bank, addr);
+        write_chr(&mut self.cart, i, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
@@ -0,0 +1,129 @@
+use cart::NESCart;
+use mapper::{banked, read_prg_ram, write_prg_ram, write_chr, Mapper, Mirroring};
+
+/// Mapper 1: registers are loaded one bit at a time through a serial
+/// port, and select 16K or 32K PRG banks and 4K or 8K CHR banks.
+pub struct MMC1 {
+    cart: NESCart,
+    shift: u8,
+    count: u8,
+    control: u8,
+    chr0: u8,
+    chr1: u8,
+    prg: u8,
+}
+
+impl MMC1 {
+    pub fn new(cart: NESCart) -> Self {
+        MMC1 {
+            cart: cart,
+            shift: 0,
+            count: 0,
+            // Powers up with the last bank fixed at $C000
+            control: 0x0C,
+            chr0: 0,
+            chr1: 0,
+            prg: 0,
+        }
+    }
+
+    /// 16K PRG bank mapped at `addr`.
+    fn prg_bank(&self, addr: u16) -> usize {
+        // SUROM and friends use a CHR line to pick a 256K half
+        let outer = match self.cart.prg_rom.len() > 0x40000 {
+            true => (self.chr0 & 0x10) as usize,
+            false => 0,
+        };
+
+        let bank = (self.prg & 0x0F) as usize;
+        let bank = match ((self.control >> 2) & 0b11, addr) {
+            (0, 0x8000...0xBFFF) | (1, 0x8000...0xBFFF) => bank & !1,
+            (0, _) | (1, _) => bank | 1,
+            (2, 0x8000...0xBFFF) => 0,
+            (2, _) => bank,
+            (_, 0x8000...0xBFFF) => bank,
+            (_, _) => 0x0F,
+        };
+
+        outer | bank
+    }
+
+    /// 4K CHR bank mapped at `addr`.
+    fn chr_bank(&self, addr: u16) -> usize {
+        match (self.control & 0x10, addr) {
+            (0, _) => ((self.chr0 & 0x1E) | (addr >> 12) as u8) as usize,
+            (_, 0x0000...0x0FFF) => self.chr0 as usize,
+            (_, _) => self.chr1 as usize,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg & 0x10 == 0
+    }
+}
+
+snapshot!(MMC1 { cart, shift, count, control, chr0, chr1, prg });
+
+impl Mapper for MMC1 {
+    fn cart(&self) -> &NESCart {
+        &self.cart
+    }
+
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled() => Some(read_prg_ram(&self.cart, addr)),
+            0x8000...0xFFFF => {
+                let prg = &self.cart.prg_rom;
+                Some(prg[banked(prg, 0x4000, self.prg_bank(addr), addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled() => write_prg_ram(&mut self.cart, addr, val),
+            0x8000...0xFFFF if val & 0x80 == 0x80 => {
+                self.shift = 0;
+                self.count = 0;
+                self.control |= 0x0C;
+            }
+            0x8000...0xFFFF => {
+                self.shift |= (val & 1) << self.count;
+                self.count += 1;
+
+                if self.count == 5 {
+                    let reg = self.shift;
+                    match (addr >> 13) & 0b11 {
+                        0 => self.control = reg,
+                        1 => self.chr0 = reg,
+                        2 => self.chr1 = reg,
+                        _ => self.prg = reg,
+                    }
+
+                    self.shift = 0;
+                    self.count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.cart.chr_rom[banked(&self.cart.chr_rom, 0x1000, self.chr_bank(addr), addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let i = banked(&self.cart.chr_rom, 0x1000, self.chr_bank(addr), addr);
+        write_chr(&mut self.cart, i, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleLower,
+            1 => Mirroring::SingleUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+}
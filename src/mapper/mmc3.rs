@@ -0,0 +1,193 @@
+use cart::NESCart;
+use mapper::{banked, banks, read_prg_ram, write_prg_ram, write_chr, Mapper, Mirroring};
+
+/// PPU dots A12 has to stay low before a rise counts as a new scanline.
+/// The sprite fetches toggle it every few dots, which the real chip
+/// filters out by waiting for a few CPU cycles.
+const A12_FILTER: u64 = 10;
+
+/// Mapper 4: 8K PRG banks, 1K and 2K CHR banks, and a scanline counter
+/// clocked by rising edges on the PPU's A12 line.
+pub struct MMC3 {
+    cart: NESCart,
+    four_screen: bool,
+
+    select: u8,
+    regs: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    prg_ram_protected: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    a12: bool,
+    a12_low_since: u64,
+}
+
+impl MMC3 {
+    pub fn new(cart: NESCart) -> Self {
+        MMC3 {
+            four_screen: cart.header.mirroring() == Mirroring::FourScreen,
+            cart: cart,
+
+            select: 0,
+            regs: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: Mirroring::Vertical,
+            prg_ram_enabled: true,
+            prg_ram_protected: false,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+
+            a12: false,
+            a12_low_since: 0,
+        }
+    }
+
+    /// 8K PRG bank mapped at `addr`.
+    fn prg_bank(&self, addr: u16) -> usize {
+        // With a single bank, the second-to-last wraps around to it too
+        let last = banks(&self.cart.prg_rom, 0x2000) - 1;
+        let second_last = last.saturating_sub(1);
+        let swap = self.select & 0x40 == 0x40;
+
+        match (addr >> 13) & 0b11 {
+            0 if swap => second_last,
+            0 => self.regs[6] as usize,
+            1 => self.regs[7] as usize,
+            2 if swap => self.regs[6] as usize,
+            2 => second_last,
+            _ => last,
+        }
+    }
+
+    /// 1K CHR bank mapped at `addr`.
+    fn chr_bank(&self, addr: u16) -> usize {
+        // A12 inversion swaps the 2K and 1K halves
+        let addr = match self.select & 0x80 {
+            0 => addr,
+            _ => addr ^ 0x1000,
+        };
+
+        let bank = match (addr >> 10) & 0b111 {
+            0 => self.regs[0] & !1,
+            1 => self.regs[0] | 1,
+            2 => self.regs[1] & !1,
+            3 => self.regs[1] | 1,
+            n => self.regs[n as usize - 2],
+        };
+
+        bank as usize
+    }
+
+    fn clock_irq(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+snapshot!(MMC3 {
+    cart, four_screen, select, regs, mirroring, prg_ram_enabled, prg_ram_protected,
+    irq_latch, irq_counter, irq_reload, irq_enabled, irq_pending, a12, a12_low_since,
+});
+
+impl Mapper for MMC3 {
+    fn cart(&self) -> &NESCart {
+        &self.cart
+    }
+
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled => Some(read_prg_ram(&self.cart, addr)),
+            0x8000...0xFFFF => {
+                let prg = &self.cart.prg_rom;
+                Some(prg[banked(prg, 0x2000, self.prg_bank(addr), addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match (addr & 0xE001, addr) {
+            (_, 0x6000...0x7FFF) => {
+                if self.prg_ram_enabled && !self.prg_ram_protected {
+                    write_prg_ram(&mut self.cart, addr, val);
+                }
+            }
+            (0x8000, _) => self.select = val,
+            (0x8001, _) => self.regs[(self.select & 0b111) as usize] = val,
+            (0xA000, _) => {
+                self.mirroring = match val & 1 {
+                    0 => Mirroring::Vertical,
+                    _ => Mirroring::Horizontal,
+                };
+            }
+            (0xA001, _) => {
+                self.prg_ram_enabled = val & 0x80 == 0x80;
+                self.prg_ram_protected = val & 0x40 == 0x40;
+            }
+            (0xC000, _) => self.irq_latch = val,
+            (0xC001, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000, _) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xE001, _) => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.cart.chr_rom[banked(&self.cart.chr_rom, 0x400, self.chr_bank(addr), addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let i = banked(&self.cart.chr_rom, 0x400, self.chr_bank(addr), addr);
+        write_chr(&mut self.cart, i, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.four_screen {
+            true => Mirroring::FourScreen,
+            false => self.mirroring,
+        }
+    }
+
+    fn ppu_address(&mut self, addr: u16, dot: u64) {
+        let a12 = addr & 0x1000 == 0x1000;
+
+        match (self.a12, a12) {
+            (false, true) => {
+                if dot.wrapping_sub(self.a12_low_since) >= A12_FILTER {
+                    self.clock_irq();
+                }
+            }
+            (true, false) => self.a12_low_since = dot,
+            _ => {}
+        }
+
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+}
@@ -0,0 +1,390 @@
+mod nrom;
+mod mmc1;
+mod uxrom;
+mod cnrom;
+mod mmc3;
+mod axrom;
+
+pub use self::nrom::NROM;
+pub use self::mmc1::MMC1;
+pub use self::uxrom::UxROM;
+pub use self::cnrom::CNROM;
+pub use self::mmc3::MMC3;
+pub use self::axrom::AxROM;
+
+use cart::NESCart;
+use state::{Snapshot, StateError};
+
+/// How the four logical nametables map onto the console's 2K of CIRAM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    /// Every nametable shows the first 1K
+    SingleLower,
+    /// Every nametable shows the second 1K
+    SingleUpper,
+    /// The cartridge supplies another 2K, so nothing is mirrored
+    FourScreen,
+}
+
+impl Mirroring {
+    /// Offset into the 4K of nametable RAM that `addr` in $2000-$3EFF
+    /// lands on. Only four-screen carts reach past the console's own 2K.
+    pub fn nametable_offset(&self, addr: u16) -> usize {
+        let table = (addr >> 10) & 0b11;
+        let page = match *self {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 1,
+            Mirroring::SingleLower => 0,
+            Mirroring::SingleUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+
+        page as usize * 0x400 + (addr & 0x3FF) as usize
+    }
+}
+
+impl Snapshot for Mirroring {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        let mut val = 0u8;
+        val.load(input)?;
+        *self = match val {
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::SingleLower,
+            3 => Mirroring::SingleUpper,
+            4 => Mirroring::FourScreen,
+            _ => return Err(StateError::Corrupt("bad mirroring")),
+        };
+        Ok(())
+    }
+}
+
+/// Cartridge hardware, seen from both the CPU and the PPU bus. Its
+/// `Snapshot` covers the bank registers along with the board's RAM.
+pub trait Mapper: Send + Snapshot {
+    fn cart(&self) -> &NESCart;
+
+    /// Reads $4020-$FFFF. `None` leaves the CPU data bus floating.
+    fn cpu_read(&self, addr: u16) -> Option<u8>;
+    fn cpu_write(&mut self, addr: u16, val: u8);
+
+    /// Reads the pattern tables at $0000-$1FFF.
+    fn ppu_read(&mut self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, val: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// Sees every address the PPU puts on its bus, along with the PPU's
+    /// dot count, for mappers that watch the address lines.
+    fn ppu_address(&mut self, _addr: u16, _dot: u64) {}
+
+    /// Whether the mapper is pulling the CPU's IRQ line low.
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+impl Snapshot for Box<dyn Mapper> {
+    fn save(&self, out: &mut Vec<u8>) {
+        (**self).save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        (**self).load(input)
+    }
+}
+
+/// How many banks of `size` bytes `data` covers, counting a partial one.
+/// NES 2.0 sizes needn't be whole banks.
+fn banks(data: &[u8], size: usize) -> usize {
+    (data.len() + size - 1) / size
+}
+
+/// Index into `data` for `addr` within bank `bank` of `size` bytes. Bank
+/// numbers wrap around the available banks, like the unconnected high bits
+/// on a real board.
+fn banked(data: &[u8], size: usize, bank: usize, addr: u16) -> usize {
+    ((bank % banks(data, size)) * size + (addr as usize % size)) % data.len()
+}
+
+fn read_prg_ram(cart: &NESCart, addr: u16) -> u8 {
+    cart.prg_ram[(addr as usize - 0x6000) % cart.prg_ram.len()]
+}
+
+fn write_prg_ram(cart: &mut NESCart, addr: u16, val: u8) {
+    let len = cart.prg_ram.len();
+    cart.prg_ram[(addr as usize - 0x6000) % len] = val;
+}
+
+/// Writes to CHR only land on boards with CHR-RAM.
+fn write_chr(cart: &mut NESCart, index: usize, val: u8) {
+    if cart.chr_ram() {
+        cart.chr_rom[index] = val;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::test_rom;
+    use std::convert::TryFrom;
+
+    /// A cart whose every byte holds the number of the 4K bank it's in.
+    fn cart(mapper: u8, prg_16k: u8, chr_8k: u8) -> NESCart {
+        let banks = |count: usize| (0..count).flat_map(|bank| vec![bank as u8; 0x1000]).collect::<Vec<u8>>();
+        NESCart::try_from(test_rom(mapper, 0, &banks(prg_16k as usize * 4), &banks(chr_8k as usize * 2))).unwrap()
+    }
+
+    fn mmc1_write(mapper: &mut Box<dyn Mapper>, addr: u16, val: u8) {
+        for i in 0..5 {
+            mapper.cpu_write(addr, (val >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn nrom_128_is_mirrored() {
+        let mapper = cart(0, 1, 1).into_mapper().unwrap();
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xF000), Some(3));
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn nrom_chr_ram() {
+        let mut mapper = cart(0, 2, 0).into_mapper().unwrap();
+        mapper.ppu_write(0x1234, 0x5A);
+        assert_eq!(mapper.ppu_read(0x1234), 0x5A);
+
+        let mut mapper = cart(0, 2, 1).into_mapper().unwrap();
+        mapper.ppu_write(0x1234, 0x5A);
+        assert_eq!(mapper.ppu_read(0x1234), 1);
+    }
+
+    #[test]
+    fn mmc1_prg_modes() {
+        let mut mapper = cart(1, 8, 2).into_mapper().unwrap();
+
+        // Powers up with the last bank fixed at $C000
+        assert_eq!(mapper.cpu_read(0xC000), Some(28));
+
+        mmc1_write(&mut mapper, 0xE000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), Some(12));
+        assert_eq!(mapper.cpu_read(0xC000), Some(28));
+
+        // First bank fixed at $8000
+        mmc1_write(&mut mapper, 0x8000, 0b01000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xC000), Some(12));
+
+        // 32K switching ignores the low bit
+        mmc1_write(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(8));
+        assert_eq!(mapper.cpu_read(0xC000), Some(12));
+    }
+
+    #[test]
+    fn mmc1_chr_and_mirroring() {
+        let mut mapper = cart(1, 2, 4).into_mapper().unwrap();
+
+        mmc1_write(&mut mapper, 0x8000, 0b10010);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        mmc1_write(&mut mapper, 0xA000, 5);
+        mmc1_write(&mut mapper, 0xC000, 2);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        assert_eq!(mapper.ppu_read(0x1000), 2);
+
+        mmc1_write(&mut mapper, 0x8000, 0b00001);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleUpper);
+        assert_eq!(mapper.ppu_read(0x0000), 4);
+        assert_eq!(mapper.ppu_read(0x1000), 5);
+    }
+
+    #[test]
+    fn mmc1_reset_bit() {
+        let mut mapper = cart(1, 8, 2).into_mapper().unwrap();
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 0x80);
+        mmc1_write(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(8));
+    }
+
+    #[test]
+    fn uxrom_switches_low_bank() {
+        let mut mapper = cart(2, 8, 0).into_mapper().unwrap();
+        mapper.cpu_write(0x8000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(20));
+        assert_eq!(mapper.cpu_read(0xC000), Some(28));
+    }
+
+    #[test]
+    fn bank_registers_are_saved() {
+        let mut mapper = cart(1, 8, 2).into_mapper().unwrap();
+        mmc1_write(&mut mapper, 0xE000, 3);
+        mapper.cpu_write(0x6000, 0x42);
+        let mut state = Vec::new();
+        mapper.save(&mut state);
+
+        mmc1_write(&mut mapper, 0xE000, 5);
+        mapper.cpu_write(0x6000, 0x00);
+        mapper.load(&mut &state[..]).unwrap();
+        assert_eq!(mapper.cpu_read(0x8000), Some(12));
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+
+        // So does a serial write in progress
+        mapper.cpu_write(0xE000, 1);
+        let mut state = Vec::new();
+        mapper.save(&mut state);
+        mapper.cpu_write(0xE000, 0x80);
+        mapper.load(&mut &state[..]).unwrap();
+        for i in 1..5 {
+            mapper.cpu_write(0xE000, (7 >> i) & 1);
+        }
+        assert_eq!(mapper.cpu_read(0x8000), Some(7 * 4));
+    }
+
+    #[test]
+    fn cnrom_switches_chr() {
+        let mut mapper = cart(3, 2, 4).into_mapper().unwrap();
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), 6);
+        assert_eq!(mapper.ppu_read(0x1000), 7);
+    }
+
+    #[test]
+    fn axrom_switches_32k_and_nametable() {
+        let mut mapper = cart(7, 8, 0).into_mapper().unwrap();
+        assert_eq!(mapper.mirroring(), Mirroring::SingleLower);
+        mapper.cpu_write(0x8000, 0x12);
+        assert_eq!(mapper.cpu_read(0x8000), Some(16));
+        assert_eq!(mapper.mirroring(), Mirroring::SingleUpper);
+    }
+
+    #[test]
+    fn nametable_mirroring() {
+        let tables = |mirroring: Mirroring| -> Vec<usize> {
+            [0x2005u16, 0x2405, 0x2805, 0x2C05].iter().map(|&addr| mirroring.nametable_offset(addr)).collect()
+        };
+
+        assert_eq!(tables(Mirroring::Horizontal), vec![0x005, 0x005, 0x405, 0x405]);
+        assert_eq!(tables(Mirroring::Vertical), vec![0x005, 0x405, 0x005, 0x405]);
+        assert_eq!(tables(Mirroring::SingleLower), vec![0x005, 0x005, 0x005, 0x005]);
+        assert_eq!(tables(Mirroring::SingleUpper), vec![0x405, 0x405, 0x405, 0x405]);
+        assert_eq!(tables(Mirroring::FourScreen), vec![0x005, 0x405, 0x805, 0xC05]);
+
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(Mirroring::FourScreen.nametable_offset(0x3E05), 0xE05);
+        assert_eq!(Mirroring::Vertical.nametable_offset(0x3405), 0x405);
+    }
+
+    /// Toggles A12 the way one scanline of rendering does.
+    fn scanline(mapper: &mut Box<dyn Mapper>, dot: &mut u64) {
+        mapper.ppu_address(0x0000, *dot);
+        *dot += 260;
+        // Sprite fetches flip A12 every four dots, which mustn't count
+        for _ in 0..8 {
+            mapper.ppu_address(0x2000, *dot);
+            *dot += 4;
+            mapper.ppu_address(0x1000, *dot);
+            *dot += 4;
+        }
+        *dot += 17;
+    }
+
+    #[test]
+    fn mmc3_prg_modes() {
+        let mut mapper = cart(4, 8, 2).into_mapper().unwrap();
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        mapper.cpu_write(0x8000, 7);
+        mapper.cpu_write(0x8001, 5);
+
+        // 8K banks hold every other 4K bank number
+        assert_eq!(mapper.cpu_read(0x8000), Some(6));
+        assert_eq!(mapper.cpu_read(0xA000), Some(10));
+        assert_eq!(mapper.cpu_read(0xC000), Some(28));
+        assert_eq!(mapper.cpu_read(0xE000), Some(30));
+
+        mapper.cpu_write(0x8000, 0x40);
+        assert_eq!(mapper.cpu_read(0x8000), Some(28));
+        assert_eq!(mapper.cpu_read(0xC000), Some(6));
+    }
+
+    #[test]
+    fn mmc3_chr_modes() {
+        let mut mapper = cart(4, 2, 4).into_mapper().unwrap();
+        for (reg, bank) in [9u8, 13, 20, 21, 22, 23].iter().enumerate() {
+            mapper.cpu_write(0x8000, reg as u8);
+            mapper.cpu_write(0x8001, *bank);
+        }
+
+        // 1K banks read back the number of the 4K bank they're in
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+        assert_eq!(mapper.ppu_read(0x0400), 2);
+        assert_eq!(mapper.ppu_read(0x0800), 3);
+        assert_eq!(mapper.ppu_read(0x1000), 5);
+        assert_eq!(mapper.ppu_read(0x1C00), 5);
+
+        mapper.cpu_write(0x8000, 0x80);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        assert_eq!(mapper.ppu_read(0x1000), 2);
+        assert_eq!(mapper.ppu_read(0x1800), 3);
+    }
+
+    #[test]
+    fn mmc3_scanline_irq() {
+        let mut mapper = cart(4, 2, 4).into_mapper().unwrap();
+        let mut dot = 0;
+
+        mapper.cpu_write(0xC000, 2);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        // Reload to 2, then 1, then 0 fires
+        scanline(&mut mapper, &mut dot);
+        scanline(&mut mapper, &mut dot);
+        assert!(!mapper.irq());
+        scanline(&mut mapper, &mut dot);
+        assert!(mapper.irq());
+
+        mapper.cpu_write(0xE000, 0);
+        assert!(!mapper.irq());
+
+        // Reloads from the latch and counts down again, but stays quiet
+        // while disabled
+        scanline(&mut mapper, &mut dot);
+        scanline(&mut mapper, &mut dot);
+        scanline(&mut mapper, &mut dot);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn prg_smaller_than_a_bank() {
+        // NES 2.0 exponent-multiplier sizes: 8K, then 2^11 * 3 bytes
+        for &(size, len) in &[(13 << 2, 0x2000), ((11 << 2) | 1, 0x1800)] {
+            for &mapper in &[2u8, 4] {
+                let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, size, 0, mapper << 4, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0];
+                rom.extend((0..len).map(|i| (i >> 8) as u8));
+                let mapper = NESCart::try_from(rom).unwrap().into_mapper().unwrap();
+
+                for page in 0x8..0x10 {
+                    assert!(mapper.cpu_read(page << 12).is_some());
+                }
+                if len == 0x2000 {
+                    assert_eq!(mapper.cpu_read(0xFFFF), Some(0x1F));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_mapper() {
+        assert!(cart(99, 2, 1).into_mapper().is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use cart::NESCart;
+use mapper::{banked, read_prg_ram, write_prg_ram, write_chr, Mapper, Mirroring};
+
+/// Mapper 0: 16K or 32K of PRG-ROM and 8K of CHR, with no bank switching.
+pub struct NROM {
+    cart: NESCart,
+    mirroring: Mirroring,
+}
+
+impl NROM {
+    pub fn new(cart: NESCart) -> Self {
+        NROM {
+            mirroring: cart.header.mirroring(),
+            cart: cart,
+        }
+    }
+}
+
+snapshot!(NROM { cart, mirroring });
+
+impl Mapper for NROM {
+    fn cart(&self) -> &NESCart {
+        &self.cart
+    }
+
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000...0x7FFF => Some(read_prg_ram(&self.cart, addr)),
+            0x8000...0xFFFF => Some(self.cart.prg_rom[banked(&self.cart.prg_rom, 0x8000, 0, addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if let 0x6000...0x7FFF = addr {
+            write_prg_ram(&mut self.cart, addr, val);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.cart.chr_rom[banked(&self.cart.chr_rom, 0x2000, 0, addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let i = banked(&self.cart.chr_rom, 0x2000, 0, addr);
+        write_chr(&mut self.cart, i, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
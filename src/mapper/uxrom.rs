@@ -0,0 +1,59 @@
+use cart::NESCart;
+use mapper::{banked, banks, read_prg_ram, write_prg_ram, write_chr, Mapper, Mirroring};
+
+/// Mapper 2: a switchable 16K bank at $8000 and the last bank fixed at
+/// $C000.
+pub struct UxROM {
+    cart: NESCart,
+    mirroring: Mirroring,
+    bank: usize,
+}
+
+impl UxROM {
+    pub fn new(cart: NESCart) -> Self {
+        UxROM {
+            mirroring: cart.header.mirroring(),
+            cart: cart,
+            bank: 0,
+        }
+    }
+}
+
+snapshot!(UxROM { cart, mirroring, bank });
+
+impl Mapper for UxROM {
+    fn cart(&self) -> &NESCart {
+        &self.cart
+    }
+
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        let prg = &self.cart.prg_rom;
+        match addr {
+            0x6000...0x7FFF => Some(read_prg_ram(&self.cart, addr)),
+            0x8000...0xBFFF => Some(prg[banked(prg, 0x4000, self.bank, addr)]),
+            0xC000...0xFFFF => Some(prg[banked(prg, 0x4000, banks(prg, 0x4000) - 1, addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF => write_prg_ram(&mut self.cart, addr, val),
+            0x8000...0xFFFF => self.bank = val as usize,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.cart.chr_rom[banked(&self.cart.chr_rom, 0x2000, 0, addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        let i = banked(&self.cart.chr_rom, 0x2000, 0, addr);
+        write_chr(&mut self.cart, i, val);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
@@ -1,16 +1,63 @@
-use cart::NESCart;
-
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use apu::{APU, DEFAULT_SAMPLE_RATE, DMC_STALL};
+use controller::Controller;
+use mapper::Mapper;
+use ppu::PPU;
 
 use std::fmt;
+use std::mem;
 use std::fmt::Debug;
 
-#[derive(Clone)]
+/// Everything the CPU reaches through its address and data lines.
+pub trait Bus {
+    fn read8(&mut self, addr: u16) -> u8;
+    fn write8(&mut self, addr: u16, val: u8);
+
+    /// Reads without side effects, for tracing and debugging.
+    fn peek8(&self, addr: u16) -> u8;
+
+    /// Says the next access lands `cycle` CPU cycles into the current
+    /// instruction, so anything running alongside can catch up to it.
+    fn sync(&mut self, _cycle: u16) {}
+
+    /// CPU cycles stolen by DMA since the last call, given the cycle the
+    /// instruction that triggered it started on.
+    fn dma_stall(&mut self, _cycle: u64) -> u16 {
+        0
+    }
+
+    /// Current PPU scanline and dot, for traces.
+    fn ppu_position(&self) -> (u16, u16) {
+        (0, 0)
+    }
+
+    /// Whether anything is holding the CPU's IRQ line low.
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+/// The NES side of the bus, owning RAM, the PPU, the APU, the controllers
+/// and the cartridge mapper.
 pub struct Memory {
-    pub cart: Arc<Mutex<NESCart>>,
     pub ram: [u8; 0x800],
+    pub ppu: PPU,
+    pub apu: APU,
+    pub controllers: [Controller; 2],
+    pub mapper: Box<dyn Mapper>,
+    oam_dma: bool,
+    /// Cycles the DMC's sample fetches have taken from the CPU
+    dmc_stall: u16,
+    /// Last value driven on the data bus, which unmapped reads return
+    open_bus: u8,
+    /// Controller port the current instruction has read
+    controller_read: Option<usize>,
+    /// Where the current instruction's next access lands
+    access_cycle: u16,
+    /// CPU cycles of the current instruction the PPU has already run
+    ppu_cycles: u16,
+    /// Cycle of the current instruction the PPU raised an NMI on while
+    /// catching up
+    ppu_nmi: Option<u16>,
 }
 
 impl Debug for Memory {
@@ -20,36 +67,309 @@ impl Debug for Memory {
 }
 
 impl Memory {
-    pub fn new(cart: Arc<Mutex<NESCart>>) -> Self {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
         Memory {
-            cart: cart,
             ram: [0u8; 0x800],
+            ppu: PPU::new(0u8),
+            apu: APU::new(DEFAULT_SAMPLE_RATE),
+            controllers: [Controller::new(), Controller::new()],
+            mapper: mapper,
+            oam_dma: false,
+            dmc_stall: 0u16,
+            open_bus: 0u8,
+            controller_read: None,
+            access_cycle: 0u16,
+            ppu_cycles: 0u16,
+            ppu_nmi: None,
+        }
+    }
+
+    /// Runs the PPU up to the access the CPU is making, so register reads
+    /// and writes see it on the right dot.
+    fn catch_up_ppu(&mut self) {
+        while self.ppu_cycles < self.access_cycle {
+            for _ in 0..3 {
+                if self.ppu.step(&mut *self.mapper) && self.ppu_nmi.is_none() {
+                    self.ppu_nmi = Some(self.ppu_cycles);
+                }
+            }
+            self.ppu_cycles += 1;
+        }
+    }
+
+    /// Ends an instruction, handing back how many of its cycles the PPU
+    /// has already run and any NMI it raised in them.
+    pub fn finish_instruction(&mut self) -> (u16, Option<u16>) {
+        self.access_cycle = 0;
+        (mem::replace(&mut self.ppu_cycles, 0), self.ppu_nmi.take())
+    }
+
+    /// Runs the APU for a CPU cycle, letting the DMC's memory reader take
+    /// the bus when it needs a sample byte.
+    ///
+    /// The CPU repeats the read it was halted on, which for the usual
+    /// `LDA $4016` is the instruction's last cycle. A fetch landing there
+    /// clocks the controller twice and the bit in between is lost.
+    pub fn clock_apu(&mut self, last_cycle: bool) {
+        self.apu.step();
+
+        if let Some(addr) = self.apu.dmc_fetch_address() {
+            if let (true, Some(port)) = (last_cycle, self.controller_read) {
+                self.controllers[port].read();
+            }
+
+            let val = self.read8(addr);
+            self.apu.dmc_fill(val);
+            self.dmc_stall += DMC_STALL;
         }
+
+        if last_cycle {
+            self.controller_read = None;
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let val = match addr {
+            0x0000...0x1FFF => self.ram[addr as usize % 0x800],
+            0x2000...0x3FFF => {
+                self.catch_up_ppu();
+                self.ppu.read8(&mut *self.mapper, 0x2000 | (addr & 0x7))
+            }
+            // $4015 doesn't drive bit 5
+            0x4015 => self.apu.read_status() | (self.open_bus & 0x20),
+            // Only the low bits are driven, the rest is left floating
+            0x4016 | 0x4017 => {
+                let port = (addr & 1) as usize;
+                self.controller_read = Some(port);
+                self.controllers[port].read() | (self.open_bus & 0xE0)
+            }
+            // The rest of $4000-$401F is write-only or the disabled test
+            // registers
+            0x4000...0x401F => self.open_bus,
+            _ => self.mapper.cpu_read(addr).unwrap_or(self.open_bus),
+        };
+
+        self.open_bus = val;
+        val
     }
 
-    pub fn read8(&self, addr: u16) -> u8 {
-        let cart = self.cart.lock().unwrap();
-        let mapper = cart.header.mapper;
+    fn write8(&mut self, addr: u16, val: u8) {
+        self.open_bus = val;
         match addr {
-            0x0000...0x2000 => self.ram[addr as usize % 0x800],
-            0x8000...0xFFFF =>
-                match mapper {
-                    0 => match addr {
-                        0x8000...0xBFFF => cart.prg_rom[addr as usize - 0x8000],
-                        0xC000...0xFFFF => cart.prg_rom[addr as usize - 0x8000],
-                        _ => panic!("Cannot read addr: 0x{:X}", addr)
-                    }
-                    _ => panic!("Unimplemented mapper: {}", mapper)
+            0x0000...0x1FFF => self.ram[addr as usize % 0x800] = val,
+            0x2000...0x3FFF => {
+                self.catch_up_ppu();
+                self.ppu.write8(&mut *self.mapper, 0x2000 | (addr & 0x7), val)
+            }
+            0x4000...0x4013 | 0x4015 | 0x4017 => self.apu.write8(addr, val),
+            0x4014 => {
+                let from = (val as u16) << 8;
+                let mut page = [0u8; 0x100];
+                for i in 0..0x100 {
+                    page[i] = self.read8(from + i as u16);
+                }
+
+                self.ppu.oamdma(&page);
+                self.oam_dma = true;
+            }
+            // The strobe goes out to both ports
+            0x4016 => {
+                for controller in self.controllers.iter_mut() {
+                    controller.write(val);
                 }
-            _ => panic!("Cannot read addr: 0x{:X}", addr)
+            }
+            0x4000...0x401F => {}
+            _ => self.mapper.cpu_write(addr, val),
         }
-   }
+    }
 
-    pub fn write8(&mut self, addr: u16, val: u8) {
+    /// Registers aren't touched, so everything from $2000 to $401F reads
+    /// as open bus.
+    fn peek8(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x2000 => self.ram[addr as usize % 0x800] = val,
-            0x4017 => {}
-            _ => panic!("Cannot write addr: 0x{:X}", addr)
+            0x0000...0x1FFF => self.ram[addr as usize % 0x800],
+            0x2000...0x401F => self.open_bus,
+            _ => self.mapper.cpu_read(addr).unwrap_or(self.open_bus),
+        }
+    }
+
+    fn sync(&mut self, cycle: u16) {
+        self.access_cycle = cycle;
+    }
+
+    fn dma_stall(&mut self, cycle: u64) -> u16 {
+        let mut stall = mem::replace(&mut self.dmc_stall, 0);
+
+        if self.oam_dma {
+            self.oam_dma = false;
+            // One more alignment cycle when the DMA starts on an odd cycle
+            stall += 513 + (cycle & 1) as u16;
+        }
+
+        stall
+    }
+
+    fn ppu_position(&self) -> (u16, u16) {
+        (self.ppu.y, self.ppu.x)
+    }
+
+    fn irq(&self) -> bool {
+        self.mapper.irq() || self.apu.irq()
+    }
+}
+
+snapshot!(Memory { ram, controllers, ppu, apu, mapper, oam_dma, dmc_stall, open_bus, controller_read });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{NESCart, test_rom};
+    use controller::Buttons;
+    use std::convert::TryFrom;
+
+    fn mem() -> Memory {
+        let rom = test_rom(0, 0, &[0xEA; 0x4000], &[0; 0x2000]);
+        Memory::new(NESCart::try_from(rom).unwrap().into_mapper().unwrap())
+    }
+
+    #[test]
+    fn ram_is_mirrored() {
+        let mut mem = mem();
+        mem.write8(0x1801, 0x42);
+        assert_eq!(mem.read8(0x0001), 0x42);
+        assert_eq!(mem.read8(0x0801), 0x42);
+    }
+
+    #[test]
+    fn ppu_registers_are_mirrored() {
+        let mut mem = mem();
+        mem.write8(0x3FFB, 0x10);
+        mem.write8(0x2C0C, 0x5A);
+        assert_eq!(mem.ppu.oamaddr, 0x11);
+        assert_eq!(mem.ppu.oam[0x10], 0x5A);
+
+        mem.ppu.oamaddr = 0x10;
+        assert_eq!(mem.read8(0x200C), 0x5A);
+    }
+
+    #[test]
+    fn ppustatus_writes_only_fill_the_latch() {
+        let mut mem = mem();
+        mem.write8(0x2002, 0x1F);
+        assert_eq!(mem.read8(0x2002) & 0x1F, 0x1F);
+        mem.write8(0x3FFA, 0x0A);
+        assert_eq!(mem.read8(0x2002) & 0x1F, 0x0A);
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut mem = mem();
+        mem.write8(0x6123, 0x77);
+        assert_eq!(mem.read8(0x6123), 0x77);
+    }
+
+    #[test]
+    fn nametables_follow_header_mirroring() {
+        let mut mem = mem();
+        mem.write8(0x2006, 0x2C);
+        mem.write8(0x2006, 0x21);
+        mem.write8(0x2007, 0x99);
+
+        // Horizontal: $2C00 shares its RAM with $2800 but not $2400
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x2821), 0x99);
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x3C21), 0x99);
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x2421), 0x00);
+
+        mem.write8(0x2006, 0x3F);
+        mem.write8(0x2006, 0xE3);
+        mem.write8(0x2007, 0x2A);
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x3F03), 0x2A);
+    }
+
+    #[test]
+    fn palette_backdrop_mirrors() {
+        let mut mem = mem();
+        mem.write8(0x2006, 0x3F);
+        mem.write8(0x2006, 0x10);
+        for val in 0x20..0x28 {
+            mem.write8(0x2007, val);
         }
+
+        // $3F10 and $3F14 land on $3F00 and $3F04, $3F11 has its own byte
+        assert_eq!(mem.ppu.palette[0x00], 0x20);
+        assert_eq!(mem.ppu.palette[0x04], 0x24);
+        assert_eq!(mem.ppu.palette[0x11], 0x21);
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x3FF4), 0x24);
+
+        // Only 6 bits are stored, and grayscale masks the reads too
+        mem.write8(0x2006, 0x3F);
+        mem.write8(0x2006, 0x01);
+        mem.write8(0x2007, 0xFA);
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x3F01), 0x3A);
+        mem.write8(0x2001, 0x01);
+        assert_eq!(mem.ppu.vram_read(&mut *mem.mapper, 0x3F01), 0x30);
+    }
+
+    #[test]
+    fn dmc_fetches_stall_the_cpu() {
+        let mut mem = mem();
+        mem.write8(0x4010, 0x80);
+        mem.write8(0x4013, 0x00);
+        mem.write8(0x4015, 0x10);
+
+        mem.clock_apu(false);
+        assert_eq!(mem.dma_stall(0), DMC_STALL);
+        assert_eq!(mem.dma_stall(0), 0);
+
+        // The one byte sample is done, and its IRQ reaches the CPU
+        assert!(mem.irq());
+        assert_eq!(mem.read8(0x4015) & !0x20, 0x80);
+    }
+
+    #[test]
+    fn unmapped_reads_return_open_bus() {
+        let mut mem = mem();
+        assert_eq!(mem.read8(0x8000), 0xEA);
+        assert_eq!(mem.read8(0x5000), 0xEA);
+        assert_eq!(mem.read8(0x4018), 0xEA);
+
+        mem.write8(0x4020, 0x33);
+        assert_eq!(mem.read8(0x4020), 0x33);
+    }
+
+    #[test]
+    fn controllers() {
+        let mut mem = mem();
+        mem.controllers[0].buttons = Buttons::A | Buttons::SELECT;
+        mem.controllers[1].buttons = Buttons::B;
+        mem.write8(0x4016, 1);
+        mem.write8(0x4016, 0);
+
+        let port1: Vec<u8> = (0..4).map(|_| mem.read8(0x4016) & 1).collect();
+        assert_eq!(port1, vec![1, 0, 1, 0]);
+        let port2: Vec<u8> = (0..4).map(|_| mem.read8(0x4017) & 1).collect();
+        assert_eq!(port2, vec![0, 1, 0, 0]);
+
+        // Only the button bit is driven, the top three float
+        mem.write8(0x4020, 0xFF);
+        assert_eq!(mem.read8(0x4016), 0xE0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn dmc_fetch_during_a_controller_read_loses_a_bit() {
+        let mut mem = mem();
+        mem.controllers[0].buttons = Buttons::B;
+        mem.write8(0x4016, 1);
+        mem.write8(0x4016, 0);
+        mem.write8(0x4010, 0x80);
+        mem.write8(0x4015, 0x10);
+
+        assert_eq!(mem.read8(0x4016) & 1, 0);
+        mem.clock_apu(true);
+        // B got clocked out by the repeated read
+        assert_eq!(mem.read8(0x4016) & 1, 0);
+        assert_eq!(mem.read8(0x4016) & 1, 0);
+    }
+}
@@ -1,47 +1,47 @@
-use cart::NESCart;
+use mapper::Mapper;
 use mem::Memory;
 use cpu::NMOS6502;
-use ppu::PPU;
-
-use std::cell::RefCell;
-use std::sync::{Arc, RwLock};
+use state;
+use state::{Snapshot, StateError, MAGIC, VERSION};
 
 #[derive(Debug)]
 pub struct NES {
-    pub cart: Arc<RwLock<NESCart>>,
-    pub mem: Arc<Mutex<Memory>>,
-    pub cpu: Arc<Mutex<NMOS6502>>,
-    pub ppu: Arc<Mutex<PPU>>,
+    pub cpu: NMOS6502,
+    pub mem: Memory,
     pub kill: bool,
 }
 
 impl NES {
-    pub fn new(cart: Arc<RwLock<NESCart>>) -> Self {
-        let mem = Arc::new(Mutex::new(Memory::new(cart.clone())));
-        let ppu = Arc::new(Mutex::new(PPU::new(cart.clone(), mem.clone(), 0u8)));
-        let cpu = Arc::new(Mutex::new(NMOS6502::new(mem.clone(), ppu.clone())));
-
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
         NES {
-            cart: cart.clone(),
-            mem: mem,
-            cpu: cpu,
-            ppu: ppu,
+            cpu: NMOS6502::new(),
+            mem: Memory::new(mapper),
             kill: false,
         }
     }
 
     pub fn reset(&mut self) {
-        let mut cpu = self.cpu.lock().unwrap();
-        cpu.reset();
+        self.cpu.reset(&mut self.mem);
+
+        // The PPU keeps running through the 7 cycles of the reset sequence
+        self.run_ppu(0, 7);
     }
 
-    pub fn step(&mut self) -> Result<u8, String> {
-        let mut cpu = self.cpu.lock().unwrap();
-        let mut res = cpu.step();
-        let mut ppu = self.ppu.lock().unwrap();
-        ppu.step(self.cpu.clone());
-        ppu.step(self.cpu.clone());
-        ppu.step(self.cpu.clone());
+    pub fn step(&mut self) -> Result<u16, String> {
+        let mut res = self.cpu.step(&mut self.mem);
+
+        // PPU register accesses will have run it part of the way already
+        let (done, nmi) = self.mem.finish_instruction();
+        if let Some(cycle) = nmi {
+            self.cpu.nmi(&mut self.mem, cycle);
+        }
+
+        if let Ok(cycles) = res {
+            self.run_ppu(done, cycles);
+            for cycle in 0..cycles {
+                self.mem.clock_apu(cycle == cycles - 1);
+            }
+        }
 
         if self.kill {
             res = Err(String::from("Ended"));
@@ -50,7 +50,126 @@ impl NES {
         res
     }
 
+    /// Captures the whole machine, under a header naming the format
+    /// version and the game.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        VERSION.save(&mut out);
+        self.game_id().save(&mut out);
+
+        self.cpu.save(&mut out);
+        self.mem.save(&mut out);
+        out
+    }
+
+    /// Restores a `save_state`. When it fails the machine is left as it
+    /// was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let input = &mut &data[..];
+        if state::take(input, MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(StateError::NotAState);
+        }
+
+        let mut version = 0u16;
+        version.load(input)?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut game = 0u32;
+        game.load(input)?;
+        if game != self.game_id() {
+            return Err(StateError::OtherGame);
+        }
+
+        let backup = self.save_state();
+        let res = self.cpu.load(input).and_then(|_| self.mem.load(input)).and_then(|_| {
+            if input.is_empty() { Ok(()) } else { Err(StateError::Corrupt("trailing data")) }
+        });
+
+        if res.is_err() {
+            self.load_state(&backup).unwrap();
+        }
+        res
+    }
+
+    fn game_id(&self) -> u32 {
+        let cart = self.mem.mapper.cart();
+        state::game_id(&cart.prg_rom, if cart.chr_ram() { &[] } else { &cart.chr_rom })
+    }
+
+    /// Runs the PPU from one CPU cycle of the last step to another.
+    fn run_ppu(&mut self, from: u16, to: u16) {
+        for dot in from * 3..to * 3 {
+            if self.mem.ppu.step(&mut *self.mem.mapper) {
+                self.cpu.nmi(&mut self.mem, dot / 3);
+            }
+        }
+    }
+
     pub fn run(&mut self) {
         self.step();
     }
+
+    /// A freshly reset machine running `rom`, for tests.
+    #[cfg(test)]
+    pub fn from_rom(rom: Vec<u8>) -> Self {
+        use cart::NESCart;
+        use std::convert::TryFrom;
+
+        let mut nes = NES::new(NESCart::try_from(rom).unwrap().into_mapper().unwrap());
+        nes.reset();
+        nes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::test_rom;
+    use mem::Bus;
+
+    /// Runs `LDA $2002, STA $00` with NMIs on, timed so the read lands with
+    /// `dot` of line 241 up next, and carries on to line 242. The NMI
+    /// handler counts itself in $01.
+    fn read_status_at(dot: usize) -> NES {
+        let mut prg = vec![0xEAu8; 0x4000];
+        prg[..8].copy_from_slice(&[0xAD, 0x02, 0x20, 0x85, 0x00, 0x4C, 0x05, 0xC0]);
+        prg[0x100..0x103].copy_from_slice(&[0xE6, 0x01, 0x40]);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xC1, 0x00, 0xC0, 0x00, 0xC0]);
+        let mut nes = NES::from_rom(test_rom(0, 0, &prg, &[0; 0x2000]));
+        nes.mem.write8(0x2000, 0x80);
+
+        // The read is on the LDA's last cycle, 9 dots in
+        let target = 241 * 341 + dot - 9;
+        while nes.mem.ppu.frames == 0 || nes.mem.ppu.y as usize * 341 + nes.mem.ppu.x as usize != target {
+            nes.mem.ppu.step(&mut *nes.mem.mapper);
+        }
+
+        nes.cpu.set_pc(0xC000);
+        while nes.mem.ppu.y != 242 {
+            nes.step().unwrap();
+        }
+        nes
+    }
+
+    /// The value read, how many NMIs there were and whether vblank is
+    /// still up.
+    fn race(dot: usize) -> (u8, u8, u8) {
+        let nes = read_status_at(dot);
+        (nes.mem.ram[0] & 0x80, nes.mem.ram[1], nes.mem.ppu.ppustatus & 0x80)
+    }
+
+    #[test]
+    fn ppustatus_read_races_vblank() {
+        // Early enough to be an ordinary read
+        assert_eq!(race(0), (0, 1, 0x80));
+        // One dot before the flag goes up loses it and the NMI
+        assert_eq!(race(1), (0, 0, 0));
+        // On that dot or the next it reads as set, but the NMI is gone
+        assert_eq!(race(2), (0x80, 0, 0));
+        assert_eq!(race(3), (0x80, 0, 0));
+        // After that the NMI is already on its way
+        assert_eq!(race(4), (0x80, 1, 0));
+    }
 }
@@ -0,0 +1,76 @@
+use ppuregs::PPUMASK;
+
+/// The 2C02's 64 colors as 0RGB, without any emphasis.
+const COLORS: [u32; 64] = [
+     0x7C7C7Cu32 ,0x0000FCu32 ,0x0000BCu32 ,0x4428BCu32 ,0x940084u32 ,0xA80020u32 ,0xA81000u32 ,0x881400u32
+    ,0x503000u32 ,0x007800u32 ,0x006800u32 ,0x005800u32 ,0x004058u32 ,0x000000u32 ,0x000000u32 ,0x000000u32
+    ,0xBCBCBCu32 ,0x0078F8u32 ,0x0058F8u32 ,0x6844FCu32 ,0xD800CCu32 ,0xE40058u32 ,0xF83800u32 ,0xE45C10u32
+    ,0xAC7C00u32 ,0x00B800u32 ,0x00A800u32 ,0x00A844u32 ,0x008888u32 ,0x000000u32 ,0x000000u32 ,0x000000u32
+    ,0xF8F8F8u32 ,0x3CBCFCu32 ,0x6888FCu32 ,0x9878F8u32 ,0xF878F8u32 ,0xF85898u32 ,0xF87858u32 ,0xFCA044u32
+    ,0xF8B800u32 ,0xB8F818u32 ,0x58D854u32 ,0x58F898u32 ,0x00E8D8u32 ,0x787878u32 ,0x000000u32 ,0x000000u32
+    ,0xFCFCFCu32 ,0xA4E4FCu32 ,0xB8B8F8u32 ,0xD8B8F8u32 ,0xF8B8F8u32 ,0xF8A4C0u32 ,0xF0D0B0u32 ,0xFCE0A8u32
+    ,0xF8D878u32 ,0xD8F878u32 ,0xB8F8B8u32 ,0xB8F8D8u32 ,0x00FCFCu32 ,0xF8D8F8u32 ,0x000000u32 ,0x000000u32
+];
+
+/// How much a channel is dimmed when another channel is emphasized.
+const ATTENUATION: f32 = 0.746;
+
+/// Converts a palette RAM value to 0RGB the way PPUMASK has the PPU
+/// output it.
+pub fn rgb(color: u8, mask: &PPUMASK) -> u32 {
+    let color = match mask.grayscale {
+        true => color & 0x30,
+        false => color & 0x3F,
+    };
+    let rgb = COLORS[color as usize];
+
+    // Each emphasis bit darkens the other two channels, but the blacks in
+    // columns $E and $F are left alone
+    if color & 0x0E == 0x0E {
+        return rgb;
+    }
+
+    let emphasis = [mask.emphasize_red, mask.emphasize_green, mask.emphasize_blue];
+    let mut out = 0;
+    for (i, &emphasized) in emphasis.iter().enumerate() {
+        let shift = 16 - 8 * i as u32;
+        let dimmed = emphasis.iter().filter(|&&e| e).count() - emphasized as usize;
+        let val = ((rgb >> shift) & 0xFF) as f32 * ATTENUATION.powi(dimmed as i32);
+        out |= (val as u32) << shift;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_colors() {
+        let mask = PPUMASK::from(0);
+        assert_eq!(rgb(0x16, &mask), 0xF83800);
+        // The upper bits of palette RAM aren't wired to anything
+        assert_eq!(rgb(0xC0 | 0x16, &mask), 0xF83800);
+    }
+
+    #[test]
+    fn grayscale() {
+        let mask = PPUMASK::from(0b1);
+        assert_eq!(rgb(0x16, &mask), COLORS[0x10]);
+        assert_eq!(rgb(0x2A, &mask), COLORS[0x20]);
+    }
+
+    #[test]
+    fn emphasis() {
+        // Red emphasis dims green and blue
+        let mask = PPUMASK::from(0b00100000);
+        assert_eq!(rgb(0x30, &mask), 0xFCBBBB);
+
+        // Each channel is dimmed by the other two
+        let mask = PPUMASK::from(0b11100000);
+        assert_eq!(rgb(0x30, &mask), 0x8C8C8C);
+
+        assert_eq!(rgb(0x0F, &PPUMASK::from(0b01000000)), 0x000000);
+    }
+}
@@ -1,35 +1,99 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::thread;
-
 use std::fmt;
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
 
-use ppuregs::{PPUCTL, VRAMINC};
-use cart::NESCart;
-use cpu::NMOS6502;
-use mem::Memory;
+use ppuregs::{PPUCTL, PPUMASK, SpriteSize, VRAMINC};
+use mapper::Mapper;
+use palette;
+
+const HEIGHT: u16 = 262;
+const WIDTH: u16 = 341;
+
+/// The line before the first visible one, which fetches like a visible line
+/// without drawing anything.
+const PRE_RENDER: u16 = 261;
+
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
 
-const HEIGHT: u16 = 261;
-const WIDTH: u16 = 340;
+const VBLANK: u8 = 0x80;
+const SPRITE_ZERO_HIT: u8 = 0x40;
+const SPRITE_OVERFLOW: u8 = 0x20;
+
+/// One of the eight sprites loaded for the line being drawn.
+#[derive(Debug, Clone, Copy, Default)]
+struct Sprite {
+    x: u8,
+    attr: u8,
+    /// Pattern planes, already flipped horizontally if the sprite is
+    lo: u8,
+    hi: u8,
+}
 
 #[derive(Clone)]
 pub struct PPU {
-    pub cart: Arc<Mutex<NESCart>>,
-    pub mem: Arc<Mutex<Memory>>,
     pub ppuctl: PPUCTL,
-    pub ppuaddr: u16,
+    pub ppumask: PPUMASK,
     pub y: u16,
     pub x: u16,
     pub ppustatus: u8,
-    pub vram: [u8; 0x4000],
-    pub w: u8,
-    pub xscroll: u8,
-    pub yscroll: u8,
+    /// Nametable RAM: the console's 2K of CIRAM, followed by the 2K that
+    /// four-screen carts add
+    pub nametables: [u8; 0x1000],
+    pub palette: [u8; 0x20],
+    /// Current VRAM address, which PPUDATA uses and rendering walks
+    /// through the nametables with
+    pub v: u16,
+    /// Temporary VRAM address: where rendering picks up from at the start
+    /// of each line and frame
+    pub t: u16,
+    pub fine_x: u8,
+    /// Whether the next PPUSCROLL or PPUADDR write is the second one
+    pub w: bool,
     pub oamaddr: u8,
-    pub oam: [u8; 0xFF],
+    pub oam: [u8; 0x100],
     pub cycles: u64,
+    /// The PPU's own data bus, which write-only registers read back
+    latch: u8,
+    /// PPUDATA reads return what the previous read fetched
+    read_buffer: u8,
+    /// PPUSTATUS was read just before vblank, so this frame neither sets
+    /// the flag nor raises an NMI
+    suppress_vblank: bool,
+
+    /// Latches for the tile being fetched, loaded into the low halves of
+    /// the shift registers every 8 dots
+    nt_latch: u8,
+    at_latch: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    /// Background shift registers: two pattern planes and the palette bits
+    /// spread out to match, one bit per pixel
+    bg_lo: u16,
+    bg_hi: u16,
+    at_lo: u16,
+    at_hi: u16,
+
+    /// Sprites found on this line for the next one, four OAM bytes each
+    secondary_oam: [u8; 0x20],
+    sprite_count: usize,
+    /// Whether the first sprite in secondary OAM is sprite 0
+    sprite_zero: bool,
+    sprites: [Sprite; 8],
+
+    odd_frame: bool,
+    /// Frames finished so far
+    pub frames: u64,
+    /// The picture so far, as 0RGB
+    pub frame: Vec<u32>,
+}
+
+/// Palette RAM is 32 bytes mirrored up to $3FFF, and the sprite palettes'
+/// backdrop entries at $3F10/$3F14/$3F18/$3F1C are the background's.
+fn palette_index(addr: u16) -> usize {
+    match addr & 0x1F {
+        i if i & 0x13 == 0x10 => (i & 0x0F) as usize,
+        i => i as usize,
+    }
 }
 
 impl Debug for PPU {
@@ -39,110 +103,905 @@ impl Debug for PPU {
 }
 
 impl PPU {
-    pub fn new(cart: Arc<Mutex<NESCart>>, mem: Arc<Mutex<Memory>>, ppuctl: u8) -> Self {
+    pub fn new(ppuctl: u8) -> Self {
         PPU {
-            cart: cart,
-            mem: mem,
             ppuctl: PPUCTL::from(ppuctl),
-            ppuaddr: 0u16,
+            ppumask: PPUMASK::from(0),
             y: 0u16,
             x: 0u16,
             ppustatus: 0u8,
-            vram: [0u8; 0x4000],
-            w: 0u8,
-            xscroll: 0u8,
-            yscroll: 0u8,
+            nametables: [0u8; 0x1000],
+            palette: [0u8; 0x20],
+            v: 0u16,
+            t: 0u16,
+            fine_x: 0u8,
+            w: false,
             oamaddr: 0u8,
-            oam: [0u8; 0xFF],
+            oam: [0u8; 0x100],
             cycles: 0u64,
+            latch: 0u8,
+            read_buffer: 0u8,
+            suppress_vblank: false,
+
+            nt_latch: 0u8,
+            at_latch: 0u8,
+            pattern_lo: 0u8,
+            pattern_hi: 0u8,
+            bg_lo: 0u16,
+            bg_hi: 0u16,
+            at_lo: 0u16,
+            at_hi: 0u16,
+
+            secondary_oam: [0xFFu8; 0x20],
+            sprite_count: 0,
+            sprite_zero: false,
+            sprites: [Sprite::default(); 8],
+
+            odd_frame: false,
+            frames: 0u64,
+            frame: vec![0u32; FRAME_WIDTH * FRAME_HEIGHT],
         }
     }
 
-    pub fn step(&mut self, cpu: Arc<Mutex<NMOS6502>>) {
+    /// Advances one dot, returning whether it raised an NMI.
+    pub fn step(&mut self, mapper: &mut dyn Mapper) -> bool {
         self.cycles += 1;
+        let mut nmi = false;
+
+        self.render(mapper);
+
+        if self.cycles > 29658 && self.y == 241 && self.x == 1 {
+            if !self.suppress_vblank {
+                self.ppustatus |= VBLANK;
+            }
+            self.suppress_vblank = false;
+        }
+
+        // The CPU only sees the NMI a couple of dots after the flag goes
+        // up, so a $2002 read in between takes it away
+        if self.cycles > 29658 && self.y == 241 && self.x == 3 {
+            nmi = self.ppuctl.nmi && self.ppustatus & VBLANK != 0;
+        }
+
+        if self.y == PRE_RENDER && self.x == 1 {
+            self.ppustatus &= !(VBLANK | SPRITE_ZERO_HIT | SPRITE_OVERFLOW);
+        }
+
+        self.x += 1;
+
+        // Odd frames skip the last dot of the pre-render line while rendering
+        if self.y == PRE_RENDER && self.x == WIDTH - 1 && self.odd_frame && self.ppumask.rendering() {
+            self.x = WIDTH;
+        }
+
+        if self.x >= WIDTH {
+            self.y += 1;
+            self.x = 0;
+        }
+
+        if self.y >= HEIGHT {
+            self.y = 0;
+            self.frames += 1;
+            self.odd_frame = !self.odd_frame;
+        }
+
+        nmi
+    }
+
+    /// Runs the background fetches and shifters for the current dot, and
+    /// draws a pixel on visible dots.
+    fn render(&mut self, mapper: &mut dyn Mapper) {
+        let (x, y) = (self.x, self.y);
+
+        if (y < 240 || y == PRE_RENDER) && self.ppumask.rendering() {
+            // Dots 321-336 fetch the first two tiles of the next line
+            if (x >= 2 && x <= 257) || (x >= 322 && x <= 337) {
+                self.shift_bg();
+                if x % 8 == 1 {
+                    self.reload_bg();
+                }
+            }
+
+            if (x >= 1 && x <= 256) || (x >= 321 && x <= 336) {
+                match x % 8 {
+                    1 => self.fetch_nametable(mapper),
+                    3 => self.fetch_attribute(mapper),
+                    5 => self.fetch_pattern(mapper, 0),
+                    7 => self.fetch_pattern(mapper, 8),
+                    0 => self.increment_x(),
+                    _ => {}
+                }
+            }
 
-        if self.cycles > 29658 {
-            if self.y == 241 {
-                self.ppustatus |= 0x80;
-                if self.x == 1 && self.ppuctl.nmi {
-                    let mut cpu = cpu.lock().unwrap();
-                    cpu.nmi();
+            match x {
+                256 => self.increment_y(),
+                257 => {
+                    self.copy_x();
+                    self.evaluate_sprites();
                 }
+                280...304 if y == PRE_RENDER => self.copy_y(),
+                _ => {}
             }
 
-            if self.y >= 257 && self.y <= 320 {
+            // Sprite patterns for the next line take the place of the
+            // background fetches, eight dots per sprite
+            if x >= 257 && x <= 320 {
                 self.oamaddr = 0;
+                match x % 8 {
+                    5 => self.fetch_sprite(mapper, (x as usize - 257) / 8, 0),
+                    7 => self.fetch_sprite(mapper, (x as usize - 257) / 8, 8),
+                    _ => {}
+                }
+            }
+        }
+
+        if y < 240 && x >= 1 && x <= 256 {
+            self.draw(x as usize - 1, y as usize);
+        }
+    }
+
+    fn draw(&mut self, x: usize, y: usize) {
+        let bg = self.bg_pixel(x);
+
+        let entry = match self.sprite_pixel(x) {
+            None => bg,
+            Some((sprite, _, _)) if bg == 0 => sprite,
+            Some((sprite, behind, zero)) => {
+                // The hit check doesn't look at the last dot of the line
+                if zero && x != 255 {
+                    self.ppustatus |= SPRITE_ZERO_HIT;
+                }
+
+                match behind {
+                    true => bg,
+                    false => sprite,
+                }
+            }
+        };
+
+        self.frame[y * FRAME_WIDTH + x] = self.rgb(entry);
+    }
+
+    /// Palette entry of the background at `x`, 0 where it's transparent.
+    fn bg_pixel(&self, x: usize) -> u8 {
+        if !self.ppumask.bg || (x < 8 && !self.ppumask.bg_left) {
+            return 0;
+        }
+
+        let bit = 0x8000 >> self.fine_x;
+        let pixel = (self.bg_lo & bit != 0) as u8 | ((self.bg_hi & bit != 0) as u8) << 1;
+        let palette = (self.at_lo & bit != 0) as u8 | ((self.at_hi & bit != 0) as u8) << 1;
+
+        match pixel {
+            0 => 0,
+            _ => palette << 2 | pixel,
+        }
+    }
+
+    /// The frontmost opaque sprite pixel at `x`, as its palette entry,
+    /// whether it's behind the background, and whether it's sprite 0's.
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, bool, bool)> {
+        if !self.ppumask.sprites || (x < 8 && !self.ppumask.sprites_left) {
+            return None;
+        }
+
+        for (i, sprite) in self.sprites[..self.sprite_count].iter().enumerate() {
+            let offset = x.wrapping_sub(sprite.x as usize);
+            if offset >= 8 {
+                continue;
             }
 
-            self.x += 1;
-            if self.x >= WIDTH {
-                self.y += 1;
-                self.x = 0;
+            let bit = 7 - offset;
+            let pixel = (sprite.lo >> bit) & 1 | ((sprite.hi >> bit) & 1) << 1;
+            if pixel != 0 {
+                let entry = 0x10 | (sprite.attr & 0b11) << 2 | pixel;
+                return Some((entry, sprite.attr & 0x20 != 0, i == 0 && self.sprite_zero));
             }
+        }
+
+        None
+    }
+
+    fn sprite_height(&self) -> u16 {
+        match self.ppuctl.spritesize {
+            SpriteSize::EightSq => 8,
+            SpriteSize::Eight16 => 16,
+        }
+    }
+
+    /// Finds the first eight sprites on this line for drawing on the next,
+    /// setting the overflow flag if there's a ninth.
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        let line = self.y;
+        let on_line = |y: u8| line.wrapping_sub(y as u16) < height;
+
+        self.sprite_count = 0;
+        self.sprite_zero = false;
+        for b in self.secondary_oam.iter_mut() {
+            *b = 0xFF;
+        }
+
+        // Nothing is drawn on the pre-render line, so nothing is found
+        // for the first visible one
+        if line == PRE_RENDER {
+            return;
+        }
+
+        let mut n = 0;
+        while n < 64 && self.sprite_count < 8 {
+            if on_line(self.oam[n * 4]) {
+                let slot = self.sprite_count * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                self.sprite_zero |= n == 0;
+                self.sprite_count += 1;
+            }
+            n += 1;
+        }
+
+        // Looking for a ninth, the hardware steps through the byte within
+        // each sprite as well as the sprite, so it compares tile numbers,
+        // attributes and X positions as if they were Y coordinates
+        let mut m = 0;
+        while n < 64 {
+            if on_line(self.oam[n * 4 + m]) {
+                self.ppustatus |= SPRITE_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0b11;
+        }
+    }
+
+    /// Fetches one pattern plane for sprite `slot`. Empty slots still fetch
+    /// tile $FF, which mappers watching A12 depend on.
+    fn fetch_sprite(&mut self, mapper: &mut dyn Mapper, slot: usize, plane: u16) {
+        let height = self.sprite_height();
+        let oam = &self.secondary_oam[slot * 4..slot * 4 + 4];
+        let (y, tile, attr, x) = (oam[0], oam[1], oam[2], oam[3]);
+
+        let mut row = self.y.wrapping_sub(y as u16) % height;
+        if attr & 0x80 != 0 {
+            row = height - 1 - row;
+        }
+        if slot >= self.sprite_count {
+            row = 0;
+        }
+
+        let addr = match height {
+            8 => u16::from(self.ppuctl.spriteaddr.clone()) + tile as u16 * 16 + row,
+            // 8x16 sprites pick their table with bit 0 of the tile number
+            _ => {
+                let table = (tile as u16 & 1) * 0x1000;
+                let tile = (tile & 0xFE) as u16 + row / 8;
+                table + tile * 16 + row % 8
+            }
+        };
+
+        let mut val = self.fetch(mapper, addr + plane);
+        if slot >= self.sprite_count {
+            return;
+        }
+        if attr & 0x40 != 0 {
+            val = (0..8).fold(0, |flipped, bit| flipped | ((val >> bit) & 1) << (7 - bit));
+        }
+
+        let sprite = &mut self.sprites[slot];
+        sprite.x = x;
+        sprite.attr = attr;
+        match plane {
+            0 => sprite.lo = val,
+            _ => sprite.hi = val,
+        }
+    }
+
+    fn shift_bg(&mut self) {
+        self.bg_lo <<= 1;
+        self.bg_hi <<= 1;
+        self.at_lo <<= 1;
+        self.at_hi <<= 1;
+    }
+
+    fn reload_bg(&mut self) {
+        self.bg_lo = (self.bg_lo & 0xFF00) | self.pattern_lo as u16;
+        self.bg_hi = (self.bg_hi & 0xFF00) | self.pattern_hi as u16;
+        self.at_lo = (self.at_lo & 0xFF00) | if self.at_latch & 1 != 0 { 0xFF } else { 0x00 };
+        self.at_hi = (self.at_hi & 0xFF00) | if self.at_latch & 2 != 0 { 0xFF } else { 0x00 };
+    }
+
+    /// A read on the PPU bus, which mappers get to watch.
+    fn fetch(&mut self, mapper: &mut dyn Mapper, addr: u16) -> u8 {
+        mapper.ppu_address(addr, self.cycles);
+        self.vram_read(mapper, addr)
+    }
+
+    fn fetch_nametable(&mut self, mapper: &mut dyn Mapper) {
+        let addr = 0x2000 | (self.v & 0x0FFF);
+        self.nt_latch = self.fetch(mapper, addr);
+    }
+
+    fn fetch_attribute(&mut self, mapper: &mut dyn Mapper) {
+        let v = self.v;
+        let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
 
-            if self.y >= HEIGHT {
-                self.y = 0;
-                self.ppustatus &= !0x80;
+        // Each attribute byte covers 4x4 tiles, two bits per 2x2 quadrant
+        let shift = ((v >> 4) & 0b100) | (v & 0b10);
+        self.at_latch = (self.fetch(mapper, addr) >> shift) & 0b11;
+    }
+
+    fn fetch_pattern(&mut self, mapper: &mut dyn Mapper, plane: u16) {
+        let fine_y = (self.v >> 12) & 0b111;
+        let addr = u16::from(self.ppuctl.backaddr.clone()) + self.nt_latch as u16 * 16 + plane + fine_y;
+
+        match plane {
+            0 => self.pattern_lo = self.fetch(mapper, addr),
+            _ => self.pattern_hi = self.fetch(mapper, addr),
+        }
+    }
+
+    /// Moves `v` to the next tile, wrapping into the next nametable across.
+    fn increment_x(&mut self) {
+        match self.v & 0x001F {
+            31 => self.v = (self.v & !0x001F) ^ 0x0400,
+            _ => self.v += 1,
+        }
+    }
+
+    /// Moves `v` down a line, wrapping into the next nametable down after
+    /// row 29. Rows 30 and 31 are the attribute table, and wrap without
+    /// switching.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+
+        self.v &= !0x7000;
+        let coarse_y = match (self.v & 0x03E0) >> 5 {
+            29 => {
+                self.v ^= 0x0800;
+                0
             }
+            31 => 0,
+            y => y + 1,
+        };
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    /// Resets the horizontal position from `t` for the next line.
+    fn copy_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    /// Resets the vertical position from `t` for the next frame.
+    fn copy_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    /// Reads the PPU's own address space. The cartridge supplies the pattern
+    /// tables and decides how the nametables are mirrored.
+    pub fn vram_read(&mut self, mapper: &mut dyn Mapper, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000...0x1FFF => mapper.ppu_read(addr),
+            0x2000...0x3EFF => self.nametables[mapper.mirroring().nametable_offset(addr)],
+            _ => self.palette[palette_index(addr)] & self.color_mask(),
         }
     }
 
-    pub fn write8(&mut self, addr: u16, val: u8) {
+    pub fn vram_write(&mut self, mapper: &mut dyn Mapper, addr: u16, val: u8) {
+        let addr = addr & 0x3FFF;
         match addr {
-            0x2000 => self.ppuctl = PPUCTL::from(val),
+            0x0000...0x1FFF => mapper.ppu_write(addr, val),
+            0x2000...0x3EFF => self.nametables[mapper.mirroring().nametable_offset(addr)] = val,
+            _ => self.palette[palette_index(addr)] = val,
+        }
+    }
+
+    /// Bits of a palette entry that reach the video output.
+    fn color_mask(&self) -> u8 {
+        match self.ppumask.grayscale {
+            true => 0x30,
+            false => 0x3F,
+        }
+    }
+
+    /// 0RGB for palette entry `entry`, as PPUMASK has it displayed.
+    pub fn rgb(&self, entry: u8) -> u32 {
+        palette::rgb(self.palette[palette_index(entry as u16)], &self.ppumask)
+    }
+
+    /// Steps `v` past a PPUDATA access. While rendering, the access
+    /// collides with the fetches and bumps both coarse X and Y instead.
+    fn increment_v(&mut self) {
+        if (self.y < 240 || self.y == PRE_RENDER) && self.ppumask.rendering() {
+            self.increment_x();
+            self.increment_y();
+            return;
+        }
+
+        let inc = match self.ppuctl.vraminc {
+            VRAMINC::Add1Across => 1,
+            VRAMINC::Add32Down => 32,
+        };
+        self.v = (self.v + inc) & 0x7FFF;
+    }
+
+    pub fn read8(&mut self, mapper: &mut dyn Mapper, addr: u16) -> u8 {
+        let val = match addr {
+            0x2002 => {
+                // Reading on the dot before vblank starts loses the flag
+                // and the NMI for the whole frame
+                if self.y == 241 && self.x == 1 {
+                    self.suppress_vblank = true;
+                }
+
+                let val = (self.ppustatus & 0xE0) | (self.latch & 0x1F);
+                self.ppustatus &= !VBLANK;
+                self.w = false;
+                val
+            }
+            0x2004 => {
+                // Secondary OAM is being cleared, and the PPU drives $FF
+                // while it does
+                if self.y < 240 && self.x >= 1 && self.x <= 64 && self.ppumask.rendering() {
+                    0xFF
+                } else {
+                    self.oam[self.oamaddr as usize]
+                }
+            }
+            0x2007 => {
+                let addr = self.v & 0x3FFF;
+                mapper.ppu_address(addr, self.cycles);
+
+                let val = match addr {
+                    // Palette reads skip the buffer, which gets the
+                    // nametable byte underneath instead. Palette RAM only
+                    // has six bits, the rest is open bus.
+                    0x3F00...0x3FFF => {
+                        self.read_buffer = self.vram_read(mapper, addr - 0x1000);
+                        self.vram_read(mapper, addr) | (self.latch & 0xC0)
+                    }
+                    _ => {
+                        let val = self.read_buffer;
+                        self.read_buffer = self.vram_read(mapper, addr);
+                        val
+                    }
+                };
+
+                self.increment_v();
+                val
+            }
+            _ => return self.latch,
+        };
+
+        self.latch = val;
+        val
+    }
+
+    pub fn write8(&mut self, mapper: &mut dyn Mapper, addr: u16, val: u8) {
+        self.latch = val;
+        match addr {
+            0x2000 => {
+                self.ppuctl = PPUCTL::from(val);
+                self.t = (self.t & !0x0C00) | (val as u16 & 0b11) << 10;
+            }
             0x2003 => self.oamaddr = val,
-            0x2001 => println!("PPUMASK {:X}", val),
+            0x2004 => self.write_oam(val),
+            0x2001 => self.ppumask = PPUMASK::from(val),
             0x2005 => {
-                let w = self.w;
-                match w {
-                    0 => {
-                        self.xscroll = val;
-                        self.w = 1;
+                match self.w {
+                    false => {
+                        self.t = (self.t & !0x001F) | (val >> 3) as u16;
+                        self.fine_x = val & 0b111;
                     }
-                    _ => {
-                        self.yscroll = val;
-                        self.w = 0;
+                    true => {
+                        self.t = (self.t & !0x73E0) | (val as u16 & 0b111) << 12 | (val as u16 & 0xF8) << 2;
                     }
                 }
+                self.w = !self.w;
             }
             0x2006 => {
-                let w = self.w;
-                match w {
-                    0 => {
-                        self.ppuaddr = ((val as u16) << 8) | (self.ppuaddr & 0x00FF); 
-                        self.w = 1;
-                    }
-                    _ => {
-                        self.ppuaddr = (self.ppuaddr & 0xFF00) | (val as u16); 
-                        self.w = 0;
+                match self.w {
+                    false => self.t = (self.t & 0x00FF) | (val as u16 & 0x3F) << 8,
+                    true => {
+                        self.t = (self.t & 0xFF00) | val as u16;
+                        self.v = self.t;
+                        mapper.ppu_address(self.v, self.cycles);
                     }
                 }
+                self.w = !self.w;
             }
             0x2007 => {
-                if self.ppustatus == 0x80 {
-                    let addr = self.ppuaddr as usize;
-                    self.vram[addr] = val;
-
-                    match self.ppuctl.vraminc {
-                        VRAMINC::Add1Across => self.ppuaddr += 1,
-                        VRAMINC::Add32Down => self.ppuaddr += 32,
-                    }
-                }
+                let addr = self.v & 0x3FFF;
+                mapper.ppu_address(addr, self.cycles);
+                self.vram_write(mapper, addr, val);
+                self.increment_v();
             }
-            _ => panic!("Cannot write to PPU @{:04X} = {:02X}", addr, val),
+            // PPUSTATUS is read-only, so all a write does is fill the latch
+            _ => {}
+        }
+    }
+
+    pub fn oamdma(&mut self, page: &[u8; 0x100]) {
+        for &val in page.iter() {
+            self.write_oam(val);
         }
     }
 
-    pub fn oamdma(&mut self, port: u8) {
-        println!("OAMDMA @{:02X}00 => @{:02X}", port, self.oamaddr);
+    fn write_oam(&mut self, val: u8) {
+        let oamaddr = self.oamaddr;
+        // Bits 2-4 of the attribute byte don't exist
+        self.oam[oamaddr as usize] = match oamaddr & 0b11 {
+            2 => val & 0xE3,
+            _ => val,
+        };
+        self.oamaddr = oamaddr.wrapping_add(1);
+    }
+}
+
+snapshot!(Sprite { x, attr, lo, hi });
+
+// The picture isn't saved; the next frame redraws it
+snapshot!(PPU {
+    ppuctl, ppumask, y, x, ppustatus, nametables, palette, v, t, fine_x, w,
+    oamaddr, oam, cycles, latch, read_buffer, suppress_vblank,
+    nt_latch, at_latch, pattern_lo, pattern_hi, bg_lo, bg_hi, at_lo, at_hi,
+    secondary_oam, sprite_count, sprite_zero, sprites, odd_frame, frames,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{NESCart, test_rom};
+    use palette;
+    use std::convert::TryFrom;
+
+    /// A PPU on an NROM board with CHR-RAM, where tile 1 is solid color 1,
+    /// the background palette is black, white, red and blue, sprites are
+    /// green, and every sprite is off the bottom of the screen.
+    fn ppu() -> (PPU, Box<dyn Mapper>) {
+        let mut mapper = NESCart::try_from(test_rom(0, 0, &[0; 0x4000], &[])).unwrap().into_mapper().unwrap();
+        let mut ppu = PPU::new(0);
 
-        let from = (port as u16) << 8;
+        for row in 0..8 {
+            ppu.vram_write(&mut *mapper, 0x0010 + row, 0xFF);
+        }
+        for (i, &color) in [0x0F, 0x30, 0x16, 0x12, 0x0F, 0x12, 0x12, 0x12].iter().enumerate() {
+            ppu.vram_write(&mut *mapper, 0x3F00 + i as u16, color);
+        }
+        ppu.vram_write(&mut *mapper, 0x3F11, 0x2A);
+        ppu.oam = [0xFF; 0x100];
 
-        let mem = self.mem.lock().unwrap();
+        (ppu, mapper)
+    }
 
-        for i in 0..0xFF {
-            self.oam[(self.oamaddr + i) as usize] = mem.read8(from + i as u16);
+    /// Runs until a whole frame has been drawn from the start.
+    fn render(ppu: &mut PPU, mapper: &mut Box<dyn Mapper>) {
+        let frames = ppu.frames + 1;
+        while ppu.frames < frames || ppu.y < 240 {
+            ppu.step(&mut **mapper);
         }
     }
-}
 
+    fn white() -> u32 {
+        palette::rgb(0x30, &PPUMASK::from(0))
+    }
+
+    fn black() -> u32 {
+        palette::rgb(0x0F, &PPUMASK::from(0))
+    }
+
+    fn green() -> u32 {
+        palette::rgb(0x2A, &PPUMASK::from(0))
+    }
+
+    fn sprite(ppu: &mut PPU, n: usize, y: u8, tile: u8, attr: u8, x: u8) {
+        ppu.oam[n * 4..n * 4 + 4].copy_from_slice(&[y, tile, attr, x]);
+    }
+
+    fn at(ppu: &PPU, x: usize, y: usize) -> u32 {
+        ppu.frame[y * FRAME_WIDTH + x]
+    }
+
+    #[test]
+    fn draws_tiles() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x2000, 1);
+        ppu.vram_write(&mut *mapper, 0x2021, 1);
+        ppu.write8(&mut *mapper, 0x2001, 0x0A);
+        render(&mut ppu, &mut mapper);
+
+        assert_eq!(&ppu.frame[0..8], &[white(); 8]);
+        assert_eq!(ppu.frame[8], black());
+        assert_eq!(ppu.frame[7 * FRAME_WIDTH + 7], white());
+        assert_eq!(ppu.frame[8 * FRAME_WIDTH + 7], black());
+        assert_eq!(ppu.frame[8 * FRAME_WIDTH + 8], white());
+    }
+
+    #[test]
+    fn fine_x_scroll() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x2001, 1);
+        ppu.write8(&mut *mapper, 0x2001, 0x0A);
+        ppu.write8(&mut *mapper, 0x2005, 11);
+        ppu.write8(&mut *mapper, 0x2005, 0);
+        render(&mut ppu, &mut mapper);
+
+        assert_eq!(&ppu.frame[0..5], &[white(); 5]);
+        assert_eq!(ppu.frame[5], black());
+    }
+
+    #[test]
+    fn attributes_pick_the_palette() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x2000, 1);
+        ppu.vram_write(&mut *mapper, 0x2002, 1);
+        // Top left quadrant uses palette 0, top right palette 1
+        ppu.vram_write(&mut *mapper, 0x23C0, 0b0100);
+        ppu.write8(&mut *mapper, 0x2001, 0x0A);
+        render(&mut ppu, &mut mapper);
+
+        assert_eq!(ppu.frame[0], white());
+        assert_eq!(ppu.frame[16], palette::rgb(0x12, &PPUMASK::from(0)));
+    }
+
+    #[test]
+    fn left_column_can_be_hidden() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x2000, 1);
+        ppu.vram_write(&mut *mapper, 0x2001, 1);
+        ppu.write8(&mut *mapper, 0x2001, 0x08);
+        render(&mut ppu, &mut mapper);
+
+        assert_eq!(ppu.frame[0], black());
+        assert_eq!(ppu.frame[8], white());
+    }
+
+    #[test]
+    fn draws_sprites() {
+        let (mut ppu, mut mapper) = ppu();
+        sprite(&mut ppu, 0, 9, 1, 0, 20);
+        ppu.write8(&mut *mapper, 0x2001, 0x1E);
+        render(&mut ppu, &mut mapper);
+
+        // Sprites are drawn a line below their Y coordinate
+        assert_eq!(at(&ppu, 20, 9), black());
+        assert_eq!(at(&ppu, 20, 10), green());
+        assert_eq!(at(&ppu, 27, 17), green());
+        assert_eq!(at(&ppu, 28, 17), black());
+        assert_eq!(at(&ppu, 20, 18), black());
+    }
+
+    #[test]
+    fn flips_sprites() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x0020, 0x80);
+        sprite(&mut ppu, 0, 9, 2, 0xC0, 20);
+        ppu.write8(&mut *mapper, 0x2001, 0x1E);
+        render(&mut ppu, &mut mapper);
+
+        assert_eq!(at(&ppu, 20, 10), black());
+        assert_eq!(at(&ppu, 27, 17), green());
+    }
+
+    #[test]
+    fn tall_sprites() {
+        let (mut ppu, mut mapper) = ppu();
+        for row in 0..8 {
+            ppu.vram_write(&mut *mapper, 0x1030 + row, 0xFF);
+        }
+        // Tile 3 means tiles 2 and 3 from the table at $1000
+        sprite(&mut ppu, 0, 9, 3, 0, 20);
+        ppu.write8(&mut *mapper, 0x2000, 0x20);
+        ppu.write8(&mut *mapper, 0x2001, 0x1E);
+        render(&mut ppu, &mut mapper);
+
+        assert_eq!(at(&ppu, 20, 17), black());
+        assert_eq!(at(&ppu, 20, 18), green());
+        assert_eq!(at(&ppu, 20, 25), green());
+        assert_eq!(at(&ppu, 20, 26), black());
+    }
+
+    #[test]
+    fn sprite_priority() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x2000, 1);
+        sprite(&mut ppu, 0, 0, 1, 0x20, 4);
+        ppu.write8(&mut *mapper, 0x2001, 0x1E);
+        render(&mut ppu, &mut mapper);
+
+        // Behind an opaque background, in front of a transparent one
+        assert_eq!(at(&ppu, 4, 1), white());
+        assert_eq!(at(&ppu, 8, 1), green());
+    }
+
+    #[test]
+    fn sprite_zero_hit() {
+        let (mut ppu, mut mapper) = ppu();
+        sprite(&mut ppu, 0, 9, 1, 0, 20);
+        ppu.write8(&mut *mapper, 0x2001, 0x1E);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_ZERO_HIT, 0);
+
+        ppu.vram_write(&mut *mapper, 0x2022, 1);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_ZERO_HIT, SPRITE_ZERO_HIT);
+
+        // Only sprite 0 counts
+        sprite(&mut ppu, 0, 0xFF, 0xFF, 0xFF, 0xFF);
+        sprite(&mut ppu, 1, 9, 1, 0, 20);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_ZERO_HIT, 0);
+    }
+
+    #[test]
+    fn sprite_overflow() {
+        let (mut ppu, mut mapper) = ppu();
+        for n in 0..8 {
+            sprite(&mut ppu, n, 10, 0, 0, 100);
+        }
+        ppu.write8(&mut *mapper, 0x2001, 0x1E);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_OVERFLOW, 0);
+
+        sprite(&mut ppu, 8, 12, 0, 0, 100);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_OVERFLOW, SPRITE_OVERFLOW);
+
+        // The ninth sprite is off the line, but the misaligned search
+        // reads the tile number of the tenth as a Y coordinate
+        sprite(&mut ppu, 8, 0xFF, 0xFF, 0xFF, 0xFF);
+        sprite(&mut ppu, 9, 0xFF, 10, 0xFF, 0xFF);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_OVERFLOW, SPRITE_OVERFLOW);
+
+        sprite(&mut ppu, 9, 10, 0xFF, 0xFF, 0xFF);
+        render(&mut ppu, &mut mapper);
+        assert_eq!(ppu.ppustatus & SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn scroll_writes_fill_t() {
+        let (mut ppu, mut mapper) = ppu();
+
+        ppu.write8(&mut *mapper, 0x2000, 0b10);
+        ppu.write8(&mut *mapper, 0x2005, 0x7D);
+        assert_eq!((ppu.t, ppu.fine_x, ppu.w), (0x080F, 5, true));
+        ppu.write8(&mut *mapper, 0x2005, 0x5E);
+        assert_eq!((ppu.t, ppu.w), (0x696F, false));
+
+        // PPUADDR's first write clears bit 14 along with the top six bits
+        ppu.write8(&mut *mapper, 0x2006, 0xFD);
+        assert_eq!(ppu.t, 0x3D6F);
+        ppu.write8(&mut *mapper, 0x2006, 0xF0);
+        assert_eq!((ppu.t, ppu.v), (0x3DF0, 0x3DF0));
+
+        // Reading PPUSTATUS resets the toggle
+        ppu.write8(&mut *mapper, 0x2005, 0x7D);
+        ppu.read8(&mut *mapper, 0x2002);
+        ppu.write8(&mut *mapper, 0x2005, 0x5E);
+        assert_eq!((ppu.fine_x, ppu.w), (6, true));
+    }
+
+    #[test]
+    fn ppudata_increments_v() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.write8(&mut *mapper, 0x2006, 0x20);
+        ppu.write8(&mut *mapper, 0x2006, 0x00);
+        ppu.write8(&mut *mapper, 0x2007, 1);
+        assert_eq!(ppu.v, 0x2001);
+
+        ppu.write8(&mut *mapper, 0x2000, 0b100);
+        ppu.write8(&mut *mapper, 0x2007, 1);
+        assert_eq!(ppu.v, 0x2021);
+        assert_eq!(ppu.vram_read(&mut *mapper, 0x2001), 1);
+    }
+
+    #[test]
+    fn mid_frame_scroll_split() {
+        let (mut ppu, mut mapper) = ppu();
+        for row in 0..30 {
+            ppu.vram_write(&mut *mapper, 0x2000 + row * 32, 1);
+        }
+        ppu.write8(&mut *mapper, 0x2001, 0x0A);
+        render(&mut ppu, &mut mapper);
+
+        // Change the horizontal scroll halfway down, like a status bar split
+        while ppu.y != 100 {
+            ppu.step(&mut *mapper);
+        }
+        ppu.write8(&mut *mapper, 0x2005, 4);
+        ppu.write8(&mut *mapper, 0x2005, 0);
+        while ppu.y != 240 {
+            ppu.step(&mut *mapper);
+        }
+
+        // Lines already drawn keep the old scroll
+        assert_eq!(at(&ppu, 7, 99), white());
+        assert_eq!(at(&ppu, 4, 101), black());
+        assert_eq!(at(&ppu, 3, 101), white());
+    }
+
+    /// Steps to the given line and dot.
+    fn run_to(ppu: &mut PPU, mapper: &mut Box<dyn Mapper>, y: u16, x: u16) -> bool {
+        let mut nmi = false;
+        while (ppu.y, ppu.x) != (y, x) {
+            nmi |= ppu.step(&mut **mapper);
+        }
+        nmi
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.vram_write(&mut *mapper, 0x2400, 0x11);
+        ppu.vram_write(&mut *mapper, 0x2401, 0x22);
+        ppu.vram_write(&mut *mapper, 0x2F05, 0x33);
+
+        ppu.write8(&mut *mapper, 0x2006, 0x24);
+        ppu.write8(&mut *mapper, 0x2006, 0x00);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2007), 0x00);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2007), 0x11);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2007), 0x22);
+
+        // Palette reads come straight through, with open bus on top, and
+        // fill the buffer from the nametable mirrored underneath
+        ppu.write8(&mut *mapper, 0x2006, 0x3F);
+        ppu.write8(&mut *mapper, 0x2006, 0x05);
+        ppu.latch = 0xC0;
+        assert_eq!(ppu.read8(&mut *mapper, 0x2007), 0xD2);
+        assert_eq!(ppu.read_buffer, 0x33);
+    }
+
+    #[test]
+    fn ppustatus_read_clears_vblank() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.write8(&mut *mapper, 0x2000, 0x80);
+        run_to(&mut ppu, &mut mapper, 0, 0);
+        assert!(run_to(&mut ppu, &mut mapper, 241, 4));
+
+        ppu.write8(&mut *mapper, 0x2005, 0);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2002) & VBLANK, VBLANK);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2002) & VBLANK, 0);
+        assert!(!ppu.w);
+
+        // And it's cleared on the pre-render line whether read or not
+        run_to(&mut ppu, &mut mapper, 241, 4);
+        run_to(&mut ppu, &mut mapper, PRE_RENDER, 2);
+        assert_eq!(ppu.ppustatus & VBLANK, 0);
+    }
+
+    #[test]
+    fn reading_ppustatus_before_vblank_suppresses_nmi() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.write8(&mut *mapper, 0x2000, 0x80);
+        run_to(&mut ppu, &mut mapper, 0, 0);
+        run_to(&mut ppu, &mut mapper, 241, 1);
+
+        assert_eq!(ppu.read8(&mut *mapper, 0x2002) & VBLANK, 0);
+        assert!(!run_to(&mut ppu, &mut mapper, 242, 0));
+        assert_eq!(ppu.read8(&mut *mapper, 0x2002) & VBLANK, 0);
+
+        // The next frame is back to normal
+        assert!(run_to(&mut ppu, &mut mapper, 241, 4));
+    }
+
+    #[test]
+    fn oamdata_reads() {
+        let (mut ppu, mut mapper) = ppu();
+        ppu.write8(&mut *mapper, 0x2003, 0x10);
+        for &val in &[0x20, 0x30, 0xFF, 0x40] {
+            ppu.write8(&mut *mapper, 0x2004, val);
+        }
+
+        ppu.write8(&mut *mapper, 0x2003, 0x12);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2004), 0xE3);
+        // Reads don't move OAMADDR
+        assert_eq!(ppu.read8(&mut *mapper, 0x2004), 0xE3);
+
+        ppu.write8(&mut *mapper, 0x2001, 0x18);
+        run_to(&mut ppu, &mut mapper, 10, 20);
+        assert_eq!(ppu.read8(&mut *mapper, 0x2004), 0xFF);
+    }
+}
@@ -1,14 +1,30 @@
+use state::{Snapshot, StateError};
+
 #[derive(Debug, Clone)]
 pub struct PPUCTL {
-    nametable: BaseNameTable,
+    pub nametable: BaseNameTable,
     pub vraminc: VRAMINC,
-    spriteaddr: SpriteAddr,
-    backaddr: BGPatternTableAddr,
-    spritesize: SpriteSize,
+    pub spriteaddr: SpriteAddr,
+    pub backaddr: BGPatternTableAddr,
+    pub spritesize: SpriteSize,
     masterslave: MasterSlave,
     pub nmi: bool
 }
 
+impl<'a> From<&'a PPUCTL> for u8 {
+    fn from(ctl: &PPUCTL) -> Self {
+        let nametable = (u16::from(ctl.nametable.clone()) >> 10 & 0b11) as u8;
+        let vraminc = match ctl.vraminc { VRAMINC::Add1Across => 0, VRAMINC::Add32Down => 0b100 };
+        let spriteaddr = (u16::from(ctl.spriteaddr.clone()) >> 9) as u8;
+        let backaddr = (u16::from(ctl.backaddr.clone()) >> 8) as u8;
+        let spritesize = match ctl.spritesize { SpriteSize::EightSq => 0, SpriteSize::Eight16 => 0b100000 };
+        let masterslave = match ctl.masterslave { MasterSlave::Master => 0, MasterSlave::Slave => 0b1000000 };
+        let nmi = if ctl.nmi { 0b10000000 } else { 0 };
+
+        nametable | vraminc | spriteaddr | backaddr | spritesize | masterslave | nmi
+    }
+}
+
 impl From<u8> for PPUCTL {
     fn from(val: u8) -> Self {
         let nt = BaseNameTable::from(val);
@@ -163,3 +179,80 @@ impl From<u8> for MasterSlave {
         }
     }
 }
+
+#[derive(Debug, Clone, Default)]
+pub struct PPUMASK {
+    pub grayscale: bool,
+    /// Show the background in the leftmost 8 pixels
+    pub bg_left: bool,
+    /// Show sprites in the leftmost 8 pixels
+    pub sprites_left: bool,
+    pub bg: bool,
+    pub sprites: bool,
+    pub emphasize_red: bool,
+    pub emphasize_green: bool,
+    pub emphasize_blue: bool,
+}
+
+impl PPUMASK {
+    /// Whether either layer is on, which is what makes the PPU fetch.
+    pub fn rendering(&self) -> bool {
+        self.bg || self.sprites
+    }
+}
+
+impl<'a> From<&'a PPUMASK> for u8 {
+    fn from(mask: &PPUMASK) -> Self {
+        [mask.grayscale, mask.bg_left, mask.sprites_left, mask.bg, mask.sprites,
+         mask.emphasize_red, mask.emphasize_green, mask.emphasize_blue]
+            .iter().enumerate().fold(0, |val, (bit, &set)| val | (set as u8) << bit)
+    }
+}
+
+impl From<u8> for PPUMASK {
+    fn from(val: u8) -> Self {
+        Self {
+            grayscale: val & 0b00000001 != 0,
+            bg_left: val & 0b00000010 != 0,
+            sprites_left: val & 0b00000100 != 0,
+            bg: val & 0b00001000 != 0,
+            sprites: val & 0b00010000 != 0,
+            emphasize_red: val & 0b00100000 != 0,
+            emphasize_green: val & 0b01000000 != 0,
+            emphasize_blue: val & 0b10000000 != 0,
+        }
+    }
+}
+
+/// Both registers are saved as the byte that was written to them.
+macro_rules! snapshot_register {
+    ($($ty:ident),*) => {$(
+        impl Snapshot for $ty {
+            fn save(&self, out: &mut Vec<u8>) {
+                u8::from(self).save(out)
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+                let mut val = 0u8;
+                val.load(input)?;
+                *self = $ty::from(val);
+                Ok(())
+            }
+        }
+    )*}
+}
+
+snapshot_register!(PPUCTL, PPUMASK);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_convert_back_to_bytes() {
+        for val in 0..0x100 {
+            assert_eq!(u8::from(&PPUCTL::from(val as u8)), val as u8);
+            assert_eq!(u8::from(&PPUMASK::from(val as u8)), val as u8);
+        }
+    }
+}
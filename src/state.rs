@@ -0,0 +1,296 @@
+use std::fmt;
+
+/// Start of every save state.
+pub const MAGIC: &'static [u8; 4] = b"NESS";
+/// Bumped whenever what gets saved changes, since states are just every
+/// field in a fixed order.
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    /// The state was saved with a different ROM loaded
+    OtherGame,
+    Truncated,
+    /// Something didn't fit, like RAM of the wrong size
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::NotAState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => {
+                write!(f, "Save state version {} isn't supported, only {}", v, VERSION)
+            }
+            StateError::OtherGame => write!(f, "Save state is for a different game"),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "Save state is corrupt: {}", what),
+        }
+    }
+}
+
+/// Machine state that goes into save states. `load` reads back exactly
+/// what `save` wrote, in the same order.
+pub trait Snapshot {
+    fn save(&self, out: &mut Vec<u8>);
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError>;
+}
+
+/// Implements `Snapshot` for a struct by saving the listed fields in order.
+macro_rules! snapshot {
+    ($ty:ty { $($field:ident),* $(,)* }) => {
+        impl ::state::Snapshot for $ty {
+            fn save(&self, out: &mut Vec<u8>) {
+                $( ::state::Snapshot::save(&self.$field, out); )*
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> Result<(), ::state::StateError> {
+                $( ::state::Snapshot::load(&mut self.$field, input)?; )*
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Splits the next `len` bytes off the input.
+pub fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], StateError> {
+    if input.len() < len {
+        return Err(StateError::Truncated);
+    }
+
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+macro_rules! snapshot_int {
+    ($($ty:ty: $bytes:expr),*) => {$(
+        impl Snapshot for $ty {
+            fn save(&self, out: &mut Vec<u8>) {
+                for i in 0..$bytes {
+                    out.push((*self >> (i * 8)) as u8);
+                }
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+                *self = take(input, $bytes)?.iter().rev().fold(0u64, |val, &b| val << 8 | b as u64) as $ty;
+                Ok(())
+            }
+        }
+    )*}
+}
+
+snapshot_int!(u8: 1, u16: 2, u32: 4, u64: 8);
+
+impl Snapshot for usize {
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self as u64).save(out)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        let mut val = 0u64;
+        val.load(input)?;
+        *self = val as usize;
+        Ok(())
+    }
+}
+
+impl Snapshot for bool {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8)
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        *self = match take(input, 1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(StateError::Corrupt("bad bool")),
+        };
+        Ok(())
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Option<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.is_some().save(out);
+        if let Some(ref val) = *self {
+            val.save(out);
+        }
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        let mut some = false;
+        some.load(input)?;
+        *self = if some {
+            let mut val = T::default();
+            val.load(input)?;
+            Some(val)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+/// Memories whose size is fixed by the cart, so a length mismatch means
+/// the state is for some other board.
+impl Snapshot for Vec<u8> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.len().save(out);
+        out.extend_from_slice(self);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+        let mut len = 0usize;
+        len.load(input)?;
+        if len != self.len() {
+            return Err(StateError::Corrupt("memory size doesn't match"));
+        }
+        self.copy_from_slice(take(input, len)?);
+        Ok(())
+    }
+}
+
+macro_rules! snapshot_array {
+    ($($len:expr),*) => {$(
+        impl<T: Snapshot> Snapshot for [T; $len] {
+            fn save(&self, out: &mut Vec<u8>) {
+                for val in self.iter() {
+                    val.save(out);
+                }
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> Result<(), StateError> {
+                for val in self.iter_mut() {
+                    val.load(input)?;
+                }
+                Ok(())
+            }
+        }
+    )*}
+}
+
+snapshot_array!(2, 8, 0x20, 0x100, 0x800, 0x1000);
+
+/// FNV-1a over the ROM, so states don't get loaded into the wrong game.
+pub fn game_id(prg_rom: &[u8], chr_rom: &[u8]) -> u32 {
+    prg_rom.iter().chain(chr_rom.iter()).fold(0x811C9DC5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::test_rom;
+    use nes::NES;
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Regs {
+        a: u8,
+        pc: u16,
+        cycles: u64,
+        flag: bool,
+        fetched: Option<u8>,
+        ram: [u8; 8],
+    }
+
+    snapshot!(Regs { a, pc, cycles, flag, fetched, ram });
+
+    /// Loops forever incrementing $00, with CHR-RAM so there's plenty to
+    /// save.
+    fn nes(prg_byte: u8) -> NES {
+        let mut prg = vec![prg_byte; 0x4000];
+        prg[..5].copy_from_slice(&[0xE6, 0x00, 0x4C, 0x00, 0xC0]);
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0xC0;
+        NES::from_rom(test_rom(1, 0, &prg, &[]))
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        let regs = Regs {
+            a: 0x12,
+            pc: 0xC123,
+            cycles: 0x0123_4567_89AB_CDEF,
+            flag: true,
+            fetched: Some(0x99),
+            ram: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let mut out = Vec::new();
+        regs.save(&mut out);
+        assert_eq!(out.len(), 1 + 2 + 8 + 1 + 2 + 8);
+        assert_eq!(&out[..3], &[0x12, 0x23, 0xC1]);
+
+        let mut loaded = Regs::default();
+        loaded.load(&mut &out[..]).unwrap();
+        assert_eq!(loaded, regs);
+
+        assert_eq!(loaded.load(&mut &out[..out.len() - 1]), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn machine_round_trip() {
+        let mut nes = nes(0xEA);
+        for _ in 0..10_000 {
+            nes.step().unwrap();
+        }
+        let state = nes.save_state();
+        let count = nes.mem.ram[0];
+
+        for _ in 0..10_000 {
+            nes.step().unwrap();
+        }
+        let later = (nes.mem.ram[0], nes.cpu.cycles, nes.mem.ppu.cycles, nes.mem.apu.cycles);
+        assert!(nes.mem.ram[0] != count);
+
+        // Picking up from the state ends up in exactly the same place
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.mem.ram[0], count);
+        for _ in 0..10_000 {
+            nes.step().unwrap();
+        }
+        assert_eq!((nes.mem.ram[0], nes.cpu.cycles, nes.mem.ppu.cycles, nes.mem.apu.cycles), later);
+    }
+
+    #[test]
+    fn refuses_bad_states() {
+        let mut nes = nes(0xEA);
+        let state = nes.save_state();
+
+        assert_eq!(nes.load_state(b"NESX"), Err(StateError::NotAState));
+
+        let mut newer = state.clone();
+        newer[4] = VERSION as u8 + 1;
+        assert_eq!(nes.load_state(&newer), Err(StateError::UnsupportedVersion(VERSION + 1)));
+
+        assert_eq!(nes.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(nes.load_state(&longer), Err(StateError::Corrupt("trailing data")));
+
+        let mut other = self::nes(0x00);
+        assert_eq!(other.load_state(&state), Err(StateError::OtherGame));
+    }
+
+    #[test]
+    fn failed_loads_leave_the_machine_alone() {
+        let mut nes = nes(0xEA);
+        for _ in 0..100 {
+            nes.step().unwrap();
+        }
+        let state = nes.save_state();
+        let cycles = nes.cpu.cycles;
+
+        let mut other = self::nes(0xEA);
+        other.step().unwrap();
+        let mut bad = other.save_state();
+        bad.truncate(bad.len() - 1);
+
+        assert_eq!(nes.load_state(&bad), Err(StateError::Truncated));
+        assert_eq!(nes.cpu.cycles, cycles);
+        assert_eq!(nes.save_state(), state);
+    }
+}
@@ -0,0 +1,203 @@
+use cart::NESCart;
+use mem::Bus;
+use nes::NES;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// $6000 while the test is still going.
+const RUNNING: u8 = 0x80;
+/// $6000 when the test wants the reset button pressed.
+const NEEDS_RESET: u8 = 0x81;
+/// At $6001-$6003 once $6000 can be trusted, since RAM powers up as junk.
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+/// Frames to wait before pressing reset, a little over the 100ms asked for.
+const RESET_DELAY: u64 = 7;
+
+/// How long a test gets before it's given up on, two minutes of frames.
+pub const DEFAULT_FRAMES: u64 = 60 * 120;
+
+/// How a test ROM finished, along with the text it left at $6004.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed(String),
+    Failed(u8, String),
+}
+
+/// The NUL-terminated text at $6004.
+fn message(nes: &NES) -> String {
+    let text: Vec<u8> = (0x6004..0x8000)
+        .map(|addr| nes.mem.peek8(addr))
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&text).trim().to_string()
+}
+
+/// `what`, followed by whatever the ROM had to say.
+fn explain(what: String, nes: &NES) -> String {
+    let text = message(nes);
+    if text.is_empty() { what } else { format!("{}: {}", what, text) }
+}
+
+/// Runs a ROM that reports through $6000 until it says it's done, pressing
+/// reset for it when asked.
+pub fn run(nes: &mut NES, max_frames: u64) -> Result<Outcome, String> {
+    let end = nes.mem.ppu.frames + max_frames;
+    let mut frame = nes.mem.ppu.frames;
+    let mut reset_at = None;
+
+    while nes.mem.ppu.frames < end {
+        nes.step()?;
+        if nes.cpu.halted() {
+            return Err(explain("CPU jammed".to_string(), nes));
+        }
+
+        // Once a frame is plenty often to look
+        if nes.mem.ppu.frames == frame {
+            continue;
+        }
+        frame = nes.mem.ppu.frames;
+
+        let signature = [nes.mem.peek8(0x6001), nes.mem.peek8(0x6002), nes.mem.peek8(0x6003)];
+        if signature != SIGNATURE {
+            continue;
+        }
+
+        match nes.mem.peek8(0x6000) {
+            RUNNING => {}
+            NEEDS_RESET => match reset_at {
+                None => reset_at = Some(frame + RESET_DELAY),
+                Some(at) if frame >= at => {
+                    nes.reset();
+                    reset_at = None;
+                }
+                Some(_) => {}
+            },
+            0 => return Ok(Outcome::Passed(message(nes))),
+            code if code < RUNNING => return Ok(Outcome::Failed(code, message(nes))),
+            _ => {}
+        }
+    }
+
+    Err(explain(format!("Timed out after {} frames", max_frames), nes))
+}
+
+/// Loads a ROM from disk and runs it as a test.
+pub fn run_rom(path: &Path, max_frames: u64) -> Result<Outcome, String> {
+    let mut rom = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut rom))
+        .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+
+    let mapper = NESCart::try_from(rom)
+        .and_then(|cart| cart.into_mapper())
+        .map_err(|e| format!("Couldn't load {}: {}", path.display(), e))?;
+
+    let mut nes = NES::new(mapper);
+    nes.reset();
+    run(&mut nes, max_frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::test_rom;
+
+    /// Runs a test ROM from the directory build.rs looked through, failing
+    /// with its message.
+    #[allow(dead_code)]
+    fn run_test_rom(path: &str) {
+        match run_rom(Path::new(path), DEFAULT_FRAMES) {
+            Ok(Outcome::Passed(_)) => {}
+            Ok(Outcome::Failed(code, message)) => panic!("Failed with code {}:\n{}", code, message),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // One test per ROM under $NES_TEST_ROMS, or tests/roms
+    include!(concat!(env!("OUT_DIR"), "/test_roms.rs"));
+
+    /// LDA #val, STA addr
+    fn store(code: &mut Vec<u8>, addr: u16, val: u8) {
+        code.extend_from_slice(&[0xA9, val, 0x8D, addr as u8, (addr >> 8) as u8]);
+    }
+
+    /// Marks the test as running and signs $6001, then leaves `text` at
+    /// $6004.
+    fn start(code: &mut Vec<u8>, text: &str) {
+        store(code, 0x6000, RUNNING);
+        for (i, &b) in SIGNATURE.iter().enumerate() {
+            store(code, 0x6001 + i as u16, b);
+        }
+        for (i, b) in text.bytes().chain(Some(0)).enumerate() {
+            store(code, 0x6004 + i as u16, b);
+        }
+    }
+
+    /// JMP to itself, at $C000 + the code so far.
+    fn hang(code: &mut Vec<u8>) {
+        let here = 0xC000 + code.len() as u16;
+        code.extend_from_slice(&[0x4C, here as u8, (here >> 8) as u8]);
+    }
+
+    fn nes(code: &[u8]) -> NES {
+        let mut prg = vec![0xEAu8; 0x4000];
+        prg[..code.len()].copy_from_slice(code);
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0xC0;
+        NES::from_rom(test_rom(0, 0, &prg, &[0; 0x2000]))
+    }
+
+    fn finish(status: u8) -> NES {
+        let mut code = Vec::new();
+        start(&mut code, "\nall done\n");
+        store(&mut code, 0x6000, status);
+        hang(&mut code);
+        nes(&code)
+    }
+
+    #[test]
+    fn passes() {
+        assert_eq!(run(&mut finish(0), 60), Ok(Outcome::Passed("all done".to_string())));
+    }
+
+    #[test]
+    fn fails_with_the_code() {
+        assert_eq!(run(&mut finish(3), 60), Ok(Outcome::Failed(3, "all done".to_string())));
+    }
+
+    #[test]
+    fn presses_reset_when_asked() {
+        let mut code = Vec::new();
+        start(&mut code, "resetting");
+
+        // LDA $6100, BNE to the second boot
+        code.extend_from_slice(&[0xAD, 0x00, 0x61, 0xD0, 13]);
+        store(&mut code, 0x6100, 1);
+        store(&mut code, 0x6000, NEEDS_RESET);
+        hang(&mut code);
+
+        store(&mut code, 0x6000, 0);
+        hang(&mut code);
+
+        let mut nes = nes(&code);
+        assert_eq!(run(&mut nes, 60), Ok(Outcome::Passed("resetting".to_string())));
+        assert!(nes.mem.ppu.frames > RESET_DELAY);
+    }
+
+    #[test]
+    fn gives_up() {
+        // Still running, and never signed in the first place
+        let mut code = Vec::new();
+        start(&mut code, "working");
+        hang(&mut code);
+        assert_eq!(run(&mut nes(&code), 10), Err("Timed out after 10 frames: working".to_string()));
+
+        let mut nes = nes(&[0x4C, 0x00, 0xC0]);
+        nes.mem.write8(0x6000, 0);
+        assert_eq!(run(&mut nes, 10), Err("Timed out after 10 frames".to_string()));
+
+        assert_eq!(run(&mut self::nes(&[0x02]), 10), Err("CPU jammed".to_string()));
+    }
+}
@@ -0,0 +1,241 @@
+use inst::{Instruction, Opcode, Value};
+
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// CPU state right before an instruction executes.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+    pub inst: Instruction,
+
+    /// Effective address of the operand, or the target of a jump or branch
+    pub addr: Option<u16>,
+    /// Pointer an indirect operand was read through
+    pub ptr: Option<u16>,
+    /// Memory at `addr` before the instruction runs
+    pub value: Option<u8>,
+
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycles: u64,
+}
+
+impl TraceRecord {
+    pub fn documented(&self) -> bool {
+        match (self.inst.0, self.bytes[0]) {
+            (Opcode::NOP, 0xEA) => true,
+            (Opcode::NOP, _) => false,
+            (Opcode::SBC, 0xEB) => false,
+            (op, _) => !op.is_undocumented(),
+        }
+    }
+
+    /// Disassembly with the memory annotations nestest.log uses.
+    pub fn disasm(&self) -> String {
+        let Instruction(op, val) = self.inst;
+        let addr = self.addr.unwrap_or(0);
+        let ptr = self.ptr.unwrap_or(0);
+        let value = self.value.unwrap_or(0);
+
+        match val {
+            Value::Implied => format!("{:?}", op),
+            Value::Accumulator => format!("{:?} A", op),
+            Value::Immediate(imm) => format!("{:?} #${:02X}", op, imm),
+            Value::ZeroPage(zpg) => format!("{:?} ${:02X} = {:02X}", op, zpg, value),
+            Value::ZeroPageX(zpg) => format!("{:?} ${:02X},X @ {:02X} = {:02X}", op, zpg, addr, value),
+            Value::ZeroPageY(zpg) => format!("{:?} ${:02X},Y @ {:02X} = {:02X}", op, zpg, addr, value),
+            Value::Absolute(abs) => match op {
+                Opcode::JMP | Opcode::JSR => format!("{:?} ${:04X}", op, abs),
+                _ => format!("{:?} ${:04X} = {:02X}", op, abs, value),
+            },
+            Value::AbsoluteX(abs) => format!("{:?} ${:04X},X @ {:04X} = {:02X}", op, abs, addr, value),
+            Value::AbsoluteY(abs) => format!("{:?} ${:04X},Y @ {:04X} = {:02X}", op, abs, addr, value),
+            Value::Indirect(ind) => format!("{:?} (${:04X}) = {:04X}", op, ind, addr),
+            Value::PreIdxIndX(zpg) => format!("{:?} (${:02X},X) @ {:02X} = {:04X} = {:02X}", op, zpg, ptr, addr, value),
+            Value::PreIdxIndY(zpg) => format!("{:?} (${:02X}),Y = {:04X} @ {:04X} = {:02X}", op, zpg, ptr, addr, value),
+            Value::Relative(_) => format!("{:?} ${:04X}", op, addr),
+        }
+    }
+
+    /// One line in the exact format of nestest.log.
+    pub fn nestest(&self) -> String {
+        let bytes = self.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+
+        format!("{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                self.pc,
+                bytes,
+                if self.documented() { ' ' } else { '*' },
+                self.disasm(),
+                self.a, self.x, self.y, self.p, self.sp,
+                self.scanline, self.dot,
+                self.cycles)
+    }
+}
+
+pub trait TraceSink: Send {
+    fn trace(&mut self, rec: &TraceRecord);
+}
+
+/// Shared handle to a sink, cheap to clone along with the CPU.
+#[derive(Clone)]
+pub struct Tracer(pub Arc<Mutex<dyn TraceSink>>);
+
+impl Tracer {
+    pub fn new<T: TraceSink + 'static>(sink: Arc<Mutex<T>>) -> Self {
+        Tracer(sink)
+    }
+
+    pub fn trace(&self, rec: &TraceRecord) {
+        self.0.lock().unwrap().trace(rec);
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+/// Writes every record as a nestest.log line.
+pub struct NestestSink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> NestestSink<W> {
+    pub fn new(out: W) -> Self {
+        NestestSink { out: out }
+    }
+}
+
+impl<W: Write + Send> TraceSink for NestestSink<W> {
+    fn trace(&mut self, rec: &TraceRecord) {
+        let _ = writeln!(self.out, "{}", rec.nestest());
+    }
+}
+
+/// Checks records against a reference log and remembers the first line
+/// that differs.
+pub struct NestestCompare {
+    expected: Vec<String>,
+    line: usize,
+    pub mismatch: Option<Mismatch>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl NestestCompare {
+    pub fn new(log: &str) -> Self {
+        NestestCompare {
+            expected: log.lines().map(|l| l.trim_right().to_owned()).collect(),
+            line: 0,
+            mismatch: None,
+        }
+    }
+
+    /// Lines matched so far.
+    pub fn matched(&self) -> usize {
+        self.line
+    }
+
+    pub fn finished(&self) -> bool {
+        self.mismatch.is_some() || self.line >= self.expected.len()
+    }
+}
+
+impl TraceSink for NestestCompare {
+    fn trace(&mut self, rec: &TraceRecord) {
+        if self.finished() {
+            return;
+        }
+
+        let actual = rec.nestest();
+        let expected = &self.expected[self.line];
+        if actual.trim_right() != expected {
+            self.mismatch = Some(Mismatch {
+                line: self.line + 1,
+                expected: expected.clone(),
+                actual: actual,
+            });
+        } else {
+            self.line += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(bytes: Vec<u8>, inst: Instruction) -> TraceRecord {
+        TraceRecord {
+            pc: 0xC000,
+            bytes: bytes,
+            inst: inst,
+            addr: None,
+            ptr: None,
+            value: None,
+            a: 0x00,
+            x: 0x00,
+            y: 0x00,
+            p: 0x24,
+            sp: 0xFD,
+            scanline: 0,
+            dot: 21,
+            cycles: 7,
+        }
+    }
+
+    #[test]
+    fn formats_nestest_line() {
+        let rec = record(vec![0x4C, 0xF5, 0xC5], Instruction(Opcode::JMP, Value::Absolute(0xC5F5)));
+        assert_eq!(rec.nestest(),
+                   "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+    }
+
+    #[test]
+    fn marks_undocumented_opcodes() {
+        let mut rec = record(vec![0x04, 0xA9], Instruction(Opcode::NOP, Value::ZeroPage(0xA9)));
+        rec.addr = Some(0x00A9);
+        rec.value = Some(0x00);
+        assert_eq!(rec.nestest(),
+                   "C000  04 A9    *NOP $A9 = 00                    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+
+        let rec = record(vec![0xEA], Instruction(Opcode::NOP, Value::Implied));
+        assert!(rec.documented());
+        let rec = record(vec![0xEB, 0x40], Instruction(Opcode::SBC, Value::Immediate(0x40)));
+        assert!(!rec.documented());
+    }
+
+    #[test]
+    fn annotates_indirect_operands() {
+        let mut rec = record(vec![0xB1, 0x89], Instruction(Opcode::LDA, Value::PreIdxIndY(0x89)));
+        rec.ptr = Some(0x0300);
+        rec.addr = Some(0x0300);
+        rec.value = Some(0x89);
+        assert_eq!(rec.disasm(), "LDA ($89),Y = 0300 @ 0300 = 89");
+
+        let mut rec = record(vec![0xA1, 0x80], Instruction(Opcode::LDA, Value::PreIdxIndX(0x80)));
+        rec.ptr = Some(0x0080);
+        rec.addr = Some(0x0200);
+        rec.value = Some(0x5A);
+        assert_eq!(rec.disasm(), "LDA ($80,X) @ 80 = 0200 = 5A");
+
+        let mut rec = record(vec![0x6C, 0xFF, 0x02], Instruction(Opcode::JMP, Value::Indirect(0x02FF)));
+        rec.addr = Some(0x0300);
+        assert_eq!(rec.disasm(), "JMP ($02FF) = 0300");
+    }
+}